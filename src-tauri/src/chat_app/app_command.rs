@@ -1,54 +1,182 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf};
 
 use super::AppState;
 use super::Invoke;
 use crate::{
     error::NetworkError,
-    models::{GroupId, GroupInfo, Setting},
-    network::{message::Message, Client},
+    identity::{IdentityInfo, NodeIdentity},
+    managers::file::{FileManager, TransferInfo},
+    models::{GroupId, GroupInfo, ListenConfig, Setting},
+    network::{self, message::Message, Client, SwarmConfig},
 };
-use libp2p::{self, multiaddr::Protocol, swarm::derive_prelude::ListenerId, Multiaddr, PeerId};
+use libp2p::{
+    self,
+    identity::{self, ed25519},
+    pnet::PreSharedKey,
+    swarm::derive_prelude::ListenerId,
+    Multiaddr, PeerId,
+};
+use rand::RngCore;
+use tokio::fs;
+use uuid::Uuid;
 #[derive(Clone)]
 pub struct AppCommandHandle {
     pub(crate) client: Client,
     pub(crate) state: AppState,
     pub(crate) managers: HashMap<String, Box<dyn Invoke>>,
+    pub(crate) file_manager: FileManager,
 }
 
 impl AppCommandHandle {
     pub async fn get_listeners(&self) -> HashMap<ListenerId, Vec<Multiaddr>> {
         self.client.listeners.lock().await.clone()
     }
+    /// Listen on `listen_addr`, or else on the addresses of the listen config, and listen there
+    /// again on the next start.
     pub async fn start_listen(
         &self,
         listen_addr: Option<Multiaddr>,
-    ) -> Result<ListenerId, NetworkError> {
-        self.client
-            .start_listening(listen_addr.unwrap_or_else(|| "/ip4/0.0.0.0/tcp/0".parse().unwrap()))
-            .await
+    ) -> Result<Vec<ListenerId>, NetworkError> {
+        let addrs = match listen_addr {
+            Some(listen_addr) => vec![listen_addr],
+            None => {
+                let setting = self.state.setting.lock().await;
+                listen_addresses(&setting, &setting.listen)
+            }
+        };
+        self.listen_on(addrs).await
+    }
+    async fn listen_on(&self, addrs: Vec<Multiaddr>) -> Result<Vec<ListenerId>, NetworkError> {
+        let mut listener_ids = Vec::new();
+        let mut opened = Vec::new();
+        let mut error = None;
+        for addr in addrs {
+            match self.client.start_listening(addr.clone()).await {
+                Ok(listener_id) => {
+                    listener_ids.push(listener_id);
+                    opened.push((listener_id, addr));
+                }
+                Err(e) => {
+                    log::warn!("Failed to listen on {addr}: {e}");
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        if let (true, Some(e)) = (listener_ids.is_empty(), error) {
+            return Err(e);
+        }
+        // the listeners whose address is known by now are saved with the ports they were
+        // assigned, the others are updated once their address comes in
+        let mut setting = self.state.setting.lock().await;
+        let listeners = self.client.listeners.lock().await.clone();
+        for (listener_id, addr) in opened {
+            let addr = match listeners.get(&listener_id).and_then(|bound| bound.first()) {
+                Some(bound) => network::with_bound_ports(&addr, bound),
+                None => addr,
+            };
+            if !setting.listen_addrs.contains(&addr) {
+                setting.listen_addrs.push(addr);
+            }
+        }
+        setting.save(&self.state.config_dir).await?;
+        Ok(listener_ids)
     }
+    /// Stop the given listener or else all of them, and stay off their addresses on the next
+    /// start.
     pub async fn stop_listen(&self, listen_id: Option<ListenerId>) -> Result<(), NetworkError> {
-        let listener_id = if let Some(listen_id) = listen_id {
+        let listener_ids = if let Some(listen_id) = listen_id {
             vec![listen_id]
         } else {
-            self.client.listeners.lock().await.keys().cloned().collect()
+            self.client
+                .listen_requests
+                .lock()
+                .await
+                .keys()
+                .cloned()
+                .collect()
         };
-        self.client.stop_listening(listener_id).await?;
+        self.stop_listeners(listener_ids).await
+    }
+    async fn stop_listeners(&self, listener_ids: Vec<ListenerId>) -> Result<(), NetworkError> {
+        let requests = self.client.listen_requests.lock().await.clone();
+        let listeners = self.client.listeners.lock().await.clone();
+        self.client.stop_listening(listener_ids.clone()).await?;
+        // the addresses are saved as requested or with the ports they were assigned
+        let stopped = listener_ids
+            .iter()
+            .filter_map(|listener_id| Some((listener_id, requests.get(listener_id)?)))
+            .flat_map(|(listener_id, addr)| {
+                let bound = listeners
+                    .get(listener_id)
+                    .and_then(|bound| bound.first())
+                    .map(|bound| network::with_bound_ports(addr, bound));
+                [addr.clone()].into_iter().chain(bound)
+            })
+            .collect::<Vec<_>>();
+        let mut setting = self.state.setting.lock().await;
+        setting.listen_addrs.retain(|addr| !stopped.contains(addr));
+        setting.save(&self.state.config_dir).await?;
         Ok(())
     }
+    /// Switch to `config`, moving the listeners on the addresses of the old config to the
+    /// addresses of the new one. The config is only saved once the listeners moved; if they
+    /// cannot be opened on the new addresses, they are reopened on the old ones.
+    pub async fn set_listen_config(
+        &self,
+        config: ListenConfig,
+    ) -> Result<Vec<ListenerId>, NetworkError> {
+        let (old_addrs, new_addrs) = {
+            let setting = self.state.setting.lock().await;
+            (
+                listen_addresses(&setting, &setting.listen),
+                listen_addresses(&setting, &config),
+            )
+        };
+        let moved = self
+            .client
+            .listen_requests
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, addr)| old_addrs.contains(addr))
+            .map(|(listener_id, _)| *listener_id)
+            .collect::<Vec<_>>();
+        let mut listener_ids = Vec::new();
+        if !moved.is_empty() {
+            self.stop_listeners(moved).await?;
+            listener_ids = match self.listen_on(new_addrs).await {
+                Ok(listener_ids) => listener_ids,
+                Err(e) => {
+                    if let Err(e) = self.listen_on(old_addrs).await {
+                        log::warn!("Failed to listen on the old addresses again: {e}");
+                    }
+                    return Err(e);
+                }
+            };
+        }
+        let mut setting = self.state.setting.lock().await;
+        setting.listen = config;
+        setting.save(&self.state.config_dir).await?;
+        Ok(listener_ids)
+    }
     pub async fn setting(&self) -> Setting {
         self.state.setting.lock().await.to_owned()
     }
     pub async fn dial(&self, addr: Multiaddr) -> Result<(), NetworkError> {
-        let peer_id = match addr.iter().last() {
-            Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).expect("Valid hash."),
-            _ => {
-                return Err(NetworkError::InvalidAddress(
-                    "Expect peer multiaddr to contain peer ID.".to_string(),
-                ))
-            }
-        };
-        self.client.dial(peer_id, addr).await
+        let peer_id = network::peer_id_of(&addr)?;
+        self.client.dial(peer_id, Some(addr)).await
+    }
+    /// Dial a peer known only by its id, looking up its addresses in the DHT.
+    pub async fn dial_peer(&self, peer_id: PeerId) -> Result<(), NetworkError> {
+        self.client.dial(peer_id, None).await
+    }
+    /// Replace the bootstrap nodes of the DHT and join it through them.
+    pub async fn set_bootstrap_nodes(&self, nodes: Vec<Multiaddr>) -> Result<(), NetworkError> {
+        let mut setting = self.state.setting.lock().await;
+        self.client.bootstrap(nodes.clone()).await?;
+        setting.bootstrap = nodes;
+        setting.save(&self.state.config_dir).await?;
+        Ok(())
     }
     pub async fn publish_message(
         &self,
@@ -90,10 +218,149 @@ impl AppCommandHandle {
         Ok(res)
     }
 
+    pub async fn get_transfers(&self) -> Vec<TransferInfo> {
+        self.file_manager.get_transfers().await
+    }
+    pub async fn pause_transfer(&self, id: Uuid) -> Result<(), NetworkError> {
+        self.file_manager.pause(&id).await
+    }
+    pub async fn resume_transfer(&self, id: Uuid) -> Result<(), NetworkError> {
+        self.file_manager.resume(&id).await
+    }
+    pub async fn cancel_transfer(&self, id: Uuid) -> Result<(), NetworkError> {
+        self.file_manager.cancel(&id).await
+    }
+
     pub fn get_managers(&self) -> Vec<String> {
         self.managers.keys().cloned().collect()
     }
     pub fn get_local_peer_id(&self) -> PeerId {
         self.client.local_peer_id()
     }
+    pub async fn get_identity(&self) -> IdentityInfo {
+        self.state.identity.lock().await.info().await
+    }
+    pub async fn export_identity(
+        &self,
+        path: PathBuf,
+        passphrase: String,
+    ) -> Result<(), NetworkError> {
+        let data = self
+            .state
+            .identity
+            .lock()
+            .await
+            .export_encrypted(&passphrase)?;
+        fs::write(path, data).await?;
+        Ok(())
+    }
+    pub async fn import_identity(
+        &self,
+        path: PathBuf,
+        passphrase: String,
+    ) -> Result<IdentityInfo, NetworkError> {
+        let data = fs::read(path).await?;
+        let keypair = NodeIdentity::import_encrypted(&data, &passphrase)?;
+        self.apply_identity(keypair).await
+    }
+    pub async fn export_recovery_phrase(&self) -> Result<String, NetworkError> {
+        Ok(self.state.identity.lock().await.recovery_phrase()?)
+    }
+    pub async fn import_recovery_phrase(
+        &self,
+        phrase: String,
+    ) -> Result<IdentityInfo, NetworkError> {
+        let keypair = NodeIdentity::from_recovery_phrase(&phrase)?;
+        self.apply_identity(keypair).await
+    }
+    /// Persist `keypair` as the node identity and restart the swarm with it. The previous
+    /// identity is restored if the swarm can not be rebuilt.
+    async fn apply_identity(
+        &self,
+        keypair: ed25519::Keypair,
+    ) -> Result<IdentityInfo, NetworkError> {
+        let mut identity = self.state.identity.lock().await;
+        let config = SwarmConfig::from(&*self.state.setting.lock().await);
+        let previous = identity.replace(keypair.clone()).await?;
+        if let Err(e) = self
+            .client
+            .rebuild(identity::Keypair::Ed25519(keypair), config)
+            .await
+        {
+            identity.replace(previous).await?;
+            return Err(e);
+        }
+        self.state.local_user.lock().await.peer_id = Some(identity.peer_id());
+        log::info!("Switched node identity to {}", identity.peer_id());
+        Ok(identity.info().await)
+    }
+    /// Generate a new network key and switch to it. The returned text is to be imported on
+    /// every other node that should stay connected.
+    pub async fn generate_network_key(&self) -> Result<String, NetworkError> {
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        let key = PreSharedKey::new(key);
+        self.apply_network_key(Some(key)).await?;
+        Ok(key.to_string())
+    }
+    pub async fn show_network_key(&self) -> Option<String> {
+        self.state
+            .setting
+            .lock()
+            .await
+            .network_key
+            .map(|key| key.to_string())
+    }
+    pub async fn import_network_key(&self, key: String) -> Result<(), NetworkError> {
+        let key = key.trim().parse::<PreSharedKey>()?;
+        self.apply_network_key(Some(key)).await
+    }
+    pub async fn remove_network_key(&self) -> Result<(), NetworkError> {
+        self.apply_network_key(None).await
+    }
+    /// Persist `network_key` in the setting and restart the swarm with it, which drops the
+    /// connections to nodes that do not have the same key.
+    async fn apply_network_key(
+        &self,
+        network_key: Option<PreSharedKey>,
+    ) -> Result<(), NetworkError> {
+        self.apply_swarm_setting(|setting| setting.network_key = network_key)
+            .await?;
+        match network_key {
+            Some(key) => log::info!("Joined private network {}", key.fingerprint()),
+            None => log::info!("Left private network"),
+        }
+        Ok(())
+    }
+    /// Start or stop relaying connections for other nodes.
+    pub async fn set_relay_server(&self, enabled: bool) -> Result<(), NetworkError> {
+        self.apply_swarm_setting(|setting| setting.relay_server = enabled)
+            .await
+    }
+    /// Change the parts of the setting the swarm is built from, restart the swarm with them and
+    /// persist them once it is running.
+    async fn apply_swarm_setting(
+        &self,
+        update: impl FnOnce(&mut Setting),
+    ) -> Result<(), NetworkError> {
+        let identity = self.state.identity.lock().await;
+        let mut setting = self.state.setting.lock().await;
+        let mut updated = setting.clone();
+        update(&mut updated);
+        self.client
+            .rebuild(identity.keypair(), SwarmConfig::from(&updated))
+            .await?;
+        *setting = updated;
+        setting.save(&self.state.config_dir).await?;
+        Ok(())
+    }
+}
+
+/// The addresses of `config`, without QUIC in private networks, which have none.
+fn listen_addresses(setting: &Setting, config: &ListenConfig) -> Vec<Multiaddr> {
+    let mut addrs = config.addresses();
+    if setting.network_key.is_some() {
+        addrs.retain(|addr| !network::is_quic(addr));
+    }
+    addrs
 }
@@ -2,10 +2,13 @@ use tauri::{AppHandle, Manager};
 
 use crate::{
     error::NetworkError,
-    models::{GroupId, GroupInfo, GroupMessage, UserInfo},
+    models::{DirectMessage, FileInfo, GroupId, GroupInfo, GroupMessage, ReconnectState, UserInfo},
+    transfer::TransferProgress,
 };
 use libp2p::{self, swarm::derive_prelude::ListenerId, Multiaddr, PeerId};
+use std::path::PathBuf;
 use tokio::sync::mpsc;
+use uuid::Uuid;
 
 use super::AppState;
 
@@ -24,6 +27,18 @@ pub enum FrontendEvent {
         group_id: GroupId,
         message: GroupMessage,
     },
+    HistorySync {
+        group_id: GroupId,
+        messages: Vec<GroupMessage>,
+    },
+    DirectMessage {
+        peer_id: PeerId,
+        message: DirectMessage,
+    },
+    DirectMessageDelivered {
+        peer_id: PeerId,
+        id: Uuid,
+    },
     Subscribed {
         group_id: GroupId,
         peer_id: PeerId,
@@ -40,6 +55,30 @@ pub enum FrontendEvent {
         peer_id: PeerId,
         user_info: UserInfo,
     },
+    ReconnectState {
+        peer_id: PeerId,
+        state: ReconnectState,
+    },
+    TransferStarted {
+        id: Uuid,
+        file: FileInfo,
+        peer_id: PeerId,
+    },
+    TransferProgress(TransferProgress),
+    TransferPaused {
+        id: Uuid,
+    },
+    TransferCompleted {
+        id: Uuid,
+        path: PathBuf,
+    },
+    TransferFailed {
+        id: Uuid,
+        error: String,
+    },
+    TransferCancelled {
+        id: Uuid,
+    },
     BackendError(NetworkError),
 }
 
@@ -65,6 +104,16 @@ impl FrontendEventLoop {
                     FrontendEvent::Message { group_id, message } => {
                         app.emit_all("message", (group_id, message)).unwrap();
                     }
+                    FrontendEvent::HistorySync { group_id, messages } => {
+                        app.emit_all("history-sync", (group_id, messages)).unwrap();
+                    }
+                    FrontendEvent::DirectMessage { peer_id, message } => {
+                        app.emit_all("direct-message", (peer_id, message)).unwrap();
+                    }
+                    FrontendEvent::DirectMessageDelivered { peer_id, id } => {
+                        app.emit_all("direct-message-delivered", (peer_id, id))
+                            .unwrap();
+                    }
                     FrontendEvent::BackendError(err) => {
                         log::error!("{err}");
                         app.emit_all("error", err.to_string()).unwrap()
@@ -92,6 +141,28 @@ impl FrontendEventLoop {
                         app.emit_all(&format!("user-update"), (peer_id, user_info))
                             .unwrap();
                     }
+                    FrontendEvent::ReconnectState { peer_id, state } => {
+                        app.emit_all("reconnect-state", (peer_id, state)).unwrap();
+                    }
+                    FrontendEvent::TransferStarted { id, file, peer_id } => {
+                        app.emit_all("transfer-started", (id, file, peer_id))
+                            .unwrap();
+                    }
+                    FrontendEvent::TransferProgress(progress) => {
+                        app.emit_all("transfer-progress", progress).unwrap();
+                    }
+                    FrontendEvent::TransferPaused { id } => {
+                        app.emit_all("transfer-paused", id).unwrap();
+                    }
+                    FrontendEvent::TransferCompleted { id, path } => {
+                        app.emit_all("transfer-completed", (id, path)).unwrap();
+                    }
+                    FrontendEvent::TransferFailed { id, error } => {
+                        app.emit_all("transfer-failed", (id, error)).unwrap();
+                    }
+                    FrontendEvent::TransferCancelled { id } => {
+                        app.emit_all("transfer-cancelled", id).unwrap();
+                    }
                 }
             });
         }
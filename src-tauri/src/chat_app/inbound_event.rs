@@ -4,6 +4,7 @@ use crate::{
     error::NetworkError,
     managers::{AppManager, HandleInboundEvent},
     network::{
+        self,
         message::{self, InboundEvent},
         Client,
     },
@@ -13,6 +14,7 @@ use futures::{
     future::{join_all, try_join_all},
     FutureExt,
 };
+use libp2p::{swarm::derive_prelude::ListenerId, Multiaddr};
 use tokio::sync::mpsc;
 
 use super::{frontend_event::FrontendEvent, AppState};
@@ -49,9 +51,50 @@ impl InboundEventLoop {
         Ok(())
     }
 
+    /// Replace the address `listener_id` was requested on in the setting with the one it was
+    /// bound to, so that the ports the OS picked are reused on the next start.
+    async fn save_bound_ports(
+        &self,
+        listener_id: ListenerId,
+        bound: &Multiaddr,
+    ) -> Result<(), NetworkError> {
+        let Some(requested) = self
+            .client
+            .listen_requests
+            .lock()
+            .await
+            .get(&listener_id)
+            .cloned()
+        else {
+            return Ok(());
+        };
+        let addr = network::with_bound_ports(&requested, bound);
+        let mut setting = self.state.setting.lock().await;
+        // unless it is not saved yet, in which case it is saved with its ports right away
+        let Some(position) = setting
+            .listen_addrs
+            .iter()
+            .position(|saved| *saved == requested)
+        else {
+            return Ok(());
+        };
+        if addr == requested {
+            return Ok(());
+        }
+        if setting.listen_addrs.contains(&addr) {
+            setting.listen_addrs.remove(position);
+        } else {
+            setting.listen_addrs[position] = addr;
+        }
+        setting.save(&self.state.config_dir).await?;
+        Ok(())
+    }
+
     async fn handle_event_default(&mut self, event: InboundEvent) -> Result<(), NetworkError> {
         match event {
-            InboundEvent::InboundRequest { request, channel } => {
+            InboundEvent::InboundRequest {
+                request, channel, ..
+            } => {
                 if let Some(_channel) = channel.lock().await.take() {
                     log::warn!("request not handled {request:?}");
                 }
@@ -60,15 +103,13 @@ impl InboundEventLoop {
                 listener_id,
                 address,
             } => {
-                let addresses = self
-                    .client
-                    .listeners
-                    .lock()
-                    .await
-                    .entry(listener_id)
-                    .and_modify(|e| e.push(address.clone()))
-                    .or_default()
-                    .clone();
+                let addresses = {
+                    let mut listeners = self.client.listeners.lock().await;
+                    let addresses = listeners.entry(listener_id).or_default();
+                    addresses.push(address.clone());
+                    addresses.clone()
+                };
+                self.save_bound_ports(listener_id, &address).await?;
 
                 self.frontend_sender
                     .send(FrontendEvent::Listen {
@@ -1,13 +1,23 @@
-use std::{collections::HashMap, sync::Arc};
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+};
 use tauri::AppHandle;
 
 use crate::{
-    managers::{group::GroupManager, user::UserManager, AppManager, Invoke},
-    models::{LocalUserInfo, Setting},
-    network::{self, EventLoop},
+    identity::NodeIdentity,
+    managers::{
+        direct::DirectManager, file::FileManager, group::GroupManager, peer::PeerManager,
+        user::UserManager, AppManager, Invoke,
+    },
+    models::{GroupId, LocalUserInfo, Setting},
+    network::{self, EventLoop, SwarmConfig},
+    store::SledStore,
 };
 use tokio::{
-    join,
+    fs, join,
     sync::{mpsc, Mutex},
 };
 
@@ -23,48 +33,119 @@ use self::{
 pub struct AppState {
     pub(super) setting: Arc<Mutex<Setting>>,
     pub(super) local_user: Arc<Mutex<LocalUserInfo>>,
+    pub(super) identity: Arc<Mutex<NodeIdentity>>,
+    pub(super) config_dir: PathBuf,
 }
 
-impl Default for AppState {
-    fn default() -> Self {
+impl AppState {
+    pub fn new(config_dir: PathBuf, setting: Setting, identity: NodeIdentity) -> Self {
+        let local_user = LocalUserInfo {
+            peer_id: Some(identity.peer_id()),
+            ..Default::default()
+        };
         Self {
-            setting: Arc::new(Mutex::new(Setting::default())),
-            local_user: Arc::new(Mutex::new(LocalUserInfo::default())),
+            setting: Arc::new(Mutex::new(setting)),
+            local_user: Arc::new(Mutex::new(local_user)),
+            identity: Arc::new(Mutex::new(identity)),
+            config_dir,
         }
     }
 }
 
 pub struct ChatApp {
     pub app: AppHandle,
-    pub state: AppState,
+    pub state: Option<AppState>,
     pub client: Option<network::Client>,
     network_eventloop: Option<EventLoop>,
     inbound_eventloop: Option<InboundEventLoop>,
     frontend_eventloop: Option<FrontendEventLoop>,
     managers: HashMap<String, Box<dyn Invoke>>,
+    file_manager: Option<FileManager>,
+    peer_manager: Option<PeerManager>,
+    restored_groups: Vec<GroupId>,
+    /// Members of the restored groups, looked up in the DHT once the network is up.
+    restored_members: HashSet<PeerId>,
 }
 
 impl ChatApp {
     pub fn new(app: AppHandle) -> Self {
         Self {
             app,
-            state: AppState::default(),
+            state: None,
             client: None,
             network_eventloop: None,
             inbound_eventloop: None,
             frontend_eventloop: None,
             managers: HashMap::new(),
+            file_manager: None,
+            peer_manager: None,
+            restored_groups: Vec::new(),
+            restored_members: HashSet::new(),
         }
     }
 
-    pub fn initialize(&mut self) -> anyhow::Result<()> {
-        let network = network::new(None)?;
+    pub async fn initialize(&mut self) -> anyhow::Result<()> {
+        let config_dir = self
+            .app
+            .path_resolver()
+            .app_config_dir()
+            .unwrap_or_else(|| PathBuf::from("."));
+        fs::create_dir_all(&config_dir).await?;
+        let setting = match Setting::load(&config_dir).await {
+            Ok(setting) => setting,
+            Err(e) => {
+                log::warn!("failed to load setting, using default: {e}");
+                Setting::default()
+            }
+        };
+        let identity = NodeIdentity::load_or_generate(&config_dir).await?;
+        log::info!("Local peer id: {}", identity.peer_id());
+
+        let network = network::new(identity.keypair(), SwarmConfig::from(&setting))?;
+        let state = AppState::new(config_dir, setting, identity);
+        self.state = Some(state.clone());
         self.client = Some(network.client.clone());
         self.network_eventloop = Some(network.event_loop);
         let (frontend_sender, frontend_receiver) = mpsc::channel(100);
 
-        let group = GroupManager::new();
+        let data_dir = self
+            .app
+            .path_resolver()
+            .app_data_dir()
+            .unwrap_or_else(|| PathBuf::from("."));
+        fs::create_dir_all(&data_dir).await?;
+        let store = SledStore::open(data_dir.join("store"))?;
+
+        let group = GroupManager::new(
+            store.clone(),
+            network.client.group_keys.clone(),
+            state.identity.clone(),
+        )?;
+        group.restore_group_keys().await?;
+        self.restored_groups = group.get_groups().await.into_keys().collect();
+        for group_id in &self.restored_groups {
+            self.restored_members
+                .extend(group.get_subscribers(group_id).await);
+        }
+        self.restored_members.remove(&identity.peer_id());
         let user = UserManager::new();
+        let file = FileManager::new(
+            network.client.clone(),
+            state.setting.clone(),
+            frontend_sender.clone(),
+            data_dir.join("downloads"),
+        );
+        file.restore_downloads().await?;
+        file.restore_offers(&store).await?;
+        self.file_manager = Some(file.clone());
+        let direct = DirectManager::new(
+            store.clone(),
+            network.client.clone(),
+            frontend_sender.clone(),
+            state.identity.clone(),
+        );
+        let peer = PeerManager::new(store, network.client.clone(), frontend_sender.clone());
+        self.peer_manager = Some(peer.clone());
         self.managers = [
             (
                 group.name().to_string(),
@@ -74,6 +155,18 @@ impl ChatApp {
                 user.name().to_string(),
                 Box::new(user.clone()) as Box<dyn Invoke>,
             ),
+            (
+                file.name().to_string(),
+                Box::new(file.clone()) as Box<dyn Invoke>,
+            ),
+            (
+                direct.name().to_string(),
+                Box::new(direct.clone()) as Box<dyn Invoke>,
+            ),
+            (
+                peer.name().to_string(),
+                Box::new(peer.clone()) as Box<dyn Invoke>,
+            ),
         ]
         .into();
 
@@ -81,13 +174,19 @@ impl ChatApp {
             client: network.client.clone(),
             inbound_event_receiver: network.event_receiver,
             frontend_sender: frontend_sender.clone(),
-            state: self.state.clone(),
-            managers: vec![Box::new(group), Box::new(user)],
+            state: state.clone(),
+            managers: vec![
+                Box::new(group),
+                Box::new(user),
+                Box::new(file),
+                Box::new(direct),
+                Box::new(peer),
+            ],
         });
         self.frontend_eventloop = Some(FrontendEventLoop {
             app: self.app.clone(),
             frontend_receiver,
-            state: self.state.clone(),
+            state,
         });
         Ok(())
     }
@@ -101,22 +200,74 @@ impl ChatApp {
         let Some(frontend_eventloop) = self.frontend_eventloop else {
             anyhow::bail!("frontend event loop is not initialized");
         };
-        let (_, _, _) = join![
-            tokio::spawn(network_eventloop.run()),
-            tokio::spawn(inbound_event_loop.run()),
-            tokio::spawn(frontend_eventloop.run())
-        ];
+        let Some(client) = self.client else {
+            anyhow::bail!("client is not initialized");
+        };
+        let network_task = tokio::spawn(network_eventloop.run());
+        let inbound_task = tokio::spawn(inbound_event_loop.run());
+        let frontend_task = tokio::spawn(frontend_eventloop.run());
+
+        let (listen_addrs, bootstrap) = match &self.state {
+            Some(state) => {
+                let setting = state.setting.lock().await;
+                let mut listen_addrs = setting.listen_addrs.clone();
+                // private networks have no QUIC
+                if setting.network_key.is_some() {
+                    listen_addrs.retain(|addr| !network::is_quic(addr));
+                }
+                (listen_addrs, setting.bootstrap.clone())
+            }
+            None => (Vec::new(), Vec::new()),
+        };
+        for addr in listen_addrs {
+            if let Err(e) = client.start_listening(addr.clone()).await {
+                log::warn!("failed to listen on {addr}: {e}");
+            }
+        }
+        if let Err(e) = client.bootstrap(bootstrap).await {
+            log::warn!("failed to join the DHT: {e}");
+        }
+
+        // rejoin the groups restored from the store
+        for group_id in self.restored_groups {
+            if let Err(e) = client.subscribe(group_id.topic()).await {
+                log::warn!("failed to resubscribe to group {group_id}: {e}");
+            }
+        }
+        if let Some(peer_manager) = &self.peer_manager {
+            if let Err(e) = peer_manager.reconnect_known_peers().await {
+                log::warn!("failed to reconnect to known peers: {e}");
+            }
+        }
+        // members outside the local network are only reachable through the DHT
+        for peer_id in self.restored_members {
+            let client = client.clone();
+            tokio::spawn(async move {
+                if let Err(e) = client.dial(peer_id, None).await {
+                    log::debug!("failed to reach group member {peer_id}: {e}");
+                }
+            });
+        }
+
+        let (_, _, _) = join![network_task, inbound_task, frontend_task];
         Ok(())
     }
     pub fn command_handle(&self) -> anyhow::Result<AppCommandHandle> {
         let  Some(client) = &self.client else {
             anyhow::bail!("client is not initialized");
         };
+        let Some(state) = &self.state else {
+            anyhow::bail!("state is not initialized");
+        };
+        let Some(file_manager) = &self.file_manager else {
+            anyhow::bail!("file manager is not initialized");
+        };
 
         Ok(AppCommandHandle {
             client: client.clone(),
-            state: self.state.clone(),
+            state: state.clone(),
             managers: self.managers.clone(),
+            file_manager: file_manager.clone(),
         })
     }
 }
@@ -0,0 +1,388 @@
+use std::{cmp::Reverse, collections::HashMap, fmt};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use chrono::Utc;
+use libp2p::{gossipsub::TopicHash, identity, PeerId};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{error::CryptoError, models::GroupId};
+
+const SEALED_VERSION: u8 = 1;
+const KEY_LEN: usize = 32;
+const KEY_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = 1 + KEY_ID_LEN + NONCE_LEN;
+/// Peer ids embed ed25519 keys as is instead of hashing them.
+const IDENTITY_MULTIHASH_CODE: u64 = 0;
+const INVITE_CONTEXT: &str = "group-invite";
+
+/// The public key `peer_id` is made of.
+pub fn identity_key(peer_id: &PeerId) -> Result<identity::PublicKey, CryptoError> {
+    let unsupported = || CryptoError::UnsupportedPeer(peer_id.to_string());
+    let multihash = peer_id.as_ref();
+    if multihash.code() != IDENTITY_MULTIHASH_CODE {
+        return Err(unsupported());
+    }
+    identity::PublicKey::from_protobuf_encoding(multihash.digest()).map_err(|_| unsupported())
+}
+
+/// Proof that `invitee` was let into a group by `inviter`, signed by the inviter. A member is
+/// admitted once it has an invite from someone already known to be a member, so the invites of
+/// a group form a tree rooted in the founder, who invites itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupInvite {
+    pub group_id: GroupId,
+    pub invitee: PeerId,
+    pub inviter: PeerId,
+    /// When the invite was signed, in milliseconds since the epoch. A revocation voids the
+    /// invites issued to a peer until then, but not the ones it is invited back with later.
+    pub issued_at: i64,
+    #[serde(with = "hex_bytes")]
+    signature: Vec<u8>,
+}
+
+impl GroupInvite {
+    pub fn sign(
+        keypair: &identity::Keypair,
+        group_id: GroupId,
+        invitee: PeerId,
+    ) -> Result<Self, CryptoError> {
+        let inviter = keypair.public().to_peer_id();
+        let issued_at = Utc::now().timestamp_millis();
+        let signature = keypair
+            .sign(&Self::signed_bytes(
+                &group_id, &invitee, &inviter, issued_at,
+            ))
+            .map_err(|_| CryptoError::Signing)?;
+        Ok(Self {
+            group_id,
+            invitee,
+            inviter,
+            issued_at,
+            signature,
+        })
+    }
+
+    /// Whether the founder of the group issued this invite to itself.
+    pub fn is_founder(&self) -> bool {
+        self.invitee == self.inviter
+    }
+
+    pub fn verify(&self) -> Result<(), CryptoError> {
+        let signed =
+            Self::signed_bytes(&self.group_id, &self.invitee, &self.inviter, self.issued_at);
+        if identity_key(&self.inviter)?.verify(&signed, &self.signature) {
+            Ok(())
+        } else {
+            Err(CryptoError::InvalidSignature)
+        }
+    }
+
+    fn signed_bytes(
+        group_id: &GroupId,
+        invitee: &PeerId,
+        inviter: &PeerId,
+        issued_at: i64,
+    ) -> Vec<u8> {
+        [
+            INVITE_CONTEXT.as_bytes(),
+            group_id.as_bytes().as_slice(),
+            invitee.to_bytes().as_slice(),
+            inviter.to_bytes().as_slice(),
+            issued_at.to_be_bytes().as_slice(),
+        ]
+        .concat()
+    }
+}
+
+/// The members of a group, each with the invite it was admitted with, and the peers that left
+/// it. Exchanged between members so that revocations reach those that did not see the peer go.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GroupMembers {
+    invites: HashMap<PeerId, GroupInvite>,
+    /// When each peer that left was revoked, in milliseconds since the epoch.
+    revoked: HashMap<PeerId, i64>,
+}
+
+impl GroupMembers {
+    /// Start from the founder invites among `invites`, trusting them as is. Used when joining a
+    /// group, the founder being whoever the invite was received from says it is.
+    pub fn from_founders(invites: &[GroupInvite]) -> Self {
+        let invites = invites
+            .iter()
+            .filter(|invite| invite.is_founder() && invite.verify().is_ok())
+            .map(|invite| (invite.invitee, invite.clone()))
+            .collect();
+        Self {
+            invites,
+            revoked: HashMap::new(),
+        }
+    }
+
+    pub fn contains(&self, peer_id: &PeerId) -> bool {
+        self.invites.contains_key(peer_id)
+    }
+
+    pub fn invites(&self) -> Vec<GroupInvite> {
+        self.invites.values().cloned().collect()
+    }
+
+    fn is_revoked(&self, invite: &GroupInvite) -> bool {
+        self.revoked
+            .get(&invite.invitee)
+            .map_or(false, |revoked_at| invite.issued_at <= *revoked_at)
+    }
+
+    /// Admit the invitees of `invites` whose inviter is a member, directly or through another
+    /// of `invites`, returning `true` if anyone new was admitted. Founder invites of unknown
+    /// peers are never admitted, so nobody can root a new tree in an existing group, and
+    /// neither are invites voided by a revocation.
+    pub fn admit<I>(&mut self, group_id: &GroupId, invites: I) -> bool
+    where
+        I: IntoIterator<Item = GroupInvite>,
+    {
+        let mut pending = invites
+            .into_iter()
+            .filter(|invite| &invite.group_id == group_id && !self.contains(&invite.invitee))
+            .filter(|invite| !self.is_revoked(invite) && invite.verify().is_ok())
+            .collect::<Vec<_>>();
+        let mut admitted = false;
+        loop {
+            let (ready, rest): (Vec<_>, Vec<_>) = pending
+                .into_iter()
+                .partition(|invite| self.contains(&invite.inviter));
+            if ready.is_empty() {
+                return admitted;
+            }
+            for invite in ready {
+                admitted |= self.invites.insert(invite.invitee, invite).is_none();
+            }
+            pending = rest;
+        }
+    }
+
+    /// Void the invites issued to `peer_id` until `revoked_at`, returning `true` if anything
+    /// changed. The peers it invited stay members.
+    pub fn revoke(&mut self, peer_id: PeerId, revoked_at: i64) -> bool {
+        let revoked = self.revoked.entry(peer_id).or_insert(i64::MIN);
+        if *revoked >= revoked_at {
+            return false;
+        }
+        *revoked = revoked_at;
+        if self
+            .invites
+            .get(&peer_id)
+            .map_or(false, |invite| invite.issued_at <= revoked_at)
+        {
+            self.invites.remove(&peer_id);
+        }
+        true
+    }
+
+    /// Take on the members and revocations `other` knows of. Revocations are only taken from a
+    /// `sender` that is a member itself, once its invites were admitted. Returns `true` if
+    /// anything changed.
+    pub fn merge(&mut self, group_id: &GroupId, sender: &PeerId, other: GroupMembers) -> bool {
+        let mut changed = self.admit(group_id, other.invites.into_values());
+        if self.contains(sender) {
+            for (peer_id, revoked_at) in other.revoked {
+                changed |= self.revoke(peer_id, revoked_at);
+            }
+        }
+        changed
+    }
+}
+
+/// A symmetric key shared by the members of a group. Group messages are sealed with the
+/// current key before they are published, so relaying peers only ever see ciphertext.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupKey {
+    /// Incremented every time the key is rotated.
+    pub epoch: u64,
+    /// The member that generated the key.
+    pub issuer: PeerId,
+    #[serde(with = "hex_key")]
+    key: [u8; KEY_LEN],
+}
+
+impl fmt::Debug for GroupKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GroupKey")
+            .field("epoch", &self.epoch)
+            .field("issuer", &self.issuer)
+            .field("id", &hex::encode(self.id()))
+            .finish()
+    }
+}
+
+impl GroupKey {
+    pub fn generate(epoch: u64, issuer: PeerId) -> Self {
+        let mut key = [0; KEY_LEN];
+        rand::thread_rng().fill_bytes(&mut key);
+        Self { epoch, issuer, key }
+    }
+
+    /// A short identifier of the key, sent along with every message sealed with it.
+    pub fn id(&self) -> [u8; KEY_ID_LEN] {
+        Sha256::digest(self.key)[..KEY_ID_LEN]
+            .try_into()
+            .expect("Digest to be longer than a key id.")
+    }
+
+    /// Later epochs win. Keys rotated concurrently by different members share an epoch, in
+    /// which case the one of the lowest issuer wins so that all members settle on the same key.
+    fn rank(&self) -> (u64, Reverse<PeerId>) {
+        (self.epoch, Reverse(self.issuer))
+    }
+}
+
+/// The keys of every group the local node is a member of. Shared between the network event
+/// loop, which seals and opens group messages, and the group manager, which distributes and
+/// rotates the keys. Superseded keys are kept so that messages sent just before a rotation can
+/// still be opened.
+#[derive(Debug, Default)]
+pub struct GroupKeys {
+    keys: HashMap<TopicHash, Vec<GroupKey>>,
+}
+
+impl GroupKeys {
+    /// The key new messages to `topic` are sealed with.
+    pub fn current(&self, topic: &TopicHash) -> Option<&GroupKey> {
+        self.keys.get(topic)?.iter().max_by_key(|key| key.rank())
+    }
+
+    pub fn all(&self, topic: &TopicHash) -> Vec<GroupKey> {
+        self.keys.get(topic).cloned().unwrap_or_default()
+    }
+
+    /// Add keys of `topic`, returning `true` if any of them was not known yet.
+    pub fn insert<I>(&mut self, topic: TopicHash, keys: I) -> bool
+    where
+        I: IntoIterator<Item = GroupKey>,
+    {
+        let known = self.keys.entry(topic).or_default();
+        let mut added = false;
+        for key in keys {
+            if !known.iter().any(|known| known.id() == key.id()) {
+                known.push(key);
+                added = true;
+            }
+        }
+        added
+    }
+
+    pub fn remove(&mut self, topic: &TopicHash) {
+        self.keys.remove(topic);
+    }
+
+    /// Encrypt `plaintext` with the current key of `topic`. The topic is authenticated along
+    /// with the message so that it cannot be replayed into another group.
+    pub fn seal(&self, topic: &TopicHash, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let key = self
+            .current(topic)
+            .ok_or_else(|| CryptoError::MissingKey(topic.to_string()))?;
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&key.key))
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: topic.as_str().as_bytes(),
+                },
+            )
+            .map_err(|_| CryptoError::Encryption)?;
+        Ok([
+            [SEALED_VERSION].as_slice(),
+            key.id().as_slice(),
+            nonce.as_slice(),
+            ciphertext.as_slice(),
+        ]
+        .concat())
+    }
+
+    /// Decrypt a message sealed by [`GroupKeys::seal`], with whichever key of `topic` it was
+    /// sealed with.
+    pub fn open(&self, topic: &TopicHash, sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if sealed.len() < HEADER_LEN || sealed[0] != SEALED_VERSION {
+            return Err(CryptoError::InvalidEnvelope);
+        }
+        let (key_id, rest) = sealed[1..].split_at(KEY_ID_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let key = self
+            .keys
+            .get(topic)
+            .and_then(|keys| keys.iter().find(|key| key.id() == key_id))
+            .ok_or_else(|| CryptoError::UnknownKey(topic.to_string()))?;
+        ChaCha20Poly1305::new(Key::from_slice(&key.key))
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: topic.as_str().as_bytes(),
+                },
+            )
+            .map_err(|_| CryptoError::Decryption)
+    }
+}
+
+pub(crate) mod hex_bytes {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let bytes = String::deserialize(deserializer)?;
+        hex::decode(bytes).map_err(D::Error::custom)
+    }
+}
+
+pub(crate) mod hex_key {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use super::KEY_LEN;
+
+    pub fn serialize<S: Serializer>(key: &[u8; KEY_LEN], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(key))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<[u8; KEY_LEN], D::Error> {
+        let key = String::deserialize(deserializer)?;
+        hex::FromHex::from_hex(key).map_err(D::Error::custom)
+    }
+
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        use super::KEY_LEN;
+
+        pub fn serialize<S: Serializer>(
+            key: &Option<[u8; KEY_LEN]>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match key {
+                Some(key) => super::serialize(key, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<[u8; KEY_LEN]>, D::Error> {
+            #[derive(Deserialize)]
+            struct Key(#[serde(with = "super")] [u8; KEY_LEN]);
+            Ok(Option::<Key>::deserialize(deserializer)?.map(|Key(key)| key))
+        }
+    }
+}
@@ -1,7 +1,8 @@
-use std::{fmt::Display, io, path::PathBuf};
+use std::{fmt::Display, io, path::PathBuf, time::Duration};
 
 use libp2p::{
     gossipsub::error::{PublishError, SubscriptionError},
+    pnet::KeyParseError,
     request_response::OutboundFailure,
     swarm::DialError,
     PeerId, TransportError,
@@ -9,7 +10,10 @@ use libp2p::{
 use serde::Serialize;
 use thiserror::Error;
 
-use crate::models::GroupId;
+use crate::{
+    models::GroupId,
+    network::message::{ErrorKind, ErrorResponse},
+};
 
 #[derive(Debug, Error, Serialize)]
 pub enum SettingErrorKind {
@@ -57,18 +61,58 @@ pub enum NetworkError {
     SubscriptionError(#[from] SubscriptionError),
     #[error("Request error: {0}")]
     RequestError(String),
+    #[error("Request timed out after {0:?}")]
+    RequestTimeout(Duration),
+    #[error("Not found by peer: {0}")]
+    NotFound(String),
+    #[error("Forbidden by peer: {0}")]
+    Forbidden(String),
+    #[error("Peer failed to answer the request: {0}")]
+    Internal(String),
     #[error(transparent)]
     SettingError(#[from] SettingError),
+    #[error(transparent)]
+    IdentityError(#[from] IdentityError),
+    #[error(transparent)]
+    StoreError(#[from] StoreError),
+    #[error(transparent)]
+    TransferError(#[from] TransferError),
+    #[error(transparent)]
+    CryptoError(#[from] CryptoError),
     #[error("Manager error: {0}")]
     ManagerError(#[from] ManagerError),
     #[error("invalid address: {0}")]
     InvalidAddress(String),
+    #[error("peer not found: {0}")]
+    PeerNotFound(PeerId),
+    #[error("invalid network key: {0}")]
+    InvalidNetworkKey(#[from] KeyParseError),
     #[error("command not found: {0}")]
     CommandNotFound(String),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
+impl NetworkError {
+    /// Whether the peer answered with an error, which it would answer again.
+    pub fn is_error_response(&self) -> bool {
+        matches!(
+            self,
+            Self::NotFound(_) | Self::Forbidden(_) | Self::Internal(_)
+        )
+    }
+}
+
+impl From<ErrorResponse> for NetworkError {
+    fn from(error: ErrorResponse) -> Self {
+        match error.kind {
+            ErrorKind::NotFound => Self::NotFound(error.message),
+            ErrorKind::Forbidden => Self::Forbidden(error.message),
+            ErrorKind::Internal => Self::Internal(error.message),
+        }
+    }
+}
+
 impl From<OutboundFailure> for NetworkError {
     fn from(value: OutboundFailure) -> Self {
         Self::RequestError(value.to_string())
@@ -84,6 +128,80 @@ impl Serialize for NetworkError {
     }
 }
 
+#[derive(Debug, Error)]
+pub enum IdentityError {
+    #[error("wrong passphrase")]
+    WrongPassphrase,
+    #[error("failed to encrypt identity")]
+    Encryption,
+    #[error("unsupported identity file version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("invalid identity file: {0}")]
+    InvalidKeyFile(String),
+    #[error("invalid recovery phrase: {0}")]
+    InvalidRecoveryPhrase(#[from] bip39::Error),
+    #[error("invalid identity file: {0}")]
+    InvalidEncoding(#[from] hex::FromHexError),
+    #[error("invalid identity file: {0}")]
+    InvalidFormat(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("database error: {0}")]
+    Database(#[from] sled::Error),
+    #[error("invalid stored value: {0}")]
+    InvalidValue(#[from] serde_json::Error),
+    #[error("invalid stored key: {0}")]
+    InvalidKey(#[from] uuid::Error),
+    #[error("invalid stored peer id: {0}")]
+    InvalidPeerId(String),
+}
+
+#[derive(Debug, Error)]
+pub enum TransferError {
+    #[error("file not provided by peer: {0}")]
+    Unavailable(String),
+    #[error("no peer offers file: {0}")]
+    NoSource(String),
+    #[error("download not found: {0}")]
+    DownloadNotFound(uuid::Uuid),
+    #[error("chunk {0} failed verification")]
+    CorruptChunk(u64),
+    #[error("received file does not match its hash: {0}")]
+    HashMismatch(String),
+    #[error("invalid file name: {0}")]
+    InvalidFileName(String),
+    #[error("invalid manifest of file: {0}")]
+    InvalidManifest(String),
+}
+
+#[derive(Debug, Error)]
+pub enum CryptoError {
+    #[error("no key for group {0}")]
+    MissingKey(String),
+    #[error("message sealed with an unknown key of group {0}")]
+    UnknownKey(String),
+    #[error("failed to encrypt message")]
+    Encryption,
+    #[error("failed to decrypt message")]
+    Decryption,
+    #[error("invalid sealed message")]
+    InvalidEnvelope,
+    #[error("peer {0} has no ed25519 key to agree on a session with")]
+    UnsupportedPeer(String),
+    #[error("invalid public key")]
+    InvalidKey,
+    #[error("message belongs to a superseded session")]
+    StaleSession,
+    #[error("message skips too far ahead in its session")]
+    TooManySkipped,
+    #[error("failed to sign")]
+    Signing,
+    #[error("invalid signature")]
+    InvalidSignature,
+}
+
 #[derive(Debug, Error)]
 pub enum ManagerError {
     #[error("Group not exist {0}")]
@@ -94,4 +212,20 @@ pub enum ManagerError {
     InvalidParams(#[from] serde_json::Error),
     #[error("invalid action: {0}")]
     InvalidAction(String),
+    #[error("invalid invite: {0}")]
+    InvalidInvite(String),
+    #[error(transparent)]
+    StoreError(#[from] StoreError),
+    #[error(transparent)]
+    CryptoError(#[from] CryptoError),
+    #[error("IO error: {0}")]
+    IOError(#[from] io::Error),
+    #[error(transparent)]
+    NetworkError(Box<NetworkError>),
+}
+
+impl From<NetworkError> for ManagerError {
+    fn from(value: NetworkError) -> Self {
+        Self::NetworkError(Box::new(value))
+    }
 }
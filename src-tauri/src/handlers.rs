@@ -1,11 +1,14 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf};
 
 use libp2p::{swarm::derive_prelude::ListenerId, Multiaddr, PeerId};
+use uuid::Uuid;
 
 use crate::{
     chat_app::app_command::AppCommandHandle,
     error::NetworkError,
-    models::{GroupId, GroupInfo, Setting},
+    identity::IdentityInfo,
+    managers::file::TransferInfo,
+    models::{GroupId, GroupInfo, ListenConfig, Setting},
     network::message::Message,
 };
 
@@ -25,10 +28,15 @@ pub async fn get_listeners(
 pub async fn start_listen(
     handle: tauri::State<'_, AppCommandHandle>,
     listen_addr: Option<Multiaddr>,
-) -> Result<u64, NetworkError> {
+) -> Result<Vec<u64>, NetworkError> {
     handle.start_listen(listen_addr).await.map_or_else(
         |e| Err(e),
-        |id| Ok(unsafe { std::mem::transmute::<ListenerId, u64>(id) }),
+        |ids| {
+            Ok(ids
+                .into_iter()
+                .map(|id| unsafe { std::mem::transmute::<ListenerId, u64>(id) })
+                .collect())
+        },
     )
 }
 
@@ -53,6 +61,20 @@ pub async fn dail(
     handle.dial(addr).await
 }
 #[tauri::command]
+pub async fn dial_peer(
+    handle: tauri::State<'_, AppCommandHandle>,
+    peer_id: PeerId,
+) -> Result<(), NetworkError> {
+    handle.dial_peer(peer_id).await
+}
+#[tauri::command]
+pub async fn set_bootstrap_nodes(
+    handle: tauri::State<'_, AppCommandHandle>,
+    nodes: Vec<Multiaddr>,
+) -> Result<(), NetworkError> {
+    handle.set_bootstrap_nodes(nodes).await
+}
+#[tauri::command]
 pub async fn publish_message(
     handle: tauri::State<'_, AppCommandHandle>,
     group_id: GroupId,
@@ -105,3 +127,113 @@ pub fn get_local_peer_id(
 ) -> Result<PeerId, NetworkError> {
     Ok(handle.get_local_peer_id())
 }
+
+#[tauri::command]
+pub async fn get_identity(
+    handle: tauri::State<'_, AppCommandHandle>,
+) -> Result<IdentityInfo, NetworkError> {
+    Ok(handle.get_identity().await)
+}
+
+#[tauri::command]
+pub async fn export_identity(
+    handle: tauri::State<'_, AppCommandHandle>,
+    path: PathBuf,
+    passphrase: String,
+) -> Result<(), NetworkError> {
+    handle.export_identity(path, passphrase).await
+}
+
+#[tauri::command]
+pub async fn import_identity(
+    handle: tauri::State<'_, AppCommandHandle>,
+    path: PathBuf,
+    passphrase: String,
+) -> Result<IdentityInfo, NetworkError> {
+    handle.import_identity(path, passphrase).await
+}
+
+#[tauri::command]
+pub async fn export_recovery_phrase(
+    handle: tauri::State<'_, AppCommandHandle>,
+) -> Result<String, NetworkError> {
+    handle.export_recovery_phrase().await
+}
+
+#[tauri::command]
+pub async fn import_recovery_phrase(
+    handle: tauri::State<'_, AppCommandHandle>,
+    phrase: String,
+) -> Result<IdentityInfo, NetworkError> {
+    handle.import_recovery_phrase(phrase).await
+}
+#[tauri::command]
+pub async fn get_transfers(
+    handle: tauri::State<'_, AppCommandHandle>,
+) -> Result<Vec<TransferInfo>, NetworkError> {
+    Ok(handle.get_transfers().await)
+}
+#[tauri::command]
+pub async fn pause_transfer(
+    handle: tauri::State<'_, AppCommandHandle>,
+    id: Uuid,
+) -> Result<(), NetworkError> {
+    handle.pause_transfer(id).await
+}
+#[tauri::command]
+pub async fn resume_transfer(
+    handle: tauri::State<'_, AppCommandHandle>,
+    id: Uuid,
+) -> Result<(), NetworkError> {
+    handle.resume_transfer(id).await
+}
+#[tauri::command]
+pub async fn cancel_transfer(
+    handle: tauri::State<'_, AppCommandHandle>,
+    id: Uuid,
+) -> Result<(), NetworkError> {
+    handle.cancel_transfer(id).await
+}
+#[tauri::command]
+pub async fn generate_network_key(
+    handle: tauri::State<'_, AppCommandHandle>,
+) -> Result<String, NetworkError> {
+    handle.generate_network_key().await
+}
+#[tauri::command]
+pub async fn show_network_key(
+    handle: tauri::State<'_, AppCommandHandle>,
+) -> Result<Option<String>, String> {
+    Ok(handle.show_network_key().await)
+}
+#[tauri::command]
+pub async fn import_network_key(
+    handle: tauri::State<'_, AppCommandHandle>,
+    key: String,
+) -> Result<(), NetworkError> {
+    handle.import_network_key(key).await
+}
+#[tauri::command]
+pub async fn remove_network_key(
+    handle: tauri::State<'_, AppCommandHandle>,
+) -> Result<(), NetworkError> {
+    handle.remove_network_key().await
+}
+#[tauri::command]
+pub async fn set_relay_server(
+    handle: tauri::State<'_, AppCommandHandle>,
+    enabled: bool,
+) -> Result<(), NetworkError> {
+    handle.set_relay_server(enabled).await
+}
+#[tauri::command]
+pub async fn set_listen_config(
+    handle: tauri::State<'_, AppCommandHandle>,
+    config: ListenConfig,
+) -> Result<Vec<u64>, NetworkError> {
+    let ids = handle.set_listen_config(config).await?;
+    Ok(ids
+        .into_iter()
+        .map(|id| unsafe { std::mem::transmute::<ListenerId, u64>(id) })
+        .collect())
+}
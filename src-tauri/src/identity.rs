@@ -0,0 +1,230 @@
+use std::path::{Path, PathBuf};
+
+use argon2::Argon2;
+use bip39::Mnemonic;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use chrono::{DateTime, Utc};
+use libp2p::{
+    identity::{self, ed25519},
+    PeerId,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::{self, AsyncReadExt, AsyncWriteExt},
+};
+
+use crate::{error::IdentityError, ratchet::KeyPair};
+
+const IDENTITY_FILE_NAME: &str = "identity.key";
+const SESSIONS_DIR_NAME: &str = "sessions";
+const EXPORT_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// The ed25519 keypair of the local node, persisted under the app config directory
+/// so that the `PeerId` stays the same across restarts.
+#[derive(Debug, Clone)]
+pub struct NodeIdentity {
+    keypair: ed25519::Keypair,
+    path: PathBuf,
+}
+
+/// The on-disk format of an exported identity: the ed25519 secret key sealed with
+/// ChaCha20-Poly1305 under a key derived from the passphrase with Argon2id.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportedIdentity {
+    version: u8,
+    peer_id: PeerId,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IdentityInfo {
+    pub peer_id: PeerId,
+    pub key_path: PathBuf,
+    pub created_at: Option<i64>,
+}
+
+impl NodeIdentity {
+    /// Load the keypair stored in `dir`, generating and saving a new one if there is none yet.
+    pub async fn load_or_generate<P: AsRef<Path>>(dir: P) -> Result<Self, io::Error> {
+        let path = dir.as_ref().join(IDENTITY_FILE_NAME);
+        match Self::load(&path).await {
+            Ok(identity) => Ok(identity),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Self {
+                    keypair: ed25519::Keypair::generate(),
+                    path,
+                };
+                identity.save().await?;
+                log::info!("Generated new node identity {}", identity.peer_id());
+                Ok(identity)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn load(path: &Path) -> Result<Self, io::Error> {
+        let mut file = fs::File::open(path).await?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).await?;
+        let keypair = ed25519::Keypair::decode(&mut buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Self {
+            keypair,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub async fn save(&self) -> Result<(), io::Error> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&self.path).await?;
+        file.write_all(&self.keypair.encode()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    /// Replace the stored keypair, e.g. after importing an identity from another machine, and
+    /// return the previous one. The previous keypair is kept if the new one can not be saved.
+    pub async fn replace(
+        &mut self,
+        keypair: ed25519::Keypair,
+    ) -> Result<ed25519::Keypair, io::Error> {
+        let previous = std::mem::replace(&mut self.keypair, keypair);
+        if let Err(e) = self.save().await {
+            self.keypair = previous;
+            return Err(e);
+        }
+        Ok(previous)
+    }
+
+    /// Encrypt the secret key with `passphrase` into a self-describing JSON document.
+    pub fn export_encrypted(&self, passphrase: &str) -> Result<Vec<u8>, IdentityError> {
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let cipher = passphrase_cipher(passphrase, &salt)?;
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), self.keypair.secret().as_ref())
+            .map_err(|_| IdentityError::Encryption)?;
+
+        let exported = ExportedIdentity {
+            version: EXPORT_VERSION,
+            peer_id: self.peer_id(),
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        };
+        Ok(serde_json::to_vec_pretty(&exported)?)
+    }
+
+    /// Decrypt a keypair previously produced by [`NodeIdentity::export_encrypted`].
+    pub fn import_encrypted(
+        data: &[u8],
+        passphrase: &str,
+    ) -> Result<ed25519::Keypair, IdentityError> {
+        let exported = serde_json::from_slice::<ExportedIdentity>(data)?;
+        if exported.version != EXPORT_VERSION {
+            return Err(IdentityError::UnsupportedVersion(exported.version));
+        }
+        let salt = hex::decode(&exported.salt)?;
+        let nonce = hex::decode(&exported.nonce)?;
+        let ciphertext = hex::decode(&exported.ciphertext)?;
+        if nonce.len() != NONCE_LEN {
+            return Err(IdentityError::InvalidKeyFile(
+                "invalid nonce length".to_string(),
+            ));
+        }
+
+        let cipher = passphrase_cipher(passphrase, &salt)?;
+        let secret = cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| IdentityError::WrongPassphrase)?;
+        let keypair = keypair_from_secret(secret)?;
+
+        let peer_id = identity::PublicKey::Ed25519(keypair.public()).to_peer_id();
+        if peer_id != exported.peer_id {
+            return Err(IdentityError::InvalidKeyFile(
+                "key does not match the exported peer id".to_string(),
+            ));
+        }
+        Ok(keypair)
+    }
+
+    /// The 32-byte secret key encoded as a 24-word BIP39 recovery phrase.
+    pub fn recovery_phrase(&self) -> Result<String, IdentityError> {
+        let mnemonic = Mnemonic::from_entropy(self.keypair.secret().as_ref())?;
+        Ok(mnemonic.to_string())
+    }
+
+    /// Restore a keypair from a phrase produced by [`NodeIdentity::recovery_phrase`].
+    pub fn from_recovery_phrase(phrase: &str) -> Result<ed25519::Keypair, IdentityError> {
+        let mnemonic = Mnemonic::parse(phrase)?;
+        keypair_from_secret(mnemonic.to_entropy())
+    }
+
+    pub fn keypair(&self) -> identity::Keypair {
+        identity::Keypair::Ed25519(self.keypair.clone())
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        self.keypair().public().to_peer_id()
+    }
+
+    /// The X25519 key direct message sessions are agreed on with.
+    pub fn agreement_key(&self) -> KeyPair {
+        KeyPair::from_ed25519(&self.keypair)
+    }
+
+    /// Where the direct message sessions of this identity are kept, next to the key file. Each
+    /// identity has its own, so importing another one does not mix up their sessions.
+    pub fn sessions_dir(&self) -> PathBuf {
+        self.path
+            .with_file_name(SESSIONS_DIR_NAME)
+            .join(self.peer_id().to_string())
+    }
+
+    pub async fn info(&self) -> IdentityInfo {
+        let created_at = fs::metadata(&self.path)
+            .await
+            .and_then(|metadata| metadata.created().or_else(|_| metadata.modified()))
+            .ok()
+            .map(|time| DateTime::<Utc>::from(time).timestamp());
+        IdentityInfo {
+            peer_id: self.peer_id(),
+            key_path: self.path.clone(),
+            created_at,
+        }
+    }
+}
+
+fn passphrase_cipher(passphrase: &str, salt: &[u8]) -> Result<ChaCha20Poly1305, IdentityError> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| IdentityError::InvalidKeyFile(e.to_string()))?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+fn keypair_from_secret(mut secret: Vec<u8>) -> Result<ed25519::Keypair, IdentityError> {
+    let secret = ed25519::SecretKey::from_bytes(&mut secret)
+        .map_err(|e| IdentityError::InvalidKeyFile(e.to_string()))?;
+    Ok(secret.into())
+}
@@ -3,12 +3,17 @@
     windows_subsystem = "windows"
 )]
 mod chat_app;
+mod crypto;
 mod error;
 mod handlers;
+mod identity;
 mod managers;
 mod models;
 mod network;
+mod ratchet;
+mod search;
 mod store;
+mod transfer;
 
 use anyhow::Context;
 use tauri::{generate_handler, Manager};
@@ -49,6 +54,8 @@ async fn main() -> anyhow::Result<()> {
             handlers::stop_listen,
             handlers::setting,
             handlers::dail,
+            handlers::dial_peer,
+            handlers::set_bootstrap_nodes,
             handlers::publish_message,
             handlers::new_group,
             handlers::subscribe,
@@ -56,11 +63,26 @@ async fn main() -> anyhow::Result<()> {
             handlers::invoke_manager,
             handlers::get_managers,
             handlers::get_local_peer_id,
+            handlers::get_identity,
+            handlers::export_identity,
+            handlers::import_identity,
+            handlers::export_recovery_phrase,
+            handlers::import_recovery_phrase,
+            handlers::get_transfers,
+            handlers::pause_transfer,
+            handlers::resume_transfer,
+            handlers::cancel_transfer,
+            handlers::generate_network_key,
+            handlers::show_network_key,
+            handlers::import_network_key,
+            handlers::remove_network_key,
+            handlers::set_relay_server,
+            handlers::set_listen_config,
         ])
         .build(tauri::generate_context!())?;
 
     let mut chat_app = ChatApp::new(tauri_app.handle());
-    chat_app.initialize()?;
+    chat_app.initialize().await?;
     tauri_app.manage(chat_app.command_handle()?);
 
     local.spawn_local(async {
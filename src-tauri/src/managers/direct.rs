@@ -0,0 +1,327 @@
+use super::{AppManager, HandleInboundEvent, Invoke};
+use crate::{
+    chat_app::{frontend_event::FrontendEvent, AppState},
+    error::{CryptoError, ManagerError, NetworkError, StoreError},
+    identity::NodeIdentity,
+    models::{Conversation, DirectMessage, HistoryPage, HistoryQuery},
+    network::{
+        message::{DirectMessageAck, DirectRejection, InboundEvent, Message, SealedDirectMessage},
+        Client,
+    },
+    ratchet::{Header, Sessions},
+    store::SledStore,
+};
+use async_trait::async_trait;
+use libp2p::PeerId;
+use serde::Deserialize;
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use tokio::sync::{mpsc, Mutex};
+use uuid::Uuid;
+
+/// One-to-one conversations, keyed by the remote peer. Messages are end-to-end encrypted with a
+/// double ratchet session per peer, agreed on with the keys of both node identities.
+#[derive(Debug, Clone)]
+pub struct DirectManager {
+    store: SledStore,
+    client: Client,
+    sender: mpsc::Sender<FrontendEvent>,
+    identity: Arc<Mutex<NodeIdentity>>,
+    sessions: Arc<Mutex<Sessions>>,
+    /// Peers the outbox is being delivered to right now.
+    delivering: Arc<Mutex<HashSet<PeerId>>>,
+}
+
+/// How many times the outbox of a peer is sent again after a failed delivery, before waiting
+/// for the peer to connect again.
+const DELIVERY_RETRIES: u32 = 6;
+const INITIAL_DELIVERY_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_DELIVERY_BACKOFF: Duration = Duration::from_secs(2 * 60);
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SendParams {
+    peer_id: PeerId,
+    message: Message,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryParams {
+    peer_id: PeerId,
+    /// The latest messages are returned when no message is given.
+    #[serde(default)]
+    before: Option<Uuid>,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+impl DirectManager {
+    pub fn new(
+        store: SledStore,
+        client: Client,
+        sender: mpsc::Sender<FrontendEvent>,
+        identity: Arc<Mutex<NodeIdentity>>,
+    ) -> Self {
+        Self {
+            store,
+            client,
+            sender,
+            identity,
+            sessions: Arc::new(Mutex::new(Sessions::default())),
+            delivering: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+    /// Store a message to `peer_id` and deliver it in the background. The message stays in the
+    /// outbox until the peer acknowledges it.
+    pub async fn send(
+        &self,
+        peer_id: PeerId,
+        message: Message,
+    ) -> Result<DirectMessage, NetworkError> {
+        let message = DirectMessage::new(message, self.client.local_peer_id(), peer_id);
+        self.store.put_direct(&peer_id, &message)?;
+        self.sender
+            .send(FrontendEvent::DirectMessage {
+                peer_id,
+                message: message.clone(),
+            })
+            .await
+            .unwrap();
+        self.spawn_delivery(peer_id);
+        Ok(message)
+    }
+    pub fn get_conversations(&self) -> Result<Vec<Conversation>, StoreError> {
+        self.store.conversations()
+    }
+    pub fn get_history(
+        &self,
+        peer_id: &PeerId,
+        before: Option<&Uuid>,
+        limit: usize,
+    ) -> Result<HistoryPage<DirectMessage>, StoreError> {
+        self.store.direct_page(peer_id, before, limit)
+    }
+    /// Encrypt `message` for its target. Every call advances the session, so a message that is
+    /// sent again is sealed with a new key.
+    async fn seal(&self, message: &DirectMessage) -> Result<SealedDirectMessage, NetworkError> {
+        let identity = self.identity.lock().await;
+        let plaintext = serde_json::to_vec(message).expect("Direct message to serialize.");
+        let envelope = self
+            .sessions
+            .lock()
+            .await
+            .seal(
+                &identity,
+                &message.target,
+                &plaintext,
+                &SealedDirectMessage::associated_data(&message.source, &message.target),
+            )
+            .await?;
+        Ok(SealedDirectMessage {
+            source: message.source,
+            target: message.target,
+            envelope,
+        })
+    }
+    async fn open(&self, sealed: &SealedDirectMessage) -> Result<DirectMessage, NetworkError> {
+        let identity = self.identity.lock().await;
+        let plaintext = self
+            .sessions
+            .lock()
+            .await
+            .open(
+                &identity,
+                &sealed.source,
+                &sealed.envelope,
+                &SealedDirectMessage::associated_data(&sealed.source, &sealed.target),
+            )
+            .await?;
+        let message = serde_json::from_slice::<DirectMessage>(&plaintext)
+            .map_err(|_| CryptoError::InvalidEnvelope)?;
+        // the addressing in the clear must match the one that was encrypted
+        if message.source != sealed.source || message.target != sealed.target {
+            return Err(CryptoError::InvalidEnvelope.into());
+        }
+        Ok(message)
+    }
+    /// Start a new session with `peer_id` with the next message, after it rejected the one sent
+    /// with `header`.
+    async fn reset_session(&self, peer_id: &PeerId, header: &Header) -> Result<(), NetworkError> {
+        let identity = self.identity.lock().await;
+        self.sessions
+            .lock()
+            .await
+            .reset(&identity, peer_id, &header.session())
+            .await?;
+        Ok(())
+    }
+    fn spawn_delivery(&self, peer_id: PeerId) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = manager.deliver(peer_id).await {
+                log::warn!("Failed to deliver direct messages to {peer_id}: {e}");
+            }
+        });
+    }
+    /// Send the outbox of `peer_id` in order, stopping at the first message that is not
+    /// acknowledged and trying again with exponential backoff. Once out of retries, the rest is
+    /// sent when the peer connects again.
+    async fn deliver(&self, peer_id: PeerId) -> Result<(), NetworkError> {
+        if !self.delivering.lock().await.insert(peer_id) {
+            return Ok(());
+        }
+        let mut result = self.deliver_outbox(peer_id).await;
+        let mut backoff = INITIAL_DELIVERY_BACKOFF;
+        for attempt in 1..=DELIVERY_RETRIES {
+            let Err(e) = &result else {
+                break;
+            };
+            log::debug!("Delivery to {peer_id} failed, retry {attempt} in {backoff:?}: {e}");
+            tokio::time::sleep(backoff).await;
+            backoff = backoff.saturating_mul(2).min(MAX_DELIVERY_BACKOFF);
+            result = self.deliver_outbox(peer_id).await;
+        }
+        self.delivering.lock().await.remove(&peer_id);
+        result
+    }
+    async fn deliver_outbox(&self, peer_id: PeerId) -> Result<(), NetworkError> {
+        // messages sent while a batch is in flight are picked up by the next round
+        loop {
+            let outbox = self.store.undelivered(&peer_id)?;
+            if outbox.is_empty() {
+                return Ok(());
+            }
+            for message in outbox {
+                let id = message.id;
+                let sealed = self.seal(&message).await?;
+                let header = sealed.envelope.header.clone();
+                let DirectMessageAck {
+                    id: acked,
+                    rejected,
+                } = self.client.send_direct(sealed).await?;
+                if let Some(reason) = rejected {
+                    self.reset_session(&peer_id, &header).await?;
+                    return Err(anyhow::anyhow!("{peer_id} rejected {id}: {reason:?}").into());
+                }
+                if acked != id {
+                    return Err(
+                        anyhow::anyhow!("{peer_id} acknowledged {acked} instead of {id}").into(),
+                    );
+                }
+                self.store.mark_delivered(&peer_id, &id)?;
+                self.sender
+                    .send(FrontendEvent::DirectMessageDelivered { peer_id, id })
+                    .await
+                    .unwrap();
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl HandleInboundEvent for DirectManager {
+    async fn handle_event(
+        &mut self,
+        event: InboundEvent,
+        client: Client,
+        _state: AppState,
+        _sender: mpsc::Sender<FrontendEvent>,
+    ) -> Result<(), NetworkError> {
+        match event {
+            InboundEvent::DirectMessage {
+                peer_id,
+                message,
+                channel,
+            } => {
+                if message.source != peer_id || message.target != client.local_peer_id() {
+                    log::warn!("{peer_id} sent a direct message not meant for this node");
+                    return Ok(());
+                }
+                // unacknowledged messages are sent again, sealed in whichever session wins
+                let mut message = match self.open(&message).await {
+                    Ok(message) => message,
+                    Err(e) => {
+                        log::warn!("Failed to open direct message from {peer_id}: {e}");
+                        // tell the sender to start over rather than send the message again and
+                        // again in a session we cannot follow
+                        let reason = match e {
+                            NetworkError::CryptoError(CryptoError::StaleSession) => {
+                                DirectRejection::StaleSession
+                            }
+                            NetworkError::CryptoError(_) => DirectRejection::Undecryptable,
+                            _ => return Ok(()),
+                        };
+                        if let Some(channel) = channel.lock().await.take() {
+                            client
+                                .ack_direct(DirectMessageAck::rejected(reason), channel)
+                                .await;
+                        }
+                        return Ok(());
+                    }
+                };
+                message.delivered = true;
+                // duplicates are acknowledged again, the first ack may have been lost
+                if self.store.put_direct(&peer_id, &message)? {
+                    self.sender
+                        .send(FrontendEvent::DirectMessage {
+                            peer_id,
+                            message: message.clone(),
+                        })
+                        .await
+                        .unwrap();
+                }
+                if let Some(channel) = channel.lock().await.take() {
+                    client
+                        .ack_direct(DirectMessageAck::delivered(message.id), channel)
+                        .await;
+                }
+            }
+            InboundEvent::PeerDiscovered { peer_id }
+            | InboundEvent::ConnectionEstablished { peer_id } => {
+                if !self.store.undelivered(&peer_id)?.is_empty() {
+                    self.spawn_delivery(peer_id);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Invoke for DirectManager {
+    async fn invoke(
+        &self,
+        command: &str,
+        params: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value, ManagerError> {
+        let value = match command {
+            "send" if params.is_some() => {
+                let SendParams { peer_id, message } =
+                    serde_json::from_value::<SendParams>(params.unwrap())?;
+                serde_json::to_value(self.send(peer_id, message).await?)?
+            }
+            "conversations" => serde_json::to_value(self.get_conversations()?)?,
+            "history" if params.is_some() => {
+                let HistoryParams {
+                    peer_id,
+                    before,
+                    limit,
+                } = serde_json::from_value::<HistoryParams>(params.unwrap())?;
+                serde_json::to_value(self.get_history(
+                    &peer_id,
+                    before.as_ref(),
+                    limit.unwrap_or(HistoryQuery::DEFAULT_LIMIT),
+                )?)?
+            }
+            c => return Err(ManagerError::InvalidAction(c.to_string())),
+        };
+        Ok(value)
+    }
+}
+
+impl AppManager for DirectManager {
+    fn name(&self) -> &'static str {
+        "direct"
+    }
+}
@@ -1,104 +1,579 @@
-// #[derive(Debug, Clone)]
-// pub struct FileManager {
-//     files: Arc<Mutex<HashMap<PeerId, Vec<FileInfo>>>>,
-//     file_resources: Arc<Mutex<HashMap<PeerId, Vec<FileSource>>>>,
-// }
+use super::{AppManager, HandleInboundEvent, Invoke};
+use crate::{
+    chat_app::{frontend_event::FrontendEvent, AppState},
+    error::{ManagerError, NetworkError, StoreError, TransferError},
+    models::{FileInfo, FileManifest, FileSource, GroupId, GroupMessage, Setting},
+    network::{
+        message::{Chunk, ChunkResponse, ErrorKind, InboundEvent, Message, Request, Response},
+        Client, RequestOptions,
+    },
+    store::{MessageStore, SledStore},
+    transfer::{self, PartialDownload},
+};
+use async_trait::async_trait;
+use futures::future;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io,
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+};
+use uuid::Uuid;
 
-// impl FileManager {
-//     pub fn new() -> Self {
-//         Self {
-//             files: Arc::new(Mutex::new(HashMap::new())),
-//             file_resources: Arc::new(Mutex::new(HashMap::new())),
-//         }
-//     }
-//     pub async fn add_local_file<P: AsRef<Path>>(
-//         &self,
-//         peer_id: PeerId,
-//         path: P,
-//     ) -> Result<FileInfo, io::Error> {
-//         let path = path.as_ref();
-//         let file_info = FileInfo::from_path(path).await?;
-//         self.files
-//             .lock()
-//             .await
-//             .entry(peer_id)
-//             .or_insert_with(Vec::new)
-//             .push(file_info.clone());
-//         self.file_resources
-//             .lock()
-//             .await
-//             .entry(peer_id)
-//             .or_insert_with(Vec::new)
-//             .push(FileSource::Local(path.to_path_buf()));
-//         Ok(file_info)
-//     }
-//     pub async fn add_remote_file(&self, peer_id: PeerId, file_info: FileInfo) {
-//         self.files
-//             .lock()
-//             .await
-//             .entry(peer_id)
-//             .or_insert_with(Vec::new)
-//             .push(file_info);
-//         self.file_resources
-//             .lock()
-//             .await
-//             .entry(peer_id)
-//             .or_insert_with(Vec::new)
-//             .push(FileSource::Remote(peer_id));
-//     }
-//     pub async fn list_provide(&self, peer_id: &PeerId) -> Option<Vec<FileInfo>> {
-//         self.files
-//             .lock()
-//             .await
-//             .get(peer_id)
-//             .map(|files| files.clone())
-//     }
-// }
-// #[derive(Debug, Clone)]
-// pub struct Manager {
-//     group: GroupManager,
-//     user: UserManager,
-//     file: FileManager,
-// }
+/// How long a peer is given to answer whether it holds a copy of a file.
+const SOURCE_TIMEOUT: Duration = Duration::from_secs(5);
 
-// impl Manager {
-//     pub fn new() -> Self {
-//         Self {
-//             group: GroupManager::new(),
-//             user: UserManager::new(),
-//             file: FileManager::new(),
-//         }
-//     }
-//     pub fn group(&self) -> &GroupManager {
-//         &self.group
-//     }
-//     pub fn user(&self) -> &UserManager {
-//         &self.user
-//     }
-//     pub fn file(&self) -> &FileManager {
-//         &self.file
-//     }
-//     pub async fn get_user_not_subscribe(&self, peer_id: &PeerId) -> Option<Vec<GroupId>> {
-//         let user_subscribe = self.user.get_user_subscribe(peer_id).await?;
+#[derive(Debug, Clone)]
+pub struct FileManager {
+    /// Every file offered in a group, with the places it can be fetched from.
+    files: Arc<Mutex<HashMap<FileInfo, Vec<FileSource>>>>,
+    /// Chunk manifests of the files provided by the local node.
+    manifests: Arc<Mutex<HashMap<FileInfo, FileManifest>>>,
+    /// Downloads that have not completed yet, including interrupted ones.
+    downloads: Arc<Mutex<HashMap<Uuid, PartialDownload>>>,
+    /// The tasks of the downloads currently transferring chunks.
+    active_downloads: Arc<Mutex<HashMap<Uuid, JoinHandle<()>>>>,
+    /// Where download records are kept between runs.
+    records_dir: PathBuf,
+    client: Client,
+    setting: Arc<Mutex<Setting>>,
+    sender: mpsc::Sender<FrontendEvent>,
+}
 
-//         let groups = self
-//             .group
-//             .get_groups()
-//             .await
-//             .keys()
-//             .filter(|group_id| !user_subscribe.contains(group_id))
-//             .cloned()
-//             .collect();
-//         Some(groups)
-//     }
-//     pub async fn subscribe(&self, peer_id: PeerId, group_id: GroupId) -> Result<(), ManagerError> {
-//         if !self.group.is_group_exist(&group_id).await {
-//             return Err(ManagerError::GroupNotExist(group_id));
-//         }
-//         self.group.add_subscribe(&group_id, peer_id).await;
-//         self.user.add_subscribe(peer_id.clone(), group_id).await
-//     }
-//     pub async fn unsubscribe(&self, peer_id: &PeerId, group_id: &GroupId) -> bool {
-//         self.user.remove_subscribe(peer_id, group_id).await
-//     }
-// }
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferInfo {
+    pub id: Uuid,
+    pub file: FileInfo,
+    pub peer_id: PeerId,
+    pub bytes: u64,
+    pub total: u64,
+    pub paused: bool,
+    /// Whether chunks are being transferred right now.
+    pub active: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OfferParams {
+    group_id: GroupId,
+    path: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DownloadParams {
+    file: FileInfo,
+    /// Fetch the manifest from this peer instead of any peer that offered the file.
+    #[serde(default)]
+    peer_id: Option<PeerId>,
+}
+
+impl FileManager {
+    pub fn new(
+        client: Client,
+        setting: Arc<Mutex<Setting>>,
+        sender: mpsc::Sender<FrontendEvent>,
+        records_dir: PathBuf,
+    ) -> Self {
+        Self {
+            files: Arc::new(Mutex::new(HashMap::new())),
+            manifests: Arc::new(Mutex::new(HashMap::new())),
+            downloads: Arc::new(Mutex::new(HashMap::new())),
+            active_downloads: Arc::new(Mutex::new(HashMap::new())),
+            records_dir,
+            client,
+            setting,
+            sender,
+        }
+    }
+    async fn add_source(&self, file_info: FileInfo, source: FileSource) {
+        let mut files = self.files.lock().await;
+        let sources = files.entry(file_info).or_default();
+        if !sources.contains(&source) {
+            sources.push(source);
+        }
+    }
+    /// Start providing the file at `path` to other peers.
+    pub async fn add_local_file<P: AsRef<Path>>(&self, path: P) -> Result<FileInfo, io::Error> {
+        let path = path.as_ref();
+        let file_info = FileInfo::from_path(path).await?;
+        let manifest = transfer::build_manifest(path, file_info.clone()).await?;
+        self.manifests
+            .lock()
+            .await
+            .insert(file_info.clone(), manifest);
+        self.add_source(file_info.clone(), FileSource::Local(path.to_path_buf()))
+            .await;
+        Ok(file_info)
+    }
+    /// Provide the file at `path` and announce it to `group_id`.
+    pub async fn offer<P: AsRef<Path>>(
+        &self,
+        group_id: &GroupId,
+        path: P,
+    ) -> Result<FileInfo, NetworkError> {
+        let file_info = self.add_local_file(path).await?;
+        self.client
+            .publish(group_id.topic(), Message::File(file_info.clone()))
+            .await?;
+        Ok(file_info)
+    }
+    pub async fn remove_local_file(&self, file_info: &FileInfo) -> bool {
+        let removed = self.manifests.lock().await.remove(file_info).is_some();
+        if let Some(sources) = self.files.lock().await.get_mut(file_info) {
+            sources.retain(FileSource::is_remote);
+        }
+        removed
+    }
+    pub async fn get_local_file(&self, file_info: &FileInfo) -> Option<(PathBuf, FileManifest)> {
+        let path =
+            self.files
+                .lock()
+                .await
+                .get(file_info)?
+                .iter()
+                .find_map(|source| match source {
+                    FileSource::Local(path) => Some(path.clone()),
+                    FileSource::Remote(_) => None,
+                })?;
+        let manifest = self.manifests.lock().await.get(file_info).cloned()?;
+        Some((path, manifest))
+    }
+    /// The files provided by the local node.
+    pub async fn list_provided(&self) -> Vec<FileInfo> {
+        self.manifests.lock().await.keys().cloned().collect()
+    }
+    /// The files offered by other peers, with the peers offering them.
+    pub async fn list_remote(&self) -> Vec<(FileInfo, Vec<PeerId>)> {
+        self.files
+            .lock()
+            .await
+            .iter()
+            .filter_map(|(file_info, sources)| {
+                let peers = sources
+                    .iter()
+                    .filter_map(|source| match source {
+                        FileSource::Remote(peer_id) => Some(*peer_id),
+                        FileSource::Local(_) => None,
+                    })
+                    .collect::<Vec<_>>();
+                (!peers.is_empty()).then(|| (file_info.clone(), peers))
+            })
+            .collect()
+    }
+    /// Rebuild the offers of other peers from the file messages in the stored group history.
+    /// The local node's own offers are not among them, their paths are not stored.
+    pub async fn restore_offers(&self, store: &SledStore) -> Result<(), StoreError> {
+        let local_peer_id = self.client.local_peer_id();
+        for group_id in store.groups()?.keys() {
+            for message in store.history(group_id)? {
+                if let Message::File(file_info) = message.message {
+                    if message.source != local_peer_id {
+                        self.add_source(file_info, FileSource::Remote(message.source))
+                            .await;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Load the downloads interrupted in a previous run; they resume once their provider shows up.
+    pub async fn restore_downloads(&self) -> Result<(), io::Error> {
+        let restored = PartialDownload::load_all(&self.records_dir).await?;
+        let mut downloads = self.downloads.lock().await;
+        for download in restored {
+            log::info!(
+                "Restored download of {} from {}",
+                download.manifest.file.name,
+                download.peer_id
+            );
+            downloads.insert(download.id, download);
+        }
+        Ok(())
+    }
+    /// Fetch the manifest of `file_info` from `peer_id`, or from a peer that offered it, and start
+    /// streaming the file into `recv_path` from every connected peer that holds it in the
+    /// background. Returns the id of the download.
+    pub async fn download(
+        &self,
+        file_info: FileInfo,
+        peer_id: Option<PeerId>,
+    ) -> Result<Uuid, NetworkError> {
+        let peer_id = match peer_id {
+            Some(peer_id) => peer_id,
+            None => self
+                .files
+                .lock()
+                .await
+                .get(&file_info)
+                .and_then(|sources| {
+                    sources.iter().find_map(|source| match source {
+                        FileSource::Remote(peer_id) => Some(*peer_id),
+                        FileSource::Local(_) => None,
+                    })
+                })
+                .ok_or_else(|| TransferError::NoSource(file_info.name.clone()))?,
+        };
+        let Response::File(manifest) = self
+            .client
+            .request(peer_id, Request::File(file_info.clone()))
+            .await?
+        else {
+            return Err(anyhow::anyhow!("unexpected response to file request").into());
+        };
+        if manifest.file != file_info || manifest.file.size != file_info.size {
+            return Err(TransferError::HashMismatch(file_info.name).into());
+        }
+        let recv_path = self.setting.lock().await.recv_path.clone();
+        let download = PartialDownload::new(peer_id, manifest, recv_path, &self.records_dir)?;
+        let id = download.id;
+        self.downloads.lock().await.insert(id, download);
+        self.spawn_download(id).await;
+        Ok(id)
+    }
+    /// The downloads that have not completed yet.
+    pub async fn get_transfers(&self) -> Vec<TransferInfo> {
+        let active_downloads = self.active_downloads.lock().await;
+        self.downloads
+            .lock()
+            .await
+            .values()
+            .map(|download| TransferInfo {
+                id: download.id,
+                file: download.manifest.file.clone(),
+                peer_id: download.peer_id,
+                bytes: download.received_bytes(),
+                total: download.manifest.file.size,
+                paused: download.paused,
+                active: active_downloads.contains_key(&download.id),
+            })
+            .collect()
+    }
+    /// Stop transferring chunks of a download, keeping what was received so far.
+    pub async fn pause(&self, id: &Uuid) -> Result<(), NetworkError> {
+        let task = self.active_downloads.lock().await.remove(id);
+        if let Some(task) = task {
+            task.abort();
+            // wait for the task to stop writing chunks before reading back its progress
+            let _ = task.await;
+        }
+        self.set_paused(id, true).await?;
+        self.sender
+            .send(FrontendEvent::TransferPaused { id: *id })
+            .await
+            .unwrap();
+        Ok(())
+    }
+    pub async fn resume(&self, id: &Uuid) -> Result<(), NetworkError> {
+        self.set_paused(id, false).await?;
+        self.spawn_download(*id).await;
+        Ok(())
+    }
+    async fn set_paused(&self, id: &Uuid, paused: bool) -> Result<(), NetworkError> {
+        let mut downloads = self.downloads.lock().await;
+        let download = downloads
+            .get_mut(id)
+            .ok_or(TransferError::DownloadNotFound(*id))?;
+        // the task that transferred chunks worked on a copy of the record, the log is up to date
+        download.load_log(&self.records_dir).await?;
+        download.paused = paused;
+        download.save(&self.records_dir).await?;
+        Ok(())
+    }
+    /// Stop a download and delete what was received so far.
+    pub async fn cancel(&self, id: &Uuid) -> Result<(), NetworkError> {
+        let task = self.active_downloads.lock().await.remove(id);
+        if let Some(task) = task {
+            task.abort();
+            // the task must stop writing chunks before the partial file and its record are deleted
+            let _ = task.await;
+        }
+        let download = self
+            .downloads
+            .lock()
+            .await
+            .remove(id)
+            .ok_or(TransferError::DownloadNotFound(*id))?;
+        download.remove(&self.records_dir).await?;
+        log::info!("Cancelled download of {}", download.manifest.file.name);
+        self.sender
+            .send(FrontendEvent::TransferCancelled { id: *id })
+            .await
+            .unwrap();
+        Ok(())
+    }
+    /// Resume the unfinished downloads that `peer_id` is known to hold the file of, unless they
+    /// were paused.
+    pub async fn resume_downloads(&self, peer_id: &PeerId) {
+        let files = self.files.lock().await;
+        let ids = self
+            .downloads
+            .lock()
+            .await
+            .values()
+            .filter(|download| !download.paused)
+            .filter(|download| {
+                &download.peer_id == peer_id
+                    || files.get(&download.manifest.file).map_or(false, |sources| {
+                        sources.contains(&FileSource::Remote(*peer_id))
+                    })
+            })
+            .map(|download| download.id)
+            .collect::<Vec<_>>();
+        drop(files);
+        for id in ids {
+            self.spawn_download(id).await;
+        }
+    }
+    /// Ask every connected peer for the manifest of the file of `manifest` and return the peers
+    /// that hold an identical copy.
+    async fn find_sources(&self, manifest: &FileManifest) -> Vec<PeerId> {
+        let local_peer_id = self.client.local_peer_id();
+        let peers = self
+            .client
+            .connected_peers()
+            .await
+            .into_iter()
+            .filter(|peer_id| peer_id != &local_peer_id);
+        let answers = future::join_all(peers.map(|peer_id| async move {
+            // peers without the file answer right away, a slow one is not worth waiting for
+            let options = RequestOptions {
+                timeout: SOURCE_TIMEOUT,
+                ..Default::default()
+            };
+            let response = self
+                .client
+                .request_with(peer_id, Request::File(manifest.file.clone()), options)
+                .await;
+            (peer_id, response)
+        }))
+        .await;
+        let mut sources = Vec::new();
+        for (peer_id, response) in answers {
+            match response {
+                Ok(Response::File(remote))
+                    if remote.chunk_size == manifest.chunk_size
+                        && remote.chunk_hashes == manifest.chunk_hashes =>
+                {
+                    self.add_source(manifest.file.clone(), FileSource::Remote(peer_id))
+                        .await;
+                    sources.push(peer_id);
+                }
+                Ok(_) => log::debug!("{peer_id} holds a different {}", manifest.file.name),
+                Err(e) => log::debug!("{peer_id} does not provide {}: {e}", manifest.file.name),
+            }
+        }
+        sources
+    }
+    async fn spawn_download(&self, id: Uuid) {
+        // the lock is held until the task is recorded, so a task finishing right away still
+        // removes its own entry
+        let mut active_downloads = self.active_downloads.lock().await;
+        if active_downloads.contains_key(&id) {
+            return;
+        }
+        let manager = self.clone();
+        let task = tokio::spawn(async move {
+            manager.run_download(id).await;
+            manager.active_downloads.lock().await.remove(&id);
+        });
+        active_downloads.insert(id, task);
+    }
+    async fn run_download(&self, id: Uuid) {
+        let Some(mut download) = self.downloads.lock().await.get(&id).cloned() else {
+            return;
+        };
+        self.sender
+            .send(FrontendEvent::TransferStarted {
+                id,
+                file: download.manifest.file.clone(),
+                peer_id: download.peer_id,
+            })
+            .await
+            .unwrap();
+        let sources = self.find_sources(&download.manifest).await;
+        log::info!(
+            "Downloading {} from {} peer(s)",
+            download.manifest.file.name,
+            sources.len()
+        );
+        let sender = self.sender.clone();
+        // progress reports are dropped rather than stalling the transfer when the UI lags behind
+        let on_progress = move |progress| {
+            let _ = sender.try_send(FrontendEvent::TransferProgress(progress));
+        };
+        match transfer::download(
+            &self.client,
+            &mut download,
+            &sources,
+            &self.records_dir,
+            on_progress,
+        )
+        .await
+        {
+            Ok(path) => {
+                log::info!("Received {} into {path:?}", download.manifest.file.name);
+                self.downloads.lock().await.remove(&id);
+                // the verified copy can be served to other peers right away
+                let file_info = download.manifest.file.clone();
+                self.manifests
+                    .lock()
+                    .await
+                    .insert(file_info.clone(), download.manifest.clone());
+                self.add_source(file_info, FileSource::Local(path.clone()))
+                    .await;
+                self.sender
+                    .send(FrontendEvent::TransferCompleted { id, path })
+                    .await
+                    .unwrap();
+            }
+            Err(e) => {
+                log::warn!(
+                    "download of {} interrupted: {e}",
+                    download.manifest.file.name
+                );
+                let mut downloads = self.downloads.lock().await;
+                if matches!(
+                    e,
+                    NetworkError::TransferError(TransferError::HashMismatch(_))
+                ) {
+                    // the provider's copy is bad, retrying it would only fail again
+                    downloads.remove(&id);
+                } else if let Some(record) = downloads.get_mut(&id) {
+                    *record = download;
+                }
+                drop(downloads);
+                self.sender
+                    .send(FrontendEvent::TransferFailed {
+                        id,
+                        error: e.to_string(),
+                    })
+                    .await
+                    .unwrap();
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl HandleInboundEvent for FileManager {
+    async fn handle_event(
+        &mut self,
+        event: InboundEvent,
+        client: Client,
+        _state: AppState,
+        _sender: mpsc::Sender<FrontendEvent>,
+    ) -> Result<(), NetworkError> {
+        match event {
+            InboundEvent::InboundRequest {
+                request: Request::File(file_info),
+                channel,
+                ..
+            } => {
+                let response = if let Some((_, manifest)) = self.get_local_file(&file_info).await {
+                    Response::File(manifest)
+                } else {
+                    // peers looking for sources of a download ask everyone
+                    log::debug!("file not provided {}", file_info.name);
+                    Response::error(ErrorKind::NotFound, "file not provided")
+                };
+                if let Some(channel) = channel.lock().await.take() {
+                    client.response(response, channel).await;
+                }
+            }
+            InboundEvent::ChunkRequest {
+                peer_id,
+                request,
+                channel,
+            } => {
+                let response = match self.get_local_file(&request.file).await {
+                    Some((path, manifest)) if request.index < manifest.chunk_count() => {
+                        let data = transfer::read_chunk(&path, &manifest, request.index).await?;
+                        ChunkResponse::Chunk(Chunk {
+                            index: request.index,
+                            hash: transfer::hash_chunk(&data),
+                            data,
+                        })
+                    }
+                    _ => {
+                        log::warn!(
+                            "{peer_id} requested chunk {} of unavailable file {}",
+                            request.index,
+                            request.file.name
+                        );
+                        ChunkResponse::Unavailable
+                    }
+                };
+                if let Some(channel) = channel.lock().await.take() {
+                    client.response_chunk(response, channel).await;
+                }
+            }
+            InboundEvent::Message {
+                message:
+                    GroupMessage {
+                        source,
+                        message: Message::File(file_info),
+                        ..
+                    },
+                ..
+            } if source != client.local_peer_id() => {
+                self.add_source(file_info, FileSource::Remote(source)).await;
+            }
+            InboundEvent::PeerDiscovered { peer_id }
+            | InboundEvent::ConnectionEstablished { peer_id } => {
+                self.resume_downloads(&peer_id).await;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Invoke for FileManager {
+    async fn invoke(
+        &self,
+        command: &str,
+        params: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value, ManagerError> {
+        let value = match command {
+            "offer" if params.is_some() => {
+                let OfferParams { group_id, path } =
+                    serde_json::from_value::<OfferParams>(params.unwrap())?;
+                serde_json::to_value(self.offer(&group_id, path).await?)?
+            }
+            "stop_provide" if params.is_some() => {
+                let file_info = serde_json::from_value::<FileInfo>(params.unwrap())?;
+                serde_json::to_value(self.remove_local_file(&file_info).await)?
+            }
+            "list_provided" => serde_json::to_value(self.list_provided().await)?,
+            "list_remote" => serde_json::to_value(self.list_remote().await)?,
+            "download" if params.is_some() => {
+                let DownloadParams { file, peer_id } =
+                    serde_json::from_value::<DownloadParams>(params.unwrap())?;
+                serde_json::to_value(self.download(file, peer_id).await?)?
+            }
+            "cancel" if params.is_some() => {
+                let id = serde_json::from_value::<Uuid>(params.unwrap())?;
+                self.cancel(&id).await?;
+                serde_json::Value::Null
+            }
+            c => return Err(ManagerError::InvalidAction(c.to_string())),
+        };
+        Ok(value)
+    }
+}
+
+impl AppManager for FileManager {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+}
@@ -1,58 +1,328 @@
 use super::{AppManager, HandleInboundEvent, Invoke};
 use crate::{
     chat_app::{frontend_event::FrontendEvent, AppState},
-    error::{ManagerError, NetworkError},
-    models::{GroupId, GroupInfo, GroupMessage, GroupState},
+    crypto::{GroupInvite, GroupKey, GroupKeys, GroupMembers},
+    error::{ManagerError, NetworkError, StoreError},
+    identity::NodeIdentity,
+    models::{
+        GroupId, GroupInfo, GroupMessage, GroupState, HistoryCursor, HistoryPage, HistoryQuery,
+    },
     network::{
-        message::{InboundEvent, Request, Response},
-        Client,
+        message::{ErrorKind, InboundEvent, Request, Response},
+        Client, RequestOptions,
     },
+    search::{self, SearchHit, SearchIndex, SearchQuery},
+    store::{MessageStore, SledStore},
 };
 use async_trait::async_trait;
+use chrono::Utc;
+use futures::future;
 use libp2p::{gossipsub::TopicHash, PeerId};
-use std::{collections::HashMap, sync::Arc};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 use tokio::sync::{mpsc, Mutex};
 
 #[derive(Debug, Clone)]
 pub struct GroupManager {
-    group_state: Arc<Mutex<HashMap<GroupId, GroupState>>>,
+    subscribers: Arc<Mutex<HashMap<GroupId, HashSet<PeerId>>>>,
     groups: Arc<Mutex<HashMap<GroupId, GroupInfo>>>,
+    store: Arc<Mutex<SledStore>>,
+    search_index: Arc<Mutex<SearchIndex>>,
+    synced_peers: Arc<Mutex<HashSet<(GroupId, PeerId)>>>,
+    group_keys: Arc<Mutex<GroupKeys>>,
+    /// Members the keys of a group are being fetched from after an unreadable message.
+    key_requests: Arc<Mutex<HashSet<(GroupId, PeerId)>>>,
+    /// The peers let into each group by a member. Keys and history are only handed to them,
+    /// whereas anyone may subscribe to the topic of a group.
+    members: Arc<Mutex<HashMap<GroupId, GroupMembers>>>,
+    identity: Arc<Mutex<NodeIdentity>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InviteParams {
+    group_id: GroupId,
+    peer_id: PeerId,
 }
 
+/// How many neighbouring messages are returned on each side of a search hit.
+const SEARCH_CONTEXT: usize = 2;
+/// Page size used when answering and sending history sync requests.
+const SYNC_PAGE_SIZE: usize = 100;
+/// Upper bound on the number of pages fetched from a single peer in one sync.
+const MAX_SYNC_PAGES: usize = 50;
+/// How many times a history page or group info request is sent again before giving up.
+const SYNC_RETRIES: u32 = 3;
+
 impl GroupManager {
-    pub fn new() -> Self {
-        Self {
-            group_state: Arc::new(Mutex::new(HashMap::new())),
-            groups: Arc::new(Mutex::new(HashMap::new())),
+    /// Create a group manager with the groups, subscribers and members persisted in `store`. Group keys
+    /// are shared with the network, which seals and opens group messages with them, and invites
+    /// are signed with `identity`.
+    pub fn new(
+        store: SledStore,
+        group_keys: Arc<Mutex<GroupKeys>>,
+        identity: Arc<Mutex<NodeIdentity>>,
+    ) -> Result<Self, StoreError> {
+        let groups = store.groups()?;
+        let subscribers = groups
+            .keys()
+            .map(|group_id| Ok((group_id.clone(), store.subscribers(group_id)?)))
+            .collect::<Result<HashMap<_, _>, StoreError>>()?;
+        let members = groups
+            .keys()
+            .map(|group_id| Ok((group_id.clone(), store.group_members(group_id)?)))
+            .collect::<Result<HashMap<_, _>, StoreError>>()?;
+        let mut search_index = SearchIndex::new();
+        for group_id in groups.keys() {
+            for message in store.history(group_id)? {
+                search_index.insert(group_id, &message);
+            }
+        }
+        Ok(Self {
+            subscribers: Arc::new(Mutex::new(subscribers)),
+            groups: Arc::new(Mutex::new(groups)),
+            store: Arc::new(Mutex::new(store)),
+            search_index: Arc::new(Mutex::new(search_index)),
+            synced_peers: Arc::new(Mutex::new(HashSet::new())),
+            group_keys,
+            key_requests: Arc::new(Mutex::new(HashSet::new())),
+            members: Arc::new(Mutex::new(members)),
+            identity,
+        })
+    }
+    /// Load the keys of every group persisted in the store.
+    pub async fn restore_group_keys(&self) -> Result<(), StoreError> {
+        let groups = self.get_groups().await;
+        let mut group_keys = self.group_keys.lock().await;
+        let store = self.store.lock().await;
+        for group_id in groups.keys() {
+            group_keys.insert(group_id.topic().hash(), store.group_keys(group_id)?);
+        }
+        Ok(())
+    }
+    /// Record the local node as the founder of a group it just created.
+    async fn found_group(&self, group_id: &GroupId) -> Result<(), NetworkError> {
+        let keypair = self.identity.lock().await.keypair();
+        let invite = GroupInvite::sign(&keypair, group_id.clone(), keypair.public().to_peer_id())?;
+        let group_members = GroupMembers::from_founders(&[invite]);
+        self.store
+            .lock()
+            .await
+            .put_group_members(group_id, &group_members)?;
+        self.members
+            .lock()
+            .await
+            .insert(group_id.clone(), group_members);
+        Ok(())
+    }
+    /// Invite `invitee` into `group_id`. The returned code carries the invites of every member
+    /// known to the local node and is to be passed to [`GroupManager::accept_invite`] by the
+    /// invitee.
+    pub async fn invite(
+        &self,
+        group_id: &GroupId,
+        invitee: PeerId,
+    ) -> Result<String, ManagerError> {
+        let keypair = self.identity.lock().await.keypair();
+        if !self
+            .is_member(group_id, &keypair.public().to_peer_id())
+            .await
+        {
+            return Err(ManagerError::InvalidAction(format!(
+                "not a member of group {group_id}"
+            )));
+        }
+        let invite = GroupInvite::sign(&keypair, group_id.clone(), invitee)?;
+        self.update_members(group_id, |members| members.admit(group_id, [invite]))
+            .await?;
+        let invites = self.group_members(group_id).await.invites();
+        Ok(hex::encode(serde_json::to_vec(&invites)?))
+    }
+    /// Take up an invite code created by [`GroupManager::invite`], returning the group it lets
+    /// the local node into. The group itself is fetched from its members once subscribed.
+    pub async fn accept_invite(&self, code: &str) -> Result<GroupId, ManagerError> {
+        let invalid = || ManagerError::InvalidInvite(code.to_string());
+        let data = hex::decode(code.trim()).map_err(|_| invalid())?;
+        let invites = serde_json::from_slice::<Vec<GroupInvite>>(&data).map_err(|_| invalid())?;
+        let group_id = invites.first().ok_or_else(invalid)?.group_id.clone();
+        let local_peer_id = self.identity.lock().await.peer_id();
+        let mut members = self.members.lock().await;
+        let mut group_members = members
+            .get(&group_id)
+            .cloned()
+            .unwrap_or_else(|| GroupMembers::from_founders(&invites));
+        group_members.admit(&group_id, invites);
+        if !group_members.contains(&local_peer_id) {
+            return Err(invalid());
+        }
+        self.store
+            .lock()
+            .await
+            .put_group_members(&group_id, &group_members)?;
+        members.insert(group_id.clone(), group_members);
+        Ok(group_id)
+    }
+    /// Apply `update` to the members of `group_id`, persisting them if it changed anything.
+    async fn update_members<F>(&self, group_id: &GroupId, update: F) -> Result<bool, StoreError>
+    where
+        F: FnOnce(&mut GroupMembers) -> bool,
+    {
+        let mut members = self.members.lock().await;
+        let Some(group_members) = members.get_mut(group_id) else {
+            return Ok(false);
+        };
+        if !update(group_members) {
+            return Ok(false);
+        }
+        self.store
+            .lock()
+            .await
+            .put_group_members(group_id, group_members)?;
+        Ok(true)
+    }
+    /// Take on the members `peer_id` knows of, see [`GroupMembers::merge`].
+    async fn merge_members(
+        &self,
+        group_id: &GroupId,
+        peer_id: &PeerId,
+        members: GroupMembers,
+    ) -> Result<bool, StoreError> {
+        self.update_members(group_id, |group_members| {
+            group_members.merge(group_id, peer_id, members)
+        })
+        .await
+    }
+    /// Void the invites `peer_id` was admitted to `group_id` with so far.
+    async fn revoke_member(&self, group_id: &GroupId, peer_id: PeerId) -> Result<(), StoreError> {
+        let revoked_at = Utc::now().timestamp_millis();
+        if self
+            .update_members(group_id, |members| members.revoke(peer_id, revoked_at))
+            .await?
+        {
+            log::info!("Revoked the membership of {peer_id} in group {group_id}");
         }
+        Ok(())
+    }
+    pub async fn is_member(&self, group_id: &GroupId, peer_id: &PeerId) -> bool {
+        self.members
+            .lock()
+            .await
+            .get(group_id)
+            .map_or(false, |members| members.contains(peer_id))
     }
-    pub async fn add_group(&self, group_id: GroupId, group_info: GroupInfo) {
+    async fn group_members(&self, group_id: &GroupId) -> GroupMembers {
+        self.members
+            .lock()
+            .await
+            .get(group_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+    pub async fn add_group(
+        &self,
+        group_id: GroupId,
+        group_info: GroupInfo,
+    ) -> Result<(), StoreError> {
+        self.store.lock().await.put_group(&group_id, &group_info)?;
         self.groups
             .lock()
             .await
             .insert(group_id.clone(), group_info);
-        self.group_state
+        self.subscribers.lock().await.entry(group_id).or_default();
+        Ok(())
+    }
+    pub async fn remove_group(&self, group_id: &GroupId) -> Result<(), StoreError> {
+        self.store.lock().await.remove_group(group_id)?;
+        self.group_keys
             .lock()
             .await
-            .insert(group_id, GroupState::new());
-    }
-    pub async fn remove_group(&self, group_id: &GroupId) {
+            .remove(&group_id.topic().hash());
+        self.search_index.lock().await.remove_group(group_id);
+        self.members.lock().await.remove(group_id);
         self.groups.lock().await.remove(group_id);
-        self.group_state.lock().await.remove(group_id);
+        self.subscribers.lock().await.remove(group_id);
+        Ok(())
     }
     pub async fn get_groups(&self) -> HashMap<GroupId, GroupInfo> {
         self.groups.lock().await.clone()
     }
-    pub async fn add_message(&self, group_id: &GroupId, message: GroupMessage) {
-        if let Some(group_status) = self.group_state.lock().await.get_mut(group_id.as_ref()) {
-            group_status.history.push(message);
+    /// Persist a message, returning `false` if it was already stored or the group is unknown.
+    pub async fn add_message(
+        &self,
+        group_id: &GroupId,
+        message: GroupMessage,
+    ) -> Result<bool, StoreError> {
+        if !self.has_group(group_id).await {
+            return Ok(false);
+        }
+        let stored = self.store.lock().await.put(group_id, message.clone())?;
+        if stored {
+            self.search_index.lock().await.insert(group_id, &message);
         }
+        Ok(stored)
     }
     pub async fn get_group_info(&self, group_id: &GroupId) -> Option<GroupInfo> {
         self.groups.lock().await.get(group_id).cloned()
     }
-    pub async fn get_group_state(&self, group_id: &GroupId) -> Option<GroupState> {
-        self.group_state.lock().await.get(group_id).cloned()
+    pub async fn get_group_state(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<GroupState>, StoreError> {
+        let Some(subscribers) = self.subscribers.lock().await.get(group_id).cloned() else {
+            return Ok(None);
+        };
+        let store = self.store.lock().await;
+        let latest = store.page(group_id, None, HistoryQuery::DEFAULT_LIMIT)?;
+        let message_count = store.count(group_id)?;
+        Ok(Some(GroupState::new(latest, message_count, subscribers)))
+    }
+    pub async fn get_history(&self, query: &HistoryQuery) -> Result<HistoryPage, ManagerError> {
+        if !self.has_group(&query.group_id).await {
+            return Err(ManagerError::GroupNotExist(query.group_id.clone()));
+        }
+        Ok(self
+            .store
+            .lock()
+            .await
+            .page(&query.group_id, query.cursor.as_ref(), query.limit)?)
+    }
+    pub async fn search(&self, query: &SearchQuery) -> Result<Vec<SearchHit>, ManagerError> {
+        let ranked = self.search_index.lock().await.search(query);
+        let store = self.store.lock().await;
+        let mut hits = Vec::with_capacity(ranked.len());
+        for ((group_id, id), score) in ranked {
+            let Some(message) = store.get(&group_id, &id)? else {
+                continue;
+            };
+            let context_before = store
+                .page(
+                    &group_id,
+                    Some(&HistoryCursor::BeforeId(id)),
+                    SEARCH_CONTEXT,
+                )?
+                .messages;
+            let context_after = store
+                .page(&group_id, Some(&HistoryCursor::AfterId(id)), SEARCH_CONTEXT)?
+                .messages;
+            hits.push(SearchHit {
+                snippet: search::snippet(&message.message, &query.query),
+                group_id,
+                message,
+                score,
+                context_before,
+                context_after,
+            });
+        }
+        Ok(hits)
+    }
+    pub async fn get_message_count(&self, group_id: &GroupId) -> Result<usize, ManagerError> {
+        if !self.has_group(group_id).await {
+            return Err(ManagerError::GroupNotExist(group_id.clone()));
+        }
+        Ok(self.store.lock().await.count(group_id)?)
     }
     pub async fn has_group(&self, group_id: &GroupId) -> bool {
         self.groups.lock().await.contains_key(group_id.as_ref())
@@ -75,21 +345,324 @@ impl GroupManager {
     pub async fn is_group_exist(&self, group_id: &GroupId) -> bool {
         self.groups.lock().await.contains_key(group_id)
     }
-    pub async fn add_subscribe(&self, group_id: &GroupId, peer_id: PeerId) {
-        if let Some(group_status) = self.group_state.lock().await.get_mut(group_id) {
-            group_status.subscribers.insert(peer_id);
+    pub async fn add_subscribe(
+        &self,
+        group_id: &GroupId,
+        peer_id: PeerId,
+    ) -> Result<(), StoreError> {
+        if let Some(subscribers) = self.subscribers.lock().await.get_mut(group_id) {
+            if subscribers.insert(peer_id) {
+                self.store
+                    .lock()
+                    .await
+                    .put_subscribers(group_id, subscribers)?;
+            }
         }
+        Ok(())
     }
-    pub async fn remove_subscribe(&self, group_id: &GroupId, peer_id: &PeerId) -> bool {
-        if let Some(group_status) = self.group_state.lock().await.get_mut(group_id) {
-            group_status.subscribers.remove(peer_id)
-        } else {
-            false
+    pub async fn remove_subscribe(
+        &self,
+        group_id: &GroupId,
+        peer_id: &PeerId,
+    ) -> Result<bool, StoreError> {
+        if let Some(subscribers) = self.subscribers.lock().await.get_mut(group_id) {
+            if subscribers.remove(peer_id) {
+                self.store
+                    .lock()
+                    .await
+                    .put_subscribers(group_id, subscribers)?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+    pub async fn get_subscribers(&self, group_id: &GroupId) -> Vec<PeerId> {
+        self.subscribers
+            .lock()
+            .await
+            .get(group_id)
+            .map(|subscribers| subscribers.iter().copied().collect())
+            .unwrap_or_default()
+    }
+    pub async fn is_subscribed(&self, group_id: &GroupId, peer_id: &PeerId) -> bool {
+        self.subscribers
+            .lock()
+            .await
+            .get(group_id)
+            .map_or(false, |subscribers| subscribers.contains(peer_id))
+    }
+    /// The group of `topic_hash` if both the local node and `peer_id` are members of it.
+    async fn shared_group(
+        &self,
+        topic_hash: &TopicHash,
+        peer_id: &PeerId,
+        client: &Client,
+    ) -> Option<GroupId> {
+        let group_id = self.get_group_by_hash(topic_hash).await?;
+        let members = self.members.lock().await;
+        let members = members.get(&group_id)?;
+        (members.contains(peer_id) && members.contains(&client.local_peer_id())).then_some(group_id)
+    }
+    /// Add keys of `group_id`, persisting them if any was new.
+    async fn add_group_keys(
+        &self,
+        group_id: &GroupId,
+        keys: Vec<GroupKey>,
+    ) -> Result<bool, StoreError> {
+        let topic_hash = group_id.topic().hash();
+        let mut group_keys = self.group_keys.lock().await;
+        if !group_keys.insert(topic_hash.clone(), keys) {
+            return Ok(false);
+        }
+        self.store
+            .lock()
+            .await
+            .put_group_keys(group_id, &group_keys.all(&topic_hash))?;
+        Ok(true)
+    }
+    async fn has_group_key(&self, group_id: &GroupId) -> bool {
+        self.group_keys
+            .lock()
+            .await
+            .current(&group_id.topic().hash())
+            .is_some()
+    }
+    /// The subscribed members of `group_id`, who the keys of the group are handed to.
+    async fn subscribed_members(&self, group_id: &GroupId) -> Vec<PeerId> {
+        let subscribers = self.get_subscribers(group_id).await;
+        let members = self.members.lock().await;
+        let Some(members) = members.get(group_id) else {
+            return Vec::new();
+        };
+        subscribers
+            .into_iter()
+            .filter(|peer_id| members.contains(peer_id))
+            .collect()
+    }
+    /// The subscribed member with the lowest peer id rotates the key of a group.
+    async fn is_key_leader(&self, group_id: &GroupId, peer_id: &PeerId) -> bool {
+        self.subscribed_members(group_id).await.iter().min() == Some(peer_id)
+    }
+    /// Generate the next key of `group_id` and hand it to every other member.
+    pub async fn rotate_group_key(
+        &self,
+        group_id: &GroupId,
+        client: &Client,
+    ) -> Result<(), NetworkError> {
+        let topic_hash = group_id.topic().hash();
+        let local_peer_id = client.local_peer_id();
+        let epoch = self
+            .group_keys
+            .lock()
+            .await
+            .current(&topic_hash)
+            .map_or(Some(0), |key| key.epoch.checked_add(1))
+            .ok_or_else(|| anyhow::anyhow!("the key of group {group_id} cannot be rotated"))?;
+        let key = GroupKey::generate(epoch, local_peer_id);
+        self.add_group_keys(group_id, vec![key.clone()]).await?;
+        log::info!("Rotated the key of group {group_id} to epoch {epoch}");
+
+        let members = self
+            .subscribed_members(group_id)
+            .await
+            .into_iter()
+            .filter(|peer_id| *peer_id != local_peer_id)
+            .collect::<Vec<_>>();
+        let group_members = self.group_members(group_id).await;
+        let responses = future::join_all(members.iter().map(|peer_id| {
+            client.request(
+                *peer_id,
+                Request::RotateGroupKey(topic_hash.clone(), key.clone(), group_members.clone()),
+            )
+        }))
+        .await;
+        for (peer_id, response) in members.into_iter().zip(responses) {
+            match response {
+                // members answer with their keys, which include any key rotated concurrently
+                Ok(Response::GroupKeys(keys, group_members)) => {
+                    self.merge_members(group_id, &peer_id, group_members)
+                        .await?;
+                    self.add_group_keys(group_id, keys).await?;
+                }
+                Ok(_) => log::warn!("unexpected response to key rotation from {peer_id}"),
+                Err(e) => {
+                    log::warn!("failed to hand the key of group {group_id} to {peer_id}: {e}")
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Fetch the keys of `group_id` from `peer_id`, exchanging the members each side knows of. If no member has a key yet, e.g. in groups created before messages were
+    /// encrypted, the key leader generates the first one.
+    async fn fetch_group_keys(
+        &self,
+        group_id: &GroupId,
+        peer_id: PeerId,
+        client: &Client,
+    ) -> Result<(), NetworkError> {
+        let request =
+            Request::GroupKeys(group_id.topic().hash(), self.group_members(group_id).await);
+        let Response::GroupKeys(keys, members) = client.request(peer_id, request).await? else {
+            return Err(anyhow::anyhow!("unexpected response to group key request").into());
+        };
+        self.merge_members(group_id, &peer_id, members).await?;
+        if !self.is_member(group_id, &peer_id).await {
+            return Err(anyhow::anyhow!("{peer_id} is not a member of group {group_id}").into());
+        }
+        self.add_group_keys(group_id, keys).await?;
+        if !self.has_group_key(group_id).await
+            && self.is_key_leader(group_id, &client.local_peer_id()).await
+        {
+            self.rotate_group_key(group_id, client).await?;
+        }
+        Ok(())
+    }
+    /// Fetch the keys of `group_id` from `peer_id` after it sent a message we could not open,
+    /// then sync the history of the group from it to recover the message.
+    async fn spawn_key_recovery(
+        &self,
+        group_id: GroupId,
+        peer_id: PeerId,
+        client: Client,
+        sender: mpsc::Sender<FrontendEvent>,
+    ) {
+        if !self
+            .key_requests
+            .lock()
+            .await
+            .insert((group_id.clone(), peer_id))
+        {
+            return;
+        }
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let fetched = manager.fetch_group_keys(&group_id, peer_id, &client).await;
+            manager
+                .key_requests
+                .lock()
+                .await
+                .remove(&(group_id.clone(), peer_id));
+            if let Err(e) = fetched {
+                log::warn!("failed to fetch the keys of group {group_id} from {peer_id}: {e}");
+                return;
+            }
+            manager
+                .synced_peers
+                .lock()
+                .await
+                .remove(&(group_id.clone(), peer_id));
+            manager
+                .spawn_history_sync(group_id, peer_id, client, sender)
+                .await;
+        });
+    }
+    /// Fetch the messages `peer_id` has for `group_id` since the last sync with it, page by page,
+    /// and emit the ones we did not have yet to the frontend in chronological order.
+    pub async fn sync_history(
+        &self,
+        group_id: GroupId,
+        peer_id: PeerId,
+        client: Client,
+        sender: mpsc::Sender<FrontendEvent>,
+    ) -> Result<(), NetworkError> {
+        // resume where the last sync with this peer stopped, or else start from the beginning,
+        // as messages received live say nothing about the ones missed before them. Step back a
+        // second so that messages sent in the same second as the last synced one are kept.
+        let position = self.store.lock().await.sync_position(&group_id, &peer_id)?;
+        let mut cursor = HistoryCursor::AfterTimestamp(
+            position.map_or(i64::MIN, |timestamp| timestamp.saturating_sub(1)),
+        );
+        for _ in 0..MAX_SYNC_PAGES {
+            let query = HistoryQuery {
+                group_id: group_id.clone(),
+                cursor: Some(cursor),
+                limit: SYNC_PAGE_SIZE,
+            };
+            let options = RequestOptions {
+                retries: SYNC_RETRIES,
+                ..Default::default()
+            };
+            let Response::History(page) = client
+                .request_with(peer_id, Request::History(query), options)
+                .await?
+            else {
+                return Err(anyhow::anyhow!("unexpected response to history request").into());
+            };
+            let Some(last) = page.messages.last() else {
+                break;
+            };
+            cursor = HistoryCursor::AfterId(last.id);
+            let last_timestamp = last.timestamp;
+
+            let mut synced = Vec::new();
+            for message in page.messages {
+                if self.add_message(&group_id, message.clone()).await? {
+                    synced.push(message);
+                }
+            }
+            self.store
+                .lock()
+                .await
+                .put_sync_position(&group_id, &peer_id, last_timestamp)?;
+            if !synced.is_empty() {
+                log::info!(
+                    "Synced {} messages of group {group_id:?} from {peer_id}",
+                    synced.len()
+                );
+                sender
+                    .send(FrontendEvent::HistorySync {
+                        group_id: group_id.clone(),
+                        messages: synced,
+                    })
+                    .await
+                    .unwrap();
+            }
+            if !page.has_more {
+                break;
+            }
+        }
+        Ok(())
+    }
+    /// Sync the history of `group_id` from `peer_id` in the background, once per session.
+    async fn spawn_history_sync(
+        &self,
+        group_id: GroupId,
+        peer_id: PeerId,
+        client: Client,
+        sender: mpsc::Sender<FrontendEvent>,
+    ) {
+        if !self
+            .synced_peers
+            .lock()
+            .await
+            .insert((group_id.clone(), peer_id))
+        {
+            return;
         }
+        let manager = self.clone();
+        tokio::spawn(async move {
+            // the peer only answers history requests of members it knows of, which it learns
+            // from the invites sent along with a key request
+            if let Err(e) = manager.fetch_group_keys(&group_id, peer_id, &client).await {
+                log::warn!("failed to fetch the keys of group {group_id} from {peer_id}: {e}");
+            }
+            if let Err(e) = manager
+                .sync_history(group_id.clone(), peer_id, client, sender)
+                .await
+            {
+                log::warn!("failed to sync history of {group_id:?} from {peer_id}: {e}");
+                // allow another attempt the next time the peer shows up
+                manager
+                    .synced_peers
+                    .lock()
+                    .await
+                    .remove(&(group_id, peer_id));
+            }
+        });
     }
     pub async fn has_any_subscriber(&self, group_id: &GroupId) -> bool {
-        if let Some(group_status) = self.group_state.lock().await.get(group_id) {
-            !group_status.subscribers.is_empty()
+        if let Some(subscribers) = self.subscribers.lock().await.get(group_id) {
+            !subscribers.is_empty()
         } else {
             false
         }
@@ -106,32 +679,115 @@ impl HandleInboundEvent for GroupManager {
         sender: mpsc::Sender<FrontendEvent>,
     ) -> Result<(), NetworkError> {
         match event {
-            InboundEvent::InboundRequest { request, channel } => match request {
-                Request::Group(topic_hash) => {
-                    if let Some(group) = self.get_group_by_hash(&topic_hash).await {
-                        let info = self.get_group_info(&group).await.unwrap();
-                        if let Some(channel) = channel.lock().await.take() {
-                            client
-                                .response(Response::Group((group, info)), channel)
-                                .await;
+            InboundEvent::InboundRequest {
+                peer_id,
+                request,
+                channel,
+            } => {
+                let response = match request {
+                    Request::Group(topic_hash) => {
+                        // the group may have been left since it was looked up
+                        let group = match self.get_group_by_hash(&topic_hash).await {
+                            Some(group) => {
+                                self.get_group_info(&group).await.map(|info| (group, info))
+                            }
+                            None => None,
+                        };
+                        match group {
+                            Some(group) => Response::Group(group),
+                            None => {
+                                log::warn!("group not found {topic_hash:?}");
+                                Response::error(ErrorKind::NotFound, "group not found")
+                            }
+                        }
+                    }
+                    Request::History(mut query) => {
+                        // history is sent in the clear over the connection, so only to members
+                        if self.is_member(&query.group_id, &peer_id).await
+                            && self
+                                .is_member(&query.group_id, &client.local_peer_id())
+                                .await
+                        {
+                            query.limit = query.limit.min(SYNC_PAGE_SIZE);
+                            match self.get_history(&query).await {
+                                Ok(page) => Response::History(page),
+                                Err(e) => {
+                                    log::warn!("failed to read history for {peer_id}: {e}");
+                                    Response::error(ErrorKind::Internal, e.to_string())
+                                }
+                            }
+                        } else {
+                            log::warn!(
+                                "{peer_id} requested history of group {} it is not a member of",
+                                query.group_id
+                            );
+                            Response::error(ErrorKind::Forbidden, "not a member of the group")
+                        }
+                    }
+                    Request::GroupKeys(topic_hash, members) => {
+                        if let Some(group_id) = self.get_group_by_hash(&topic_hash).await {
+                            if let Err(e) = self.merge_members(&group_id, &peer_id, members).await {
+                                log::warn!("failed to store the members of group {group_id}: {e}");
+                            }
+                        }
+                        if let Some(group_id) =
+                            self.shared_group(&topic_hash, &peer_id, &client).await
+                        {
+                            Response::GroupKeys(
+                                self.group_keys.lock().await.all(&topic_hash),
+                                self.group_members(&group_id).await,
+                            )
+                        } else {
+                            log::warn!(
+                                "{peer_id} requested the keys of a group it is not a member of"
+                            );
+                            Response::error(ErrorKind::Forbidden, "not a member of the group")
+                        }
+                    }
+                    Request::RotateGroupKey(topic_hash, key, members) => {
+                        if let Some(group_id) =
+                            self.shared_group(&topic_hash, &peer_id, &client).await
+                        {
+                            log::info!(
+                                "{peer_id} rotated the key of group {group_id} to epoch {}",
+                                key.epoch
+                            );
+                            if let Err(e) = self.merge_members(&group_id, &peer_id, members).await {
+                                log::warn!("failed to store the members of group {group_id}: {e}");
+                            }
+                            match self.add_group_keys(&group_id, vec![key]).await {
+                                Ok(_) => Response::GroupKeys(
+                                    self.group_keys.lock().await.all(&topic_hash),
+                                    self.group_members(&group_id).await,
+                                ),
+                                Err(e) => {
+                                    log::warn!("failed to store key of group {group_id}: {e}");
+                                    Response::error(ErrorKind::Internal, e.to_string())
+                                }
+                            }
+                        } else {
+                            log::warn!("{peer_id} sent a key of a group it is not a member of");
+                            Response::error(ErrorKind::Forbidden, "not a member of the group")
                         }
-                    } else {
-                        log::warn!("group not found {topic_hash:?}");
                     }
+                    _ => return Ok(()),
+                };
+                if let Some(channel) = channel.lock().await.take() {
+                    client.response(response, channel).await;
                 }
-                _ => {}
-            },
+            }
             InboundEvent::Message {
                 message_id: _,
                 topic,
                 message,
             } => {
                 if let Some(group_id) = self.get_group_by_hash(&topic).await {
-                    self.add_message(&group_id, message.clone()).await;
-                    sender
-                        .send(FrontendEvent::Message { group_id, message })
-                        .await
-                        .unwrap();
+                    if self.add_message(&group_id, message.clone()).await? {
+                        sender
+                            .send(FrontendEvent::Message { group_id, message })
+                            .await
+                            .unwrap();
+                    }
                 }
             }
             InboundEvent::Subscribed { peer_id, topic } => {
@@ -146,9 +802,16 @@ impl HandleInboundEvent for GroupManager {
                         }
                         // if local peer is not the one who create the group
                         _ => {
-                            let Ok(Response::Group((group_id, group_info))) = client.request(peer_id, Request::Group(topic.clone())).await else {
-                                        return Err(anyhow::anyhow!("group not found").into());
-                                    };
+                            let options = RequestOptions {
+                                retries: SYNC_RETRIES,
+                                ..Default::default()
+                            };
+                            let Ok(Response::Group((group_id, group_info))) = client
+                                .request_with(peer_id, Request::Group(topic.clone()), options)
+                                .await
+                            else {
+                                return Err(anyhow::anyhow!("group not found").into());
+                            };
                             (group_id, group_info)
                         }
                     };
@@ -159,23 +822,86 @@ impl HandleInboundEvent for GroupManager {
                         })
                         .await
                         .unwrap();
-                    self.add_group(group_id.clone(), group_info).await;
+                    self.add_group(group_id.clone(), group_info).await?;
+                    // only the creator of a group gets here through its own subscription
+                    if peer_id == client.local_peer_id() {
+                        self.found_group(&group_id).await?;
+                    }
                     group_id
                 };
 
-                self.add_subscribe(&group_id, peer_id).await;
+                self.add_subscribe(&group_id, peer_id).await?;
                 sender
-                    .send(FrontendEvent::Subscribed { group_id, peer_id })
+                    .send(FrontendEvent::Subscribed {
+                        group_id: group_id.clone(),
+                        peer_id,
+                    })
                     .await
                     .unwrap();
+
+                // catch up on the history of groups we are in from every peer we meet there
+                let local_peer_id = client.local_peer_id();
+                let peers = if peer_id == local_peer_id {
+                    self.get_subscribers(&group_id)
+                        .await
+                        .into_iter()
+                        .filter(|peer_id| *peer_id != local_peer_id)
+                        .collect()
+                } else if self.is_subscribed(&group_id, &local_peer_id).await {
+                    vec![peer_id]
+                } else {
+                    Vec::new()
+                };
+                if peer_id == local_peer_id
+                    && peers.is_empty()
+                    && !self.has_group_key(&group_id).await
+                {
+                    // nobody else is around to hand us a key, e.g. because we just created the group
+                    self.rotate_group_key(&group_id, &client).await?;
+                }
+                for peer_id in peers {
+                    self.spawn_history_sync(
+                        group_id.clone(),
+                        peer_id,
+                        client.clone(),
+                        sender.clone(),
+                    )
+                    .await;
+                }
+            }
+            InboundEvent::MissingGroupKey { peer_id, topic } => {
+                if let Some(group_id) = self.get_group_by_hash(&topic).await {
+                    self.spawn_key_recovery(group_id, peer_id, client, sender)
+                        .await;
+                }
             }
             InboundEvent::Unsubscribed { peer_id, topic } => {
                 if let Some(group_id) = self.get_group_by_hash(&topic).await {
-                    if self.remove_subscribe(&group_id, &peer_id).await {
+                    if self.remove_subscribe(&group_id, &peer_id).await? {
                         sender
-                            .send(FrontendEvent::Unsubscribed { group_id, peer_id })
+                            .send(FrontendEvent::Unsubscribed {
+                                group_id: group_id.clone(),
+                                peer_id,
+                            })
                             .await
                             .unwrap();
+                        let local_peer_id = client.local_peer_id();
+                        // the member that left has to be invited again to come back
+                        if peer_id != local_peer_id && self.is_member(&group_id, &peer_id).await {
+                            self.revoke_member(&group_id, peer_id).await?;
+                        }
+                        if peer_id != local_peer_id
+                            && self.is_subscribed(&group_id, &local_peer_id).await
+                            && self.is_key_leader(&group_id, &local_peer_id).await
+                        {
+                            // the member that left must not be able to read what is sent from now on
+                            let manager = self.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = manager.rotate_group_key(&group_id, &client).await {
+                                    log::warn!("failed to rotate the key of group {group_id}: {e}");
+                                }
+                            });
+                        }
                     }
                 }
             }
@@ -196,7 +922,32 @@ impl Invoke for GroupManager {
             "get_groups" => serde_json::to_value(self.get_groups().await)?,
             "get_group_state" if params.is_some() => {
                 let group_id = serde_json::from_value::<GroupId>(params.unwrap())?;
-                serde_json::to_value(self.get_group_state(&group_id).await.unwrap())?
+                serde_json::to_value(
+                    self.get_group_state(&group_id)
+                        .await?
+                        .ok_or(ManagerError::GroupNotExist(group_id))?,
+                )?
+            }
+            "get_history" if params.is_some() => {
+                let query = serde_json::from_value::<HistoryQuery>(params.unwrap())?;
+                serde_json::to_value(self.get_history(&query).await?)?
+            }
+            "search" if params.is_some() => {
+                let query = serde_json::from_value::<SearchQuery>(params.unwrap())?;
+                serde_json::to_value(self.search(&query).await?)?
+            }
+            "get_message_count" if params.is_some() => {
+                let group_id = serde_json::from_value::<GroupId>(params.unwrap())?;
+                serde_json::to_value(self.get_message_count(&group_id).await?)?
+            }
+            "invite" if params.is_some() => {
+                let InviteParams { group_id, peer_id } =
+                    serde_json::from_value::<InviteParams>(params.unwrap())?;
+                serde_json::to_value(self.invite(&group_id, peer_id).await?)?
+            }
+            "accept_invite" if params.is_some() => {
+                let code = serde_json::from_value::<String>(params.unwrap())?;
+                serde_json::to_value(self.accept_invite(&code).await?)?
             }
             c => return Err(ManagerError::InvalidAction(c.to_string())),
         };
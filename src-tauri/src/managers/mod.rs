@@ -1,5 +1,7 @@
-mod file;
+pub mod direct;
+pub mod file;
 pub mod group;
+pub mod peer;
 pub mod user;
 
 use crate::{
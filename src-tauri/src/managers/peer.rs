@@ -0,0 +1,231 @@
+use super::{AppManager, HandleInboundEvent, Invoke};
+use crate::{
+    chat_app::{frontend_event::FrontendEvent, AppState},
+    error::{ManagerError, NetworkError, StoreError},
+    models::{KnownPeer, ReconnectState},
+    network::{message::InboundEvent, Client},
+    store::SledStore,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::{mpsc, Mutex};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+/// Peers not connected for this long are forgotten.
+const KNOWN_PEER_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Remembers the contacts the node has been connected to and keeps reconnecting to them: on
+/// startup and whenever the last connection to one of them closes, with exponential backoff
+/// between failed dials. Contacts are the members of the groups of the node and the peers it
+/// exchanged direct messages with; DHT contacts, relays and bootstrap nodes are left out.
+#[derive(Debug, Clone)]
+pub struct PeerManager {
+    store: SledStore,
+    client: Client,
+    sender: mpsc::Sender<FrontendEvent>,
+    states: Arc<Mutex<HashMap<PeerId, ReconnectState>>>,
+    /// Peers a reconnect task is running for.
+    reconnecting: Arc<Mutex<HashSet<PeerId>>>,
+}
+
+impl PeerManager {
+    pub fn new(store: SledStore, client: Client, sender: mpsc::Sender<FrontendEvent>) -> Self {
+        Self {
+            store,
+            client,
+            sender,
+            states: Arc::new(Mutex::new(HashMap::new())),
+            reconnecting: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+    pub fn get_known_peers(&self) -> Result<HashMap<PeerId, KnownPeer>, StoreError> {
+        self.store.known_peers()
+    }
+    pub async fn get_reconnect_states(&self) -> HashMap<PeerId, ReconnectState> {
+        self.states.lock().await.clone()
+    }
+    /// Stop reconnecting to `peer_id` and forget it.
+    pub async fn forget(&self, peer_id: &PeerId) -> Result<(), StoreError> {
+        self.store.remove_known_peer(peer_id)?;
+        self.states.lock().await.remove(peer_id);
+        Ok(())
+    }
+    /// Dial every known peer, forgetting the ones that have not been seen for too long.
+    pub async fn reconnect_known_peers(&self) -> Result<(), StoreError> {
+        for (peer_id, peer) in self.store.known_peers()? {
+            if is_expired(&peer) {
+                log::info!("Forgetting {peer_id}, not seen since {}", peer.last_seen);
+                self.store.remove_known_peer(&peer_id)?;
+            } else if !self.is_contact(&peer_id)? {
+                log::info!("Forgetting {peer_id}, no longer a contact");
+                self.store.remove_known_peer(&peer_id)?;
+            } else {
+                // requests to the peer dial it at these addresses before looking it up
+                self.client
+                    .add_addresses(peer_id, peer.addresses.clone())
+                    .await;
+                self.spawn_reconnect(peer_id).await;
+            }
+        }
+        Ok(())
+    }
+    async fn set_state(&self, peer_id: PeerId, state: ReconnectState) {
+        let previous = self.states.lock().await.insert(peer_id, state.clone());
+        if previous.as_ref() != Some(&state) {
+            self.sender
+                .send(FrontendEvent::ReconnectState { peer_id, state })
+                .await
+                .unwrap();
+        }
+    }
+    fn is_contact(&self, peer_id: &PeerId) -> Result<bool, StoreError> {
+        if self.store.has_conversation(peer_id)? {
+            return Ok(true);
+        }
+        for group_id in self.store.groups()?.keys() {
+            if self.store.group_members(group_id)?.contains(peer_id) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+    async fn is_connected(&self, peer_id: &PeerId) -> bool {
+        self.states.lock().await.get(peer_id) == Some(&ReconnectState::Connected)
+    }
+    async fn spawn_reconnect(&self, peer_id: PeerId) {
+        if !self.reconnecting.lock().await.insert(peer_id) {
+            return;
+        }
+        let manager = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = manager.reconnect(peer_id).await {
+                log::warn!("Stopped reconnecting to {peer_id}: {e}");
+            }
+            manager.reconnecting.lock().await.remove(&peer_id);
+        });
+    }
+    /// Dial `peer_id` until it is connected, waiting twice as long after every failed dial.
+    /// Gives up once the peer is forgotten.
+    async fn reconnect(&self, peer_id: PeerId) -> Result<(), StoreError> {
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1.. {
+            let Some(peer) = self.store.known_peer(&peer_id)? else {
+                return Ok(());
+            };
+            if is_expired(&peer) {
+                return self.forget(&peer_id).await;
+            }
+            if self.is_connected(&peer_id).await {
+                return Ok(());
+            }
+            self.set_state(peer_id, ReconnectState::Dialing { attempt })
+                .await;
+            // without addresses the peer is looked up in the DHT right away
+            let looked_up = peer.addresses.is_empty();
+            let mut result = self.client.dial_addresses(peer_id, peer.addresses).await;
+            if result.is_err() && !looked_up {
+                // the peer may have moved, look it up in the DHT
+                result = self.client.dial(peer_id, None).await;
+            }
+            match result {
+                Ok(()) => {
+                    log::info!("Reconnected to {peer_id}");
+                    return Ok(());
+                }
+                Err(e) => log::debug!("Failed to reconnect to {peer_id}: {e}"),
+            }
+            // the peer may have connected to us meanwhile
+            if self.is_connected(&peer_id).await {
+                return Ok(());
+            }
+            let retry_at = Utc::now().timestamp() + backoff.as_secs() as i64;
+            self.set_state(peer_id, ReconnectState::Waiting { attempt, retry_at })
+                .await;
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+        Ok(())
+    }
+}
+
+fn is_expired(peer: &KnownPeer) -> bool {
+    Utc::now().timestamp() - peer.last_seen > KNOWN_PEER_TTL.as_secs() as i64
+}
+
+#[async_trait]
+impl HandleInboundEvent for PeerManager {
+    async fn handle_event(
+        &mut self,
+        event: InboundEvent,
+        _client: Client,
+        _state: AppState,
+        _sender: mpsc::Sender<FrontendEvent>,
+    ) -> Result<(), NetworkError> {
+        match event {
+            // peers are identified again from time to time, so someone who only just became a
+            // contact is remembered then
+            InboundEvent::PeerIdentified { peer_id, info } => {
+                if self.is_contact(&peer_id)? {
+                    self.store
+                        .put_known_peer(&peer_id, &KnownPeer::new(info.listen_addrs))?;
+                    self.set_state(peer_id, ReconnectState::Connected).await;
+                }
+            }
+            InboundEvent::ConnectionChanged {
+                peer_id,
+                connection: Some(_),
+            } => {
+                if let Some(peer) = self.store.known_peer(&peer_id)? {
+                    self.store
+                        .put_known_peer(&peer_id, &KnownPeer::new(peer.addresses))?;
+                    self.set_state(peer_id, ReconnectState::Connected).await;
+                }
+            }
+            InboundEvent::ConnectionChanged {
+                peer_id,
+                connection: None,
+            } => {
+                if self.store.known_peer(&peer_id)?.is_some() {
+                    self.states.lock().await.remove(&peer_id);
+                    self.spawn_reconnect(peer_id).await;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Invoke for PeerManager {
+    async fn invoke(
+        &self,
+        command: &str,
+        params: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value, ManagerError> {
+        let value = match command {
+            "known_peers" => serde_json::to_value(self.get_known_peers()?)?,
+            "reconnect_states" => serde_json::to_value(self.get_reconnect_states().await)?,
+            "forget" if params.is_some() => {
+                let peer_id = serde_json::from_value::<PeerId>(params.unwrap())?;
+                self.forget(&peer_id).await?;
+                serde_json::Value::Null
+            }
+            c => return Err(ManagerError::InvalidAction(c.to_string())),
+        };
+        Ok(value)
+    }
+}
+
+impl AppManager for PeerManager {
+    fn name(&self) -> &'static str {
+        "peer"
+    }
+}
@@ -2,24 +2,59 @@ use super::{AppManager, HandleInboundEvent, Invoke};
 use crate::{
     chat_app::{frontend_event::FrontendEvent, AppState},
     error::{ManagerError, NetworkError},
-    models::{UserInfo, UserState},
+    models::{ConnectionType, PeerInfo, UserInfo, UserState},
     network::{
-        message::{InboundEvent, Request, Response},
+        message::{ErrorKind, InboundEvent, Request, Response},
         Client,
     },
 };
 use async_trait::async_trait;
 use libp2p::{gossipsub::TopicHash, PeerId};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     sync::Arc,
+    time::Duration,
 };
 use tokio::sync::{mpsc, Mutex};
 
+/// How many pings the round trip time of a peer is averaged over.
+const RTT_SAMPLES: usize = 10;
+
+/// What the network told us about a peer. Kept apart from the users, as peers are connected and
+/// identified before their user info is known.
+#[derive(Debug, Default)]
+struct PeerRecord {
+    connection: Option<ConnectionType>,
+    info: Option<PeerInfo>,
+    rtts: VecDeque<Duration>,
+}
+
+impl PeerRecord {
+    fn rtt(&self) -> Option<f64> {
+        if self.rtts.is_empty() {
+            return None;
+        }
+        let total = self.rtts.iter().sum::<Duration>();
+        Some(total.as_secs_f64() * 1000.0 / self.rtts.len() as f64)
+    }
+
+    fn peer_info(&self) -> Option<PeerInfo> {
+        let mut info = self.info.clone()?;
+        info.rtt = self.rtt();
+        Some(info)
+    }
+
+    fn apply(&self, user_info: &mut UserInfo) {
+        user_info.connection = self.connection;
+        user_info.peer = self.peer_info();
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct UserManager {
     users: Arc<Mutex<HashMap<PeerId, UserInfo>>>,
     user_subscribe: Arc<Mutex<HashMap<PeerId, HashSet<TopicHash>>>>,
+    peers: Arc<Mutex<HashMap<PeerId, PeerRecord>>>,
 }
 
 impl UserManager {
@@ -27,9 +62,13 @@ impl UserManager {
         Self {
             users: Arc::new(Mutex::new(HashMap::new())),
             user_subscribe: Arc::new(Mutex::new(HashMap::new())),
+            peers: Arc::new(Mutex::new(HashMap::new())),
         }
     }
-    pub async fn add_user(&self, peer_id: PeerId, user_info: UserInfo) {
+    pub async fn add_user(&self, peer_id: PeerId, mut user_info: UserInfo) {
+        if let Some(record) = self.peers.lock().await.get(&peer_id) {
+            record.apply(&mut user_info);
+        }
         self.users.lock().await.insert(peer_id, user_info);
     }
     pub async fn remove_user(&self, peer_id: &PeerId) {
@@ -62,6 +101,24 @@ impl UserManager {
             user_info.status = status;
         }
     }
+    /// Update what is known of the peer `peer_id`, returning the user with the update applied if
+    /// the user is known.
+    async fn update_peer(
+        &self,
+        peer_id: &PeerId,
+        update: impl FnOnce(&mut PeerRecord),
+    ) -> Option<UserInfo> {
+        let mut peers = self.peers.lock().await;
+        let record = peers.entry(*peer_id).or_default();
+        update(record);
+        let mut users = self.users.lock().await;
+        let user_info = users.get_mut(peer_id)?;
+        record.apply(user_info);
+        Some(user_info.clone())
+    }
+    pub async fn get_peer_info(&self, peer_id: &PeerId) -> Option<PeerInfo> {
+        self.peers.lock().await.get(peer_id)?.peer_info()
+    }
     pub async fn get_user_subscribe(&self, peer_id: &PeerId) -> Option<Vec<TopicHash>> {
         self.user_subscribe
             .lock()
@@ -87,7 +144,9 @@ impl HandleInboundEvent for UserManager {
         sender: mpsc::Sender<FrontendEvent>,
     ) -> Result<(), NetworkError> {
         match event {
-            InboundEvent::InboundRequest { request, channel } => {
+            InboundEvent::InboundRequest {
+                request, channel, ..
+            } => {
                 if let Request::User(peer_id) = request {
                     if let Some(user_info) = self.get_user_info(&peer_id).await {
                         if let Some(channel) = channel.lock().await.take() {
@@ -102,6 +161,13 @@ impl HandleInboundEvent for UserManager {
                                 )
                                 .await;
                         }
+                    } else if let Some(channel) = channel.lock().await.take() {
+                        client
+                            .response(
+                                Response::error(ErrorKind::NotFound, "user not found"),
+                                channel,
+                            )
+                            .await;
                     }
                 }
             }
@@ -158,6 +224,53 @@ impl HandleInboundEvent for UserManager {
             InboundEvent::Unsubscribed { peer_id, topic } => {
                 self.remove_subscribe(&peer_id, &topic).await;
             }
+            InboundEvent::ConnectionChanged {
+                peer_id,
+                connection,
+            } => {
+                let updated = self
+                    .update_peer(&peer_id, |record| {
+                        record.connection = connection;
+                        if connection.is_none() {
+                            // round trips of an earlier connection say little about the next
+                            record.rtts.clear();
+                        }
+                    })
+                    .await;
+                if let Some(user_info) = updated {
+                    sender
+                        .send(FrontendEvent::UserUpdate { peer_id, user_info })
+                        .await
+                        .unwrap();
+                }
+            }
+            InboundEvent::PeerIdentified { peer_id, info } => {
+                let updated = self
+                    .update_peer(&peer_id, |record| record.info = Some(info))
+                    .await;
+                if let Some(user_info) = updated {
+                    sender
+                        .send(FrontendEvent::UserUpdate { peer_id, user_info })
+                        .await
+                        .unwrap();
+                }
+            }
+            InboundEvent::PeerPing { peer_id, rtt } => {
+                let updated = self
+                    .update_peer(&peer_id, |record| {
+                        if record.rtts.len() == RTT_SAMPLES {
+                            record.rtts.pop_front();
+                        }
+                        record.rtts.push_back(rtt);
+                    })
+                    .await;
+                if let Some(user_info) = updated {
+                    sender
+                        .send(FrontendEvent::UserUpdate { peer_id, user_info })
+                        .await
+                        .unwrap();
+                }
+            }
             _ => {}
         }
 
@@ -182,6 +295,14 @@ impl Invoke for UserManager {
                 )?
             }
             "get_users" => serde_json::to_value(self.get_users().await)?,
+            "get_peer_info" if params.is_some() => {
+                let peer_id = serde_json::from_value::<PeerId>(params.unwrap())?;
+                serde_json::to_value(
+                    self.get_peer_info(&peer_id)
+                        .await
+                        .ok_or(ManagerError::PeerNotExist(peer_id))?,
+                )?
+            }
             c => return Err(ManagerError::InvalidAction(c.to_string())),
         };
         Ok(value)
@@ -1,15 +1,17 @@
 use crate::{
     error::{SettingError, SettingErrorKind},
     network::message::Message,
+    transfer,
 };
 use chrono::Utc;
 use derive_more::Display;
-use libp2p::{gossipsub::Sha256Topic, PeerId};
+use libp2p::{gossipsub::Sha256Topic, multiaddr::Protocol, pnet::PreSharedKey, Multiaddr, PeerId};
 use mediatype::MediaTypeBuf;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashSet,
     hash::Hash,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     path::{Path, PathBuf},
 };
 use tokio::{
@@ -28,9 +30,11 @@ pub struct FileInfo {
     pub hash: Option<String>,
 }
 
+/// Files are identified by their content hash. Only files from peers that do not send a hash
+/// fall back to being compared by name.
 impl PartialEq for FileInfo {
     fn eq(&self, other: &Self) -> bool {
-        self.name == other.name
+        self.hash == other.hash && (self.hash.is_some() || self.name == other.name)
     }
 }
 
@@ -38,7 +42,10 @@ impl Eq for FileInfo {}
 
 impl Hash for FileInfo {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.name.hash(state);
+        match &self.hash {
+            Some(hash) => hash.hash(state),
+            None => self.name.hash(state),
+        }
     }
 }
 
@@ -57,7 +64,33 @@ impl FileInfo {
                 .to_string(),
             size: data.len(),
             file_type,
-            hash: None,
+            hash: Some(transfer::hash_file(path).await?),
+        })
+    }
+}
+
+/// How a file is split into fixed-size chunks for transfer, with the sha256 of every chunk
+/// so that the receiver can verify each one as it arrives.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileManifest {
+    pub file: FileInfo,
+    pub chunk_size: u64,
+    pub chunk_hashes: Vec<String>,
+}
+
+impl FileManifest {
+    pub fn chunk_count(&self) -> u64 {
+        self.chunk_hashes.len() as u64
+    }
+    /// Where the chunk at `index` starts in the file, unless that is beyond any file offset.
+    pub fn chunk_offset(&self, index: u64) -> Option<u64> {
+        index.checked_mul(self.chunk_size)
+    }
+    /// The length of the chunk at `index`; only the last chunk may be shorter than `chunk_size`.
+    pub fn chunk_len(&self, index: u64) -> u64 {
+        self.chunk_offset(index).map_or(0, |offset| {
+            self.file.size.saturating_sub(offset).min(self.chunk_size)
         })
     }
 }
@@ -67,6 +100,62 @@ impl FileInfo {
 pub struct Setting {
     pub recv_path: PathBuf,
     pub user_info: UserInfo,
+    /// Restricts the swarm to the nodes that have the same key. Without one any node may connect.
+    #[serde(default, with = "network_key")]
+    pub network_key: Option<PreSharedKey>,
+    /// Nodes the DHT is joined through, each address ending with the `/p2p` id of the node.
+    #[serde(default)]
+    pub bootstrap: Vec<Multiaddr>,
+    /// Relay connections between nodes that cannot reach each other directly.
+    #[serde(default)]
+    pub relay_server: bool,
+    /// Where to listen when no address is given.
+    #[serde(default)]
+    pub listen: ListenConfig,
+    /// The addresses listened on when the app was last closed, reopened on startup.
+    #[serde(default = "default_listen_addrs")]
+    pub listen_addrs: Vec<Multiaddr>,
+}
+
+fn default_listen_addrs() -> Vec<Multiaddr> {
+    ListenConfig::default().addresses()
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListenConfig {
+    /// Picked by the OS when 0.
+    pub tcp_port: u16,
+    /// Picked by the OS when 0.
+    pub quic_port: u16,
+    /// Also listen on every IPv6 interface. Ignored when the interfaces are given.
+    pub ipv6: bool,
+    /// The addresses of the interfaces to listen on, every interface when empty.
+    pub interfaces: Vec<IpAddr>,
+}
+
+impl ListenConfig {
+    /// A TCP and a QUIC address for each interface.
+    pub fn addresses(&self) -> Vec<Multiaddr> {
+        let mut interfaces = self.interfaces.clone();
+        if interfaces.is_empty() {
+            interfaces.push(Ipv4Addr::UNSPECIFIED.into());
+            if self.ipv6 {
+                interfaces.push(Ipv6Addr::UNSPECIFIED.into());
+            }
+        }
+        interfaces
+            .into_iter()
+            .flat_map(|ip| {
+                [
+                    Multiaddr::from(ip).with(Protocol::Tcp(self.tcp_port)),
+                    Multiaddr::from(ip)
+                        .with(Protocol::Udp(self.quic_port))
+                        .with(Protocol::Quic),
+                ]
+            })
+            .collect()
+    }
 }
 
 impl Setting {
@@ -111,10 +200,40 @@ impl Default for Setting {
         Self {
             recv_path: dirs::desktop_dir().unwrap_or_else(|| PathBuf::from(".")),
             user_info: UserInfo::default(),
+            network_key: None,
+            bootstrap: Vec::new(),
+            relay_server: false,
+            listen: ListenConfig::default(),
+            listen_addrs: default_listen_addrs(),
         }
     }
 }
 
+/// Network keys are kept in the key file format of go-libp2p, the same text they are shown and
+/// imported as.
+mod network_key {
+    use libp2p::pnet::PreSharedKey;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        key: &Option<PreSharedKey>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match key {
+            Some(key) => serializer.serialize_some(&key.to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<PreSharedKey>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|key| key.parse().map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
 #[derive(Debug, Clone, Display, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(rename_all = "camelCase")]
 pub struct GroupId(Uuid);
@@ -127,6 +246,14 @@ impl GroupId {
     pub fn topic(&self) -> Sha256Topic {
         Sha256Topic::new(self.0.to_string())
     }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        self.0.as_bytes()
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, uuid::Error> {
+        Ok(Self(Uuid::from_slice(bytes)?))
+    }
 }
 impl AsRef<GroupId> for &GroupId {
     fn as_ref(&self) -> &GroupId {
@@ -143,6 +270,10 @@ pub struct GroupInfo {
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct GroupMessage {
+    /// Nil when sent by a peer that predates message ids, until derived from the gossipsub
+    /// message id on receipt.
+    #[serde(default)]
+    pub id: Uuid,
     pub source: PeerId,
     pub timestamp: i64,
     pub message: Message,
@@ -151,14 +282,96 @@ pub struct GroupMessage {
 impl GroupMessage {
     pub fn new(message: Message, source: PeerId) -> Self {
         Self {
+            id: Uuid::new_v4(),
+            source,
+            timestamp: Utc::now().timestamp(),
+            message,
+        }
+    }
+}
+
+/// Where to start reading a page of group history from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HistoryCursor {
+    BeforeId(Uuid),
+    AfterId(Uuid),
+    BeforeTimestamp(i64),
+    AfterTimestamp(i64),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryQuery {
+    pub group_id: GroupId,
+    /// The latest messages are returned when no cursor is given.
+    #[serde(default)]
+    pub cursor: Option<HistoryCursor>,
+    #[serde(default = "HistoryQuery::default_limit")]
+    pub limit: usize,
+}
+
+impl HistoryQuery {
+    pub const DEFAULT_LIMIT: usize = 50;
+
+    fn default_limit() -> usize {
+        Self::DEFAULT_LIMIT
+    }
+}
+
+/// A page of history in chronological order. `has_more` tells whether further messages exist
+/// in the direction of the query.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryPage<M = GroupMessage> {
+    pub messages: Vec<M>,
+    pub has_more: bool,
+}
+
+/// A message sent straight to a single peer instead of through a group topic.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectMessage {
+    pub id: Uuid,
+    pub source: PeerId,
+    pub target: PeerId,
+    pub timestamp: i64,
+    pub message: Message,
+    /// Whether the target acknowledged the message. Always `true` for received messages.
+    #[serde(default)]
+    pub delivered: bool,
+}
+
+impl DirectMessage {
+    pub fn new(message: Message, source: PeerId, target: PeerId) -> Self {
+        Self {
+            id: Uuid::new_v4(),
             source,
+            target,
             timestamp: Utc::now().timestamp(),
             message,
+            delivered: false,
+        }
+    }
+    /// The other side of the conversation as seen from `local_peer_id`.
+    pub fn peer(&self, local_peer_id: &PeerId) -> PeerId {
+        if &self.source == local_peer_id {
+            self.target
+        } else {
+            self.source
         }
     }
 }
 
+/// A one-to-one conversation, keyed by the remote peer, with its latest message.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Conversation {
+    pub peer_id: PeerId,
+    pub last_message: DirectMessage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum FileSource {
     Local(PathBuf),
     Remote(PeerId),
@@ -181,6 +394,64 @@ pub enum UserState {
     Offline,
 }
 
+/// What a peer announced about itself over identify, and how far away it is.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerInfo {
+    pub listen_addrs: Vec<Multiaddr>,
+    pub protocols: Vec<String>,
+    pub protocol_version: String,
+    pub agent_version: String,
+    /// The mean round trip time of the latest pings, in milliseconds.
+    pub rtt: Option<f64>,
+}
+
+/// A peer the local node has been connected to, remembered to reconnect to it after a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KnownPeer {
+    /// The addresses the peer last announced over identify.
+    pub addresses: Vec<Multiaddr>,
+    /// When the peer was last connected, in seconds since the epoch.
+    pub last_seen: i64,
+}
+
+impl KnownPeer {
+    pub fn new(addresses: Vec<Multiaddr>) -> Self {
+        Self {
+            addresses,
+            last_seen: Utc::now().timestamp(),
+        }
+    }
+}
+
+/// How reconnecting to a known peer is going.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase", tag = "state")]
+pub enum ReconnectState {
+    Connected,
+    Dialing {
+        attempt: u32,
+    },
+    /// The dial failed, the next one is due at `retry_at`, in seconds since the epoch.
+    #[serde(rename_all = "camelCase")]
+    Waiting {
+        attempt: u32,
+        retry_at: i64,
+    },
+}
+
+/// The best connection the local node has to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConnectionType {
+    Direct,
+    /// Through a relay node, which sees the traffic go by but cannot read it.
+    Relayed,
+    /// Direct, upgraded from a relayed connection by punching through both NATs.
+    HolePunched,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LocalUserInfo {
     pub peer_id: Option<PeerId>,
@@ -194,6 +465,8 @@ impl From<LocalUserInfo> for UserInfo {
             name: info.name,
             avatar: info.avatar,
             status: UserState::Online,
+            connection: None,
+            peer: None,
         }
     }
 }
@@ -214,6 +487,11 @@ pub struct UserInfo {
     pub avatar: Option<Url>,
     #[serde(skip_deserializing)]
     pub status: UserState,
+    /// How the local node is connected to the user, if it is.
+    #[serde(skip_deserializing)]
+    pub connection: Option<ConnectionType>,
+    #[serde(skip_deserializing)]
+    pub peer: Option<PeerInfo>,
 }
 
 impl UserInfo {
@@ -222,6 +500,8 @@ impl UserInfo {
             name,
             avatar,
             status: UserState::Online,
+            connection: None,
+            peer: None,
         }
     }
 }
@@ -232,21 +512,29 @@ impl Default for UserInfo {
             name: "Anonymous".to_string(),
             avatar: None,
             status: UserState::Online,
+            connection: None,
+            peer: None,
         }
     }
 }
 
+/// The subscribers of a group together with its latest page of history.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct GroupState {
     pub history: Vec<GroupMessage>,
+    pub has_more_history: bool,
+    pub message_count: usize,
     pub subscribers: HashSet<PeerId>,
 }
 
 impl GroupState {
-    pub fn new() -> Self {
+    pub fn new(latest: HistoryPage, message_count: usize, subscribers: HashSet<PeerId>) -> Self {
         Self {
-            history: Vec::new(),
-            subscribers: HashSet::new(),
+            history: latest.messages,
+            has_more_history: latest.has_more,
+            message_count,
+            subscribers,
         }
     }
 }
@@ -1,44 +1,303 @@
-use super::message::{FileResponse, Request, Response};
+use super::message::{
+    ChunkRequest, ChunkResponse, DirectMessageAck, ErrorKind, ErrorResponse, FileResponse, Request,
+    Response, SealedDirectMessage,
+};
+use crate::{
+    crypto::{GroupKey, GroupMembers},
+    models::{FileInfo, FileManifest, GroupId, GroupInfo, HistoryPage, HistoryQuery, UserInfo},
+    transfer::MAX_CHUNK_SIZE,
+};
 use async_trait::async_trait;
 use derive_more::From;
 use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use libp2p::{
     core::upgrade::{read_length_prefixed, read_varint, write_length_prefixed, write_varint},
+    dcutr,
     gossipsub::{Gossipsub, GossipsubEvent, TopicHash},
-    mdns,
+    identify,
+    kad::{store::MemoryStore, Kademlia, KademliaEvent},
+    mdns, ping,
+    relay::v2::{client, relay},
     request_response::{ProtocolName, RequestResponse, RequestResponseCodec, RequestResponseEvent},
-    swarm::{keep_alive, NetworkBehaviour},
+    swarm::{behaviour::toggle::Toggle, keep_alive, NetworkBehaviour},
+    PeerId,
 };
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tokio::io;
 
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "ComposedEvent")]
 pub struct ComposedBehaviour {
     pub request_response: RequestResponse<FileExchangeCodec>,
+    pub file_transfer: RequestResponse<FileTransferCodec>,
+    pub direct_message: RequestResponse<DirectMessageCodec>,
     pub gossipsub: Gossipsub,
-    pub mdns: mdns::tokio::Behaviour,
+    /// Disabled where peers on the local network should not be discovered, as in tests.
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
+    pub kademlia: Kademlia<MemoryStore>,
+    pub relay_client: client::Client,
+    /// Only enabled when the node is set up to relay for others.
+    pub relay_server: Toggle<relay::Relay>,
+    pub dcutr: dcutr::behaviour::Behaviour,
+    pub identify: identify::Behaviour,
+    pub ping: ping::Behaviour,
     pub keep_alive: keep_alive::Behaviour,
 }
 
 #[derive(Debug, From)]
 pub enum ComposedEvent {
     RequestResponse(RequestResponseEvent<FileRequest, FileResponse>),
+    FileTransfer(RequestResponseEvent<ChunkRequest, ChunkResponse>),
+    DirectMessage(RequestResponseEvent<SealedDirectMessage, DirectMessageAck>),
     Gossipsub(GossipsubEvent),
     Mdns(mdns::Event),
+    Kademlia(KademliaEvent),
+    RelayClient(client::Event),
+    RelayServer(relay::Event),
+    Dcutr(dcutr::behaviour::Event),
+    Identify(identify::Event),
+    Ping(ping::Event),
     KeepAlive(void::Void),
 }
-// Simple file exchange protocol
-#[derive(Debug, Clone)]
-pub struct FileExchangeProtocol();
-#[derive(Clone)]
-pub struct FileExchangeCodec();
+// Information exchange protocol. Version 1 frames a request or response as a string prefix
+// naming its kind followed by JSON; version 2 encodes the typed frames below as CBOR. Both are
+// offered so that older peers keep working, version 2 preferred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileExchangeProtocol {
+    V1,
+    V2,
+}
+/// Each substream gets a clone of the codec, which reads the request and writes the response
+/// or writes the request and reads the response, so it remembers the id of the request between
+/// the two.
+#[derive(Clone, Default)]
+pub struct FileExchangeCodec {
+    request_id: Option<u64>,
+}
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone)]
 pub struct FileRequest(pub Request);
 
 impl ProtocolName for FileExchangeProtocol {
     fn protocol_name(&self) -> &[u8] {
-        "/information-exchange/1".as_bytes()
+        match self {
+            FileExchangeProtocol::V1 => "/information-exchange/1".as_bytes(),
+            FileExchangeProtocol::V2 => "/information-exchange/2".as_bytes(),
+        }
+    }
+}
+
+/// A request or response as sent over `/information-exchange/2`. The requester picks a random
+/// id for every request and the responder answers with the same id, so that a response can
+/// never be taken for the answer to another request.
+#[derive(Debug, Serialize, Deserialize)]
+struct WireFrame<T> {
+    id: u64,
+    body: T,
+}
+
+/// The body of a request frame. The variant names the kind of request, the responder answers
+/// with the variant of the same name or an error.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum WireRequest {
+    File(FileInfo),
+    Group(String),
+    User(PeerId),
+    History(HistoryQuery),
+    GroupKeys {
+        topic: String,
+        members: GroupMembers,
+    },
+    RotateGroupKey {
+        topic: String,
+        key: GroupKey,
+        members: GroupMembers,
+    },
+}
+
+/// The body of a response frame.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum WireResponse {
+    File(FileManifest),
+    Group(GroupId, GroupInfo),
+    User(UserInfo),
+    History(HistoryPage),
+    GroupKeys {
+        keys: Vec<GroupKey>,
+        members: GroupMembers,
+    },
+    Error(ErrorResponse),
+}
+
+impl From<Request> for WireRequest {
+    fn from(request: Request) -> Self {
+        match request {
+            Request::File(file) => WireRequest::File(file),
+            Request::Group(topic) => WireRequest::Group(topic.into_string()),
+            Request::User(peer) => WireRequest::User(peer),
+            Request::History(query) => WireRequest::History(query),
+            Request::GroupKeys(topic, members) => WireRequest::GroupKeys {
+                topic: topic.into_string(),
+                members,
+            },
+            Request::RotateGroupKey(topic, key, members) => WireRequest::RotateGroupKey {
+                topic: topic.into_string(),
+                key,
+                members,
+            },
+        }
+    }
+}
+
+impl From<WireRequest> for Request {
+    fn from(request: WireRequest) -> Self {
+        match request {
+            WireRequest::File(file) => Request::File(file),
+            WireRequest::Group(topic) => Request::Group(TopicHash::from_raw(topic)),
+            WireRequest::User(peer) => Request::User(peer),
+            WireRequest::History(query) => Request::History(query),
+            WireRequest::GroupKeys { topic, members } => {
+                Request::GroupKeys(TopicHash::from_raw(topic), members)
+            }
+            WireRequest::RotateGroupKey {
+                topic,
+                key,
+                members,
+            } => Request::RotateGroupKey(TopicHash::from_raw(topic), key, members),
+        }
+    }
+}
+
+impl From<Response> for WireResponse {
+    fn from(response: Response) -> Self {
+        match response {
+            Response::File(manifest) => WireResponse::File(manifest),
+            Response::Group((group_id, info)) => WireResponse::Group(group_id, info),
+            Response::User(user) => WireResponse::User(user),
+            Response::History(page) => WireResponse::History(page),
+            Response::GroupKeys(keys, members) => WireResponse::GroupKeys { keys, members },
+            Response::Error(error) => WireResponse::Error(error),
+        }
+    }
+}
+
+impl From<WireResponse> for Response {
+    fn from(response: WireResponse) -> Self {
+        match response {
+            WireResponse::File(manifest) => Response::File(manifest),
+            WireResponse::Group(group_id, info) => Response::Group((group_id, info)),
+            WireResponse::User(user) => Response::User(user),
+            WireResponse::History(page) => Response::History(page),
+            WireResponse::GroupKeys { keys, members } => Response::GroupKeys(keys, members),
+            WireResponse::Error(error) => Response::Error(error),
+        }
+    }
+}
+
+fn invalid_data(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+fn to_cbor<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    ciborium::ser::into_writer(value, &mut data).map_err(invalid_data)?;
+    Ok(data)
+}
+
+fn from_cbor<T: DeserializeOwned>(data: &[u8]) -> io::Result<T> {
+    ciborium::de::from_reader(data).map_err(invalid_data)
+}
+
+/// Split a version 1 frame into its kind and body.
+fn split_v1(data: &[u8]) -> io::Result<(&str, &[u8])> {
+    let space_pos = data
+        .iter()
+        .position(|&b| b == b' ')
+        .ok_or::<io::Error>(io::ErrorKind::InvalidData.into())?;
+    let kind = std::str::from_utf8(&data[0..space_pos]).map_err(invalid_data)?;
+    Ok((kind, &data[space_pos + 1..]))
+}
+
+fn from_json<T: DeserializeOwned>(data: &[u8]) -> io::Result<T> {
+    serde_json::from_slice(data).map_err(invalid_data)
+}
+
+fn to_json<T: Serialize>(kind: &[u8], value: &T) -> io::Result<Vec<u8>> {
+    let data = serde_json::to_vec(value).map_err(invalid_data)?;
+    Ok([kind, data.as_slice()].concat())
+}
+
+fn decode_request_v1(data: &[u8]) -> io::Result<Request> {
+    let (kind, body) = split_v1(data)?;
+    let request = match kind {
+        "/file" => Request::File(from_json(body)?),
+        "/group" => {
+            let topic_hash = std::str::from_utf8(body).map_err(invalid_data)?;
+            Request::Group(TopicHash::from_raw(topic_hash))
+        }
+        "/user" => Request::User(from_json(body)?),
+        "/history" => Request::History(from_json(body)?),
+        "/group-keys" => {
+            let topic_hash = std::str::from_utf8(body).map_err(invalid_data)?;
+            // peers on the first version know nothing of members
+            Request::GroupKeys(TopicHash::from_raw(topic_hash), GroupMembers::default())
+        }
+        "/rotate-group-key" => {
+            let (topic_hash, key) = from_json::<(String, _)>(body)?;
+            Request::RotateGroupKey(
+                TopicHash::from_raw(topic_hash),
+                key,
+                GroupMembers::default(),
+            )
+        }
+        err => return Err(invalid_data(err)),
+    };
+    Ok(request)
+}
+
+fn decode_response_v1(data: &[u8]) -> io::Result<Response> {
+    let (kind, body) = split_v1(data)?;
+    let response = match kind {
+        "/file" => Response::File(from_json(body)?),
+        "/group" => Response::Group(from_json(body)?),
+        // older peers send the bare message, which is all they show
+        "/error" => Response::Error(from_json(body).unwrap_or_else(|_| ErrorResponse {
+            kind: ErrorKind::Internal,
+            message: String::from_utf8_lossy(body).into_owned(),
+        })),
+        "/user" => Response::User(from_json(body)?),
+        "/history" => Response::History(from_json(body)?),
+        "/group-keys" => Response::GroupKeys(from_json(body)?, GroupMembers::default()),
+        err => return Err(invalid_data(err)),
+    };
+    Ok(response)
+}
+
+fn encode_request_v1(request: Request) -> io::Result<Vec<u8>> {
+    match request {
+        Request::File(file) => to_json(b"/file ", &file),
+        Request::Group(topic_hash) => Ok([b"/group ", topic_hash.as_str().as_bytes()].concat()),
+        Request::User(peer) => to_json(b"/user ", &peer),
+        Request::History(query) => to_json(b"/history ", &query),
+        Request::GroupKeys(topic_hash, _) => {
+            Ok([b"/group-keys ", topic_hash.as_str().as_bytes()].concat())
+        }
+        Request::RotateGroupKey(topic_hash, key, _) => {
+            to_json(b"/rotate-group-key ", &(topic_hash.as_str(), key))
+        }
+    }
+}
+
+fn encode_response_v1(response: Response) -> io::Result<Vec<u8>> {
+    match response {
+        Response::File(manifest) => to_json(b"/file ", &manifest),
+        Response::Group(pair) => to_json(b"/group ", &pair),
+        Response::User(user) => to_json(b"/user ", &user),
+        Response::History(page) => to_json(b"/history ", &page),
+        Response::GroupKeys(keys, _) => to_json(b"/group-keys ", &keys),
+        Response::Error(error) => to_json(b"/error ", &error),
     }
 }
 
@@ -50,7 +309,7 @@ impl RequestResponseCodec for FileExchangeCodec {
 
     async fn read_request<T>(
         &mut self,
-        _: &FileExchangeProtocol,
+        protocol: &FileExchangeProtocol,
         io: &mut T,
     ) -> io::Result<Self::Request>
     where
@@ -62,84 +321,67 @@ impl RequestResponseCodec for FileExchangeCodec {
             return Err(io::ErrorKind::UnexpectedEof.into());
         }
 
-        let space_pos = data
-            .iter()
-            .position(|&b| b == b' ')
-            .ok_or::<io::Error>(io::ErrorKind::InvalidData.into())?;
-        let string_part = std::str::from_utf8(&data[0..space_pos])
-            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
-
-        match string_part {
-            "/file" => serde_json::from_slice(&data[space_pos + 1..]).map_or_else(
-                |err| Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
-                |file| Ok(FileRequest(Request::File(file))),
-            ),
-            "/group" => {
-                let topic_hash =
-                    TopicHash::from_raw(std::str::from_utf8(&data[space_pos + 1..]).unwrap());
-                Ok(FileRequest(Request::Group(topic_hash)))
+        let request = match protocol {
+            FileExchangeProtocol::V1 => decode_request_v1(&data)?,
+            FileExchangeProtocol::V2 => {
+                let frame = from_cbor::<WireFrame<WireRequest>>(&data)?;
+                self.request_id = Some(frame.id);
+                frame.body.into()
             }
-            "/user" => serde_json::from_slice(&data[space_pos + 1..]).map_or_else(
-                |err| Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
-                |peer| Ok(FileRequest(Request::User(peer))),
-            ),
-            err => Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
-        }
+        };
+        Ok(FileRequest(request))
     }
 
     async fn read_response<T>(
         &mut self,
-        _: &FileExchangeProtocol,
+        protocol: &FileExchangeProtocol,
         io: &mut T,
     ) -> io::Result<Self::Response>
     where
         T: AsyncRead + Unpin + Send,
     {
-        let data = read_length_prefixed(io, 500_000_000).await?; // update transfer maximum
+        // file contents go over the file transfer protocol, so responses only carry metadata
+        let data = read_length_prefixed(io, 64_000_000).await?;
         if data.is_empty() {
             return Err(io::ErrorKind::UnexpectedEof.into());
         }
-        let space_pos = data.iter().position(|&b| b == b' ').unwrap();
-        let string_part = std::str::from_utf8(&data[0..space_pos]).unwrap();
-        match string_part {
-            "/file" => Ok(FileResponse(Response::File(data[space_pos + 1..].to_vec()))),
-            "/group" => serde_json::from_slice(&data[space_pos + 1..]).map_or_else(
-                |err| Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
-                |pair| Ok(FileResponse(Response::Group(pair))),
-            ),
-            "/error" => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                std::str::from_utf8(&data[space_pos + 1..]).unwrap(),
-            )),
-            "/user" => serde_json::from_slice(&data[space_pos + 1..]).map_or_else(
-                |err| Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
-                |peer| Ok(FileResponse(Response::User(peer))),
-            ),
-            _ => Err(io::ErrorKind::InvalidData.into()),
-        }
+        let response = match protocol {
+            FileExchangeProtocol::V1 => decode_response_v1(&data)?,
+            FileExchangeProtocol::V2 => {
+                let frame = from_cbor::<WireFrame<WireResponse>>(&data)?;
+                if Some(frame.id) != self.request_id {
+                    return Err(invalid_data(format!(
+                        "response to request {} instead of {:?}",
+                        frame.id, self.request_id
+                    )));
+                }
+                frame.body.into()
+            }
+        };
+        Ok(FileResponse(response))
     }
 
     async fn write_request<T>(
         &mut self,
-        _: &FileExchangeProtocol,
+        protocol: &FileExchangeProtocol,
         io: &mut T,
-        FileRequest(data): FileRequest,
+        FileRequest(request): FileRequest,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send,
     {
-        let req = match data {
-            Request::File(file) => {
-                let data = serde_json::to_vec(&file).unwrap();
-                [b"/file ", data.as_slice()].concat()
-            }
-            Request::Group(topic_hash) => [b"/group ", topic_hash.as_str().as_bytes()].concat(),
-            Request::User(peer) => {
-                let data = serde_json::to_vec(&peer).unwrap();
-                [b"/user ", data.as_slice()].concat()
+        let data = match protocol {
+            FileExchangeProtocol::V1 => encode_request_v1(request)?,
+            FileExchangeProtocol::V2 => {
+                let id = rand::random();
+                self.request_id = Some(id);
+                to_cbor(&WireFrame {
+                    id,
+                    body: WireRequest::from(request),
+                })?
             }
         };
-        write_length_prefixed(io, req).await?;
+        write_length_prefixed(io, data).await?;
         io.close().await?;
 
         Ok(())
@@ -147,23 +389,192 @@ impl RequestResponseCodec for FileExchangeCodec {
 
     async fn write_response<T>(
         &mut self,
-        _: &FileExchangeProtocol,
+        protocol: &FileExchangeProtocol,
         io: &mut T,
-        FileResponse(resp): FileResponse,
+        FileResponse(response): FileResponse,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send,
     {
-        let resp_data = match resp {
-            Response::File(data) => [b"/file ", data.as_slice()].concat(),
-            Response::Group(pair) => {
-                [b"/group ", serde_json::to_vec(&pair).unwrap().as_slice()].concat()
-            }
-            Response::User(user) => {
-                [b"/user ", serde_json::to_vec(&user).unwrap().as_slice()].concat()
+        let data = match protocol {
+            FileExchangeProtocol::V1 => encode_response_v1(response)?,
+            FileExchangeProtocol::V2 => {
+                let id = self
+                    .request_id
+                    .ok_or_else(|| invalid_data("response without a request"))?;
+                to_cbor(&WireFrame {
+                    id,
+                    body: WireResponse::from(response),
+                })?
             }
         };
-        write_length_prefixed(io, resp_data).await?;
+        write_length_prefixed(io, data).await?;
+        io.close().await?;
+
+        Ok(())
+    }
+}
+
+// Chunked file transfer protocol. Every response is a JSON header followed, for chunks, by
+// the raw chunk data in a frame of its own.
+#[derive(Debug, Clone)]
+pub struct FileTransferProtocol();
+#[derive(Clone)]
+pub struct FileTransferCodec();
+
+impl ProtocolName for FileTransferProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        "/file-transfer/1".as_bytes()
+    }
+}
+
+#[async_trait]
+impl RequestResponseCodec for FileTransferCodec {
+    type Protocol = FileTransferProtocol;
+    type Request = ChunkRequest;
+    type Response = ChunkResponse;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &FileTransferProtocol,
+        io: &mut T,
+    ) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, 1_000_000).await?;
+        if data.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &FileTransferProtocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let header = read_length_prefixed(io, 1_000_000).await?;
+        if header.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut response = serde_json::from_slice::<ChunkResponse>(&header)?;
+        if let ChunkResponse::Chunk(chunk) = &mut response {
+            chunk.data = read_length_prefixed(io, MAX_CHUNK_SIZE as usize).await?;
+        }
+        Ok(response)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &FileTransferProtocol,
+        io: &mut T,
+        request: ChunkRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, serde_json::to_vec(&request)?).await?;
+        io.close().await?;
+
+        Ok(())
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &FileTransferProtocol,
+        io: &mut T,
+        response: ChunkResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, serde_json::to_vec(&response)?).await?;
+        if let ChunkResponse::Chunk(chunk) = response {
+            write_length_prefixed(io, chunk.data).await?;
+        }
+        io.close().await?;
+
+        Ok(())
+    }
+}
+
+// One-to-one messages outside of gossipsub. The response acknowledges delivery.
+#[derive(Debug, Clone)]
+pub struct DirectMessageProtocol();
+#[derive(Clone)]
+pub struct DirectMessageCodec();
+
+impl ProtocolName for DirectMessageProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        "/direct-message/2".as_bytes()
+    }
+}
+
+#[async_trait]
+impl RequestResponseCodec for DirectMessageCodec {
+    type Protocol = DirectMessageProtocol;
+    type Request = SealedDirectMessage;
+    type Response = DirectMessageAck;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &DirectMessageProtocol,
+        io: &mut T,
+    ) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, 1_000_000).await?;
+        if data.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &DirectMessageProtocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, 1_000).await?;
+        if data.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &DirectMessageProtocol,
+        io: &mut T,
+        message: SealedDirectMessage,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, serde_json::to_vec(&message)?).await?;
+        io.close().await?;
+
+        Ok(())
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &DirectMessageProtocol,
+        io: &mut T,
+        ack: DirectMessageAck,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, serde_json::to_vec(&ack)?).await?;
         io.close().await?;
 
         Ok(())
@@ -5,10 +5,16 @@ use libp2p::{
     Multiaddr, PeerId,
 };
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 use tokio::sync::Mutex;
+use uuid::Uuid;
 
-use crate::models::{FileInfo, GroupId, GroupInfo, GroupMessage, UserInfo};
+use crate::crypto::{GroupKey, GroupMembers};
+use crate::models::{
+    ConnectionType, FileInfo, FileManifest, GroupId, GroupInfo, GroupMessage, HistoryPage,
+    HistoryQuery, PeerInfo, UserInfo,
+};
+use crate::ratchet::Envelope;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
@@ -20,14 +26,30 @@ pub enum Message {
 #[derive(Debug, Clone)]
 pub enum InboundEvent {
     InboundRequest {
+        peer_id: PeerId,
         request: Request,
         channel: Arc<Mutex<Option<ResponseChannel<FileResponse>>>>,
     },
+    ChunkRequest {
+        peer_id: PeerId,
+        request: ChunkRequest,
+        channel: Arc<Mutex<Option<ResponseChannel<ChunkResponse>>>>,
+    },
+    DirectMessage {
+        peer_id: PeerId,
+        message: SealedDirectMessage,
+        channel: Arc<Mutex<Option<ResponseChannel<DirectMessageAck>>>>,
+    },
     Message {
         message_id: MessageId,
         topic: TopicHash,
         message: GroupMessage,
     },
+    /// A group message was sealed with a key the local node does not have; `peer_id` sent it.
+    MissingGroupKey {
+        peer_id: PeerId,
+        topic: TopicHash,
+    },
     Subscribed {
         peer_id: PeerId,
         topic: TopicHash,
@@ -42,6 +64,24 @@ pub enum InboundEvent {
     PeerExpired {
         peer_id: PeerId,
     },
+    ConnectionEstablished {
+        peer_id: PeerId,
+    },
+    /// `peer_id` told us about itself over identify.
+    PeerIdentified {
+        peer_id: PeerId,
+        info: PeerInfo,
+    },
+    /// A ping to `peer_id` made it back after `rtt`.
+    PeerPing {
+        peer_id: PeerId,
+        rtt: Duration,
+    },
+    /// The best connection to `peer_id` changed, or there is none left.
+    ConnectionChanged {
+        peer_id: PeerId,
+        connection: Option<ConnectionType>,
+    },
     NewListenAddr {
         listener_id: ListenerId,
         address: Multiaddr,
@@ -52,18 +92,135 @@ pub enum InboundEvent {
     },
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone)]
 pub enum Request {
     File(FileInfo),
     Group(TopicHash),
     User(PeerId),
+    History(HistoryQuery),
+    /// Ask a member for the keys of a group, along with the members the sender knows of, whose
+    /// invites prove that it is a member too.
+    GroupKeys(TopicHash, GroupMembers),
+    /// Hand a freshly rotated key to a member, along with the members the sender knows of, so
+    /// that the revocation the key was rotated for reaches it.
+    RotateGroupKey(TopicHash, GroupKey, GroupMembers),
 }
 
 #[derive(Debug, Clone)]
 pub enum Response {
-    File(Vec<u8>),
+    File(FileManifest),
     Group((GroupId, GroupInfo)),
     User(UserInfo),
+    History(HistoryPage),
+    /// The keys of a group and its members known to the responder.
+    GroupKeys(Vec<GroupKey>, GroupMembers),
+    /// The request could not be answered.
+    Error(ErrorResponse),
+}
+
+impl Response {
+    pub fn error(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Response::Error(ErrorResponse {
+            kind,
+            message: message.into(),
+        })
+    }
 }
 #[derive(Debug, Clone)]
 pub struct FileResponse(pub Response);
+
+/// Why a peer could not answer a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorKind {
+    NotFound,
+    Forbidden,
+    Internal,
+}
+
+/// Sent back instead of a response when a request can not be answered.
+#[derive(Debug, Clone, Serialize, Deserialize, thiserror::Error)]
+#[serde(rename_all = "camelCase")]
+#[error("{kind:?}: {message}")]
+pub struct ErrorResponse {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+/// Ask for the chunk at `index` of `file` over the file transfer protocol.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChunkRequest {
+    pub file: FileInfo,
+    pub index: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ChunkResponse {
+    Chunk(Chunk),
+    /// The peer does not provide the file (any more).
+    Unavailable,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Chunk {
+    pub index: u64,
+    /// The sha256 of `data`, hex encoded.
+    pub hash: String,
+    /// Sent as a raw frame after the header rather than as JSON.
+    #[serde(skip)]
+    pub data: Vec<u8>,
+}
+
+/// A [`DirectMessage`](crate::models::DirectMessage) encrypted for its target. Only the
+/// addressing is readable in transit, it is authenticated along with the message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SealedDirectMessage {
+    pub source: PeerId,
+    pub target: PeerId,
+    pub envelope: Envelope,
+}
+
+impl SealedDirectMessage {
+    pub fn associated_data(source: &PeerId, target: &PeerId) -> Vec<u8> {
+        [source.to_bytes(), target.to_bytes()].concat()
+    }
+}
+
+/// Sent back by the target of a direct message once it has stored the message, or with the
+/// reason it could not open the message, in which case `id` is nil.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectMessageAck {
+    pub id: Uuid,
+    /// Never set by older peers, which drop messages they cannot open.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rejected: Option<DirectRejection>,
+}
+
+impl DirectMessageAck {
+    pub fn delivered(id: Uuid) -> Self {
+        Self { id, rejected: None }
+    }
+
+    pub fn rejected(reason: DirectRejection) -> Self {
+        Self {
+            id: Uuid::nil(),
+            rejected: Some(reason),
+        }
+    }
+}
+
+/// Why the target of a direct message could not open it. Either way the sender starts a new
+/// session and sends the message again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DirectRejection {
+    /// The session was replaced, by the target or by a session both sides started at once.
+    StaleSession,
+    /// The message does not decrypt in the session, e.g. because the target lost its state.
+    Undecryptable,
+}
@@ -1,36 +1,71 @@
 pub mod behaviour;
 pub mod message;
 
-use crate::error::NetworkError;
-use crate::models::{GroupId, GroupInfo, GroupMessage};
+use crate::crypto::GroupKeys;
+use crate::error::{CryptoError, NetworkError};
+use crate::models::{ConnectionType, GroupId, GroupInfo, GroupMessage, PeerInfo, Setting};
 
 /// The network module, encapsulating all network related logic.
 use futures::StreamExt;
 
-use libp2p::gossipsub::{GossipsubEvent, MessageId, Sha256Topic};
-use libp2p::identity::ed25519;
+use libp2p::core::either::{EitherOutput, EitherTransport};
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::Boxed;
+use libp2p::core::upgrade::{SelectUpgrade, Version};
+use libp2p::core::ConnectedPoint;
+use libp2p::dcutr;
+use libp2p::gossipsub::{GossipsubEvent, MessageId, Sha256Topic, TopicHash};
+use libp2p::kad::store::MemoryStore;
+use libp2p::kad::{
+    GetClosestPeersError, GetClosestPeersOk, Kademlia, KademliaConfig, KademliaEvent, QueryId,
+    QueryResult,
+};
+use libp2p::multiaddr::Protocol;
+use libp2p::pnet::{PnetConfig, PreSharedKey};
+use libp2p::relay::v2::client::{self as relay_client, transport::ClientTransport};
+use libp2p::relay::v2::relay;
 use libp2p::request_response::{
-    ProtocolSupport, RequestId, RequestResponse, RequestResponseEvent, RequestResponseMessage,
-    ResponseChannel,
+    ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig, RequestResponseEvent,
+    RequestResponseMessage, ResponseChannel,
 };
+use libp2p::swarm::behaviour::toggle::Toggle;
 use libp2p::swarm::derive_prelude::ListenerId;
-use libp2p::swarm::{keep_alive, Swarm, SwarmBuilder, SwarmEvent};
-use libp2p::{gossipsub, mdns};
+use libp2p::swarm::dial_opts::DialOpts;
+use libp2p::swarm::{
+    keep_alive, AddressScore, DialError, NetworkBehaviour, Swarm, SwarmBuilder, SwarmEvent,
+};
+use libp2p::{dns, gossipsub, mdns, mplex, noise, quic, tcp, websocket, yamux, Transport};
+use libp2p::{identify, ping};
 use libp2p::{identity, Multiaddr, PeerId};
+use std::borrow::Cow;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::{hash_map, HashMap};
+use std::collections::{hash_map, HashMap, HashSet};
 use std::error::Error;
 use std::fmt::Debug;
 use std::hash::{Hash, Hasher};
+use std::io;
+use std::num::NonZeroU8;
 
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use tokio::sync::{mpsc, oneshot, Mutex};
+use uuid::Uuid;
 
 use self::behaviour::*;
 use self::message::*;
 
+const KADEMLIA_PROTOCOL: &[u8] = b"/chat/kad/1.0.0";
+const IDENTIFY_PROTOCOL_VERSION: &str = "/chat/1.0.0";
+/// How often the Kademlia routing table is refreshed.
+const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// The longest a request may wait for its response, whatever its [`RequestOptions`].
+const MAX_REQUEST_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// The longest wait between two attempts of a request, whatever its [`RequestOptions`].
+const MAX_REQUEST_BACKOFF: Duration = Duration::from_secs(60);
+/// How often requests whose callers stopped waiting are forgotten.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(30);
+
 pub struct Network {
     pub client: Client,
     pub peer_id: PeerId,
@@ -38,19 +73,90 @@ pub struct Network {
     pub event_receiver: mpsc::Receiver<InboundEvent>,
 }
 
-pub fn new(secret_key_seed: Option<u8>) -> anyhow::Result<Network> {
-    // Create a public/private key pair, either random or based on a seed.
-    let id_keys = match secret_key_seed {
-        Some(seed) => {
-            let mut bytes = [0u8; 32];
-            bytes[0] = seed;
-            let secret_key = ed25519::SecretKey::from_bytes(&mut bytes).expect(
-                "this returns `Err` only if the length is wrong; the length is correct; qed",
-            );
-            identity::Keypair::Ed25519(secret_key.into())
+/// The parts of the [`Setting`] the swarm is built from.
+#[derive(Debug, Clone, Default)]
+pub struct SwarmConfig {
+    pub network_key: Option<PreSharedKey>,
+    pub relay_server: bool,
+    /// Whether peers on the local network are discovered with mDNS.
+    pub mdns: bool,
+}
+
+/// How [`Client::request_with`] waits for a response.
+#[derive(Debug, Clone)]
+pub struct RequestOptions {
+    /// How long each attempt waits for the response, at most [`MAX_REQUEST_TIMEOUT`].
+    pub timeout: Duration,
+    /// How many times a failed request is sent again. Error responses are not retried.
+    ///
+    /// A request that timed out or lost its connection may still have been handled by the peer,
+    /// so only idempotent requests may be retried.
+    pub retries: u32,
+    /// The wait before the first retry, doubled for every further one up to
+    /// [`MAX_REQUEST_BACKOFF`].
+    pub backoff: Duration,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            retries: 0,
+            backoff: Duration::from_secs(1),
         }
-        None => identity::Keypair::generate_ed25519(),
+    }
+}
+
+impl From<&Setting> for SwarmConfig {
+    fn from(setting: &Setting) -> Self {
+        Self {
+            network_key: setting.network_key,
+            relay_server: setting.relay_server,
+            mdns: true,
+        }
+    }
+}
+
+pub fn new(id_keys: identity::Keypair, config: SwarmConfig) -> anyhow::Result<Network> {
+    let peer_id = id_keys.public().to_peer_id();
+    let swarm = build_swarm(id_keys, config)?;
+
+    let (command_sender, command_receiver) = mpsc::channel(100);
+    let (event_sender, event_receiver) = mpsc::channel::<InboundEvent>(100);
+    let listeners = Arc::new(Mutex::new(HashMap::new()));
+    let listen_requests = Arc::new(Mutex::new(HashMap::new()));
+    let group_keys = Arc::new(Mutex::new(GroupKeys::default()));
+
+    let network = Network {
+        client: Client {
+            sender: command_sender,
+            local_peer_id: Arc::new(RwLock::new(peer_id)),
+            listeners: listeners.clone(),
+            listen_requests: listen_requests.clone(),
+            pending_new_group: Arc::new(Mutex::new(None)),
+            group_keys: group_keys.clone(),
+        },
+        peer_id,
+        event_loop: EventLoop::new(
+            swarm,
+            command_receiver,
+            event_sender,
+            listeners,
+            listen_requests,
+            group_keys,
+        ),
+        event_receiver,
     };
+
+    Ok(network)
+}
+
+/// Build a swarm around the given identity. Used at startup and whenever the identity or the
+/// swarm config changes.
+fn build_swarm(
+    id_keys: identity::Keypair,
+    config: SwarmConfig,
+) -> anyhow::Result<Swarm<ComposedBehaviour>> {
     let peer_id = id_keys.public().to_peer_id();
     // To content-address message, we can take the hash of message and use it as an ID.
     let message_id_fn = |message: &gossipsub::GossipsubMessage| {
@@ -73,54 +179,140 @@ pub fn new(secret_key_seed: Option<u8>) -> anyhow::Result<Network> {
     )
     .expect("Correct configuration");
 
-    // Create a Request-Response protocol supporting the FileExchange protocol.
+    // Create a Request-Response protocol supporting the FileExchange protocol. Requests time out
+    // in the client, as set for each of them.
+    let mut request_response_config = RequestResponseConfig::default();
+    request_response_config.set_request_timeout(MAX_REQUEST_TIMEOUT);
     let request_response = RequestResponse::new(
-        FileExchangeCodec(),
-        std::iter::once((FileExchangeProtocol(), ProtocolSupport::Full)),
+        FileExchangeCodec::default(),
+        [
+            (FileExchangeProtocol::V2, ProtocolSupport::Full),
+            (FileExchangeProtocol::V1, ProtocolSupport::Full),
+        ],
+        request_response_config,
+    );
+    // File contents are streamed chunk by chunk over a protocol of their own.
+    let file_transfer = RequestResponse::new(
+        FileTransferCodec(),
+        std::iter::once((FileTransferProtocol(), ProtocolSupport::Full)),
+        Default::default(),
+    );
+    // Direct messages go straight to the target peer instead of through a topic.
+    let direct_message = RequestResponse::new(
+        DirectMessageCodec(),
+        std::iter::once((DirectMessageProtocol(), ProtocolSupport::Full)),
         Default::default(),
     );
     // Create a mdns behaviour
-    let mdns = mdns::tokio::Behaviour::new(mdns::Config::default()).unwrap();
+    let mdns = config
+        .mdns
+        .then(|| mdns::tokio::Behaviour::new(mdns::Config::default()))
+        .transpose()?;
+    // Kademlia finds peers beyond the local network. It speaks a protocol of its own so that the
+    // routing table only ever holds nodes of this app.
+    let mut kademlia_config = KademliaConfig::default();
+    kademlia_config.set_protocol_names(vec![Cow::Borrowed(KADEMLIA_PROTOCOL)]);
+    let kademlia = Kademlia::with_config(peer_id, MemoryStore::new(peer_id), kademlia_config);
+    // Nodes behind NAT are reached through a circuit on a relay node, which DCUtR then tries to
+    // upgrade to a direct connection.
+    let (relay_transport, relay_client) =
+        relay_client::Client::new_transport_and_behaviour(peer_id);
+    let relay_server = config
+        .relay_server
+        .then(|| relay::Relay::new(peer_id, Default::default()));
 
     let behaviour = ComposedBehaviour {
-        mdns,
+        mdns: Toggle::from(mdns),
+        kademlia,
+        relay_client,
+        relay_server: Toggle::from(relay_server),
+        dcutr: dcutr::behaviour::Behaviour::new(),
+        identify: identify::Behaviour::new(
+            identify::Config::new(IDENTIFY_PROTOCOL_VERSION.to_string(), id_keys.public())
+                .with_agent_version(format!(
+                    "{}/{}",
+                    env!("CARGO_PKG_NAME"),
+                    env!("CARGO_PKG_VERSION")
+                )),
+        ),
+        ping: ping::Behaviour::new(ping::Config::new()),
         request_response,
+        file_transfer,
+        direct_message,
         gossipsub,
         keep_alive: keep_alive::Behaviour::default(),
     };
     // Build the Swarm, connecting the lower layer transport logic with the
     // higher layer network behaviour logic.
     let swarm = SwarmBuilder::with_tokio_executor(
-        libp2p::tokio_development_transport(id_keys)?,
+        build_transport(&id_keys, relay_transport, config.network_key)?,
         behaviour,
         peer_id,
     )
     .build();
 
-    let (command_sender, command_receiver) = mpsc::channel(100);
-    let (event_sender, event_receiver) = mpsc::channel::<InboundEvent>(100);
+    Ok(swarm)
+}
 
-    let network = Network {
-        client: Client {
-            sender: command_sender,
-            local_peer_id: peer_id,
-            listeners: Arc::new(Mutex::new(HashMap::new())),
-            pending_new_group: Arc::new(Mutex::new(None)),
-        },
-        peer_id,
-        event_loop: EventLoop::new(swarm, command_receiver, event_sender),
-        event_receiver,
+/// TCP, websockets and relay circuits secured with noise and multiplexed with yamux or mplex, as
+/// in the libp2p development transport, next to QUIC. With a network key every connection first
+/// runs the pnet handshake, which nodes without the same key cannot complete.
+///
+/// QUIC secures and multiplexes connections itself and never hands out the byte stream pnet
+/// works on, so private networks go without it. The QUIC of this libp2p version speaks draft-29,
+/// addressed as `/udp/<port>/quic`.
+fn build_transport(
+    id_keys: &identity::Keypair,
+    relay_transport: ClientTransport,
+    network_key: Option<PreSharedKey>,
+) -> io::Result<Boxed<(PeerId, StreamMuxerBox)>> {
+    let dns_tcp = || {
+        dns::TokioDnsConfig::system(tcp::tokio::Transport::new(tcp::Config::new().nodelay(true)))
     };
-
-    Ok(network)
+    let base_transport =
+        relay_transport.or_transport(dns_tcp()?.or_transport(websocket::WsConfig::new(dns_tcp()?)));
+    let transport = match network_key {
+        Some(key) => EitherTransport::Left(
+            base_transport.and_then(move |socket, _| PnetConfig::new(key).handshake(socket)),
+        ),
+        None => EitherTransport::Right(base_transport),
+    };
+    let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
+        .into_authentic(id_keys)
+        .expect("Signing libp2p-noise static DH keypair failed.");
+    let transport = transport
+        .upgrade(Version::V1)
+        .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
+        .multiplex(SelectUpgrade::new(
+            yamux::YamuxConfig::default(),
+            mplex::MplexConfig::default(),
+        ))
+        .timeout(Duration::from_secs(20))
+        .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)));
+    if network_key.is_some() {
+        return Ok(transport.boxed());
+    }
+    let quic_transport = quic::tokio::Transport::new(quic::Config::new(id_keys))
+        .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)));
+    Ok(quic_transport
+        .or_transport(transport)
+        .map(|output, _| match output {
+            EitherOutput::First(output) | EitherOutput::Second(output) => output,
+        })
+        .boxed())
 }
 
 #[derive(Debug, Clone)]
 pub struct Client {
     sender: mpsc::Sender<Command>,
-    local_peer_id: PeerId,
+    local_peer_id: Arc<RwLock<PeerId>>,
     pub listeners: Arc<Mutex<HashMap<ListenerId, Vec<Multiaddr>>>>,
+    /// The address each listener was opened on, before its ports were assigned and its IP was
+    /// resolved to the addresses of the interfaces.
+    pub listen_requests: Arc<Mutex<HashMap<ListenerId, Multiaddr>>>,
     pub pending_new_group: Arc<Mutex<Option<(GroupId, GroupInfo)>>>,
+    /// The keys group messages are sealed with before publishing.
+    pub group_keys: Arc<Mutex<GroupKeys>>,
 }
 
 impl Client {
@@ -146,22 +338,120 @@ impl Client {
             .expect("Command receiver not to be dropped.");
         receiver.await.expect("Sender not to be dropped.")
     }
-    /// Dial the given peer at the given address.
-    pub async fn dial(&self, peer_id: PeerId, addr: Multiaddr) -> Result<(), NetworkError> {
+    /// Dial the given peer, at the given address or else at the addresses found for it in the
+    /// DHT. Resolves once the peer is connected.
+    pub async fn dial(&self, peer_id: PeerId, addr: Option<Multiaddr>) -> Result<(), NetworkError> {
+        self.dial_addresses(peer_id, Vec::from_iter(addr)).await
+    }
+    /// Dial the given peer at any of the given addresses, or through the DHT if there are none.
+    pub async fn dial_addresses(
+        &self,
+        peer_id: PeerId,
+        addresses: Vec<Multiaddr>,
+    ) -> Result<(), NetworkError> {
         let (sender, receiver) = oneshot::channel();
         self.sender
             .send(Command::Dial {
                 peer_id,
-                addr,
+                addresses,
                 sender,
             })
             .await
             .expect("Command receiver not to be dropped.");
         receiver.await.expect("Sender not to be dropped.")
     }
+    /// Dial `peer_id` at the addresses known for it, looking it up in the DHT if there are none
+    /// or none of them works.
+    async fn redial(&self, peer_id: PeerId) -> Result<(), NetworkError> {
+        let addresses = self.addresses_of_peer(peer_id).await;
+        if addresses.is_empty() {
+            return self.dial(peer_id, None).await;
+        }
+        if let Err(e) = self.dial_addresses(peer_id, addresses).await {
+            // the peer may have moved
+            log::debug!("Failed to dial {peer_id} at its known addresses: {e}");
+            return self.dial(peer_id, None).await;
+        }
+        Ok(())
+    }
+    /// The addresses the network knows for `peer_id`, e.g. from the DHT routing table.
+    pub async fn addresses_of_peer(&self, peer_id: PeerId) -> Vec<Multiaddr> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::AddressesOfPeer { peer_id, sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+    /// Remember `addresses` of `peer_id`, e.g. the ones it was last seen at, for later dials.
+    pub async fn add_addresses(&self, peer_id: PeerId, addresses: Vec<Multiaddr>) {
+        self.sender
+            .send(Command::AddAddresses { peer_id, addresses })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Join the DHT through `nodes`, each of which has to end with the `/p2p` id of the node.
+    /// The nodes are kept and joined again whenever the swarm is rebuilt.
+    pub async fn bootstrap(&self, nodes: Vec<Multiaddr>) -> Result<(), NetworkError> {
+        let nodes = nodes
+            .into_iter()
+            .map(|addr| Ok((peer_id_of(&addr)?, addr)))
+            .collect::<Result<Vec<_>, NetworkError>>()?;
+        self.sender
+            .send(Command::Bootstrap { nodes })
+            .await
+            .expect("Command receiver not to be dropped.");
+        Ok(())
+    }
 
-    /// Request the content of the given file from the given peer.
+    /// Send `request` to the given peer with the default [`RequestOptions`]. An error response
+    /// of the peer is returned as the [`NetworkError`] of its kind, such as
+    /// [`NetworkError::NotFound`].
     pub async fn request(&self, peer: PeerId, request: Request) -> Result<Response, NetworkError> {
+        self.request_with(peer, request, RequestOptions::default())
+            .await
+    }
+
+    /// Send `request` to the given peer, sending it again after a failure as often as `options`
+    /// allow. Before every retry the peer is dialed again, at its known addresses or else through
+    /// the DHT. Dropping the returned future cancels the request.
+    ///
+    /// The peer may receive a retried request more than once, so `request` has to be idempotent
+    /// unless `options.retries` is 0.
+    pub async fn request_with(
+        &self,
+        peer: PeerId,
+        request: Request,
+        options: RequestOptions,
+    ) -> Result<Response, NetworkError> {
+        let mut backoff = options.backoff;
+        let mut attempt = 0;
+        loop {
+            match self
+                .try_request(peer, request.clone(), options.timeout)
+                .await
+            {
+                Err(e) if attempt < options.retries && !e.is_error_response() => {
+                    attempt += 1;
+                    log::debug!("Request to {peer} failed, retry {attempt} in {backoff:?}: {e}");
+                    tokio::time::sleep(backoff).await;
+                    backoff = backoff.saturating_mul(2).min(MAX_REQUEST_BACKOFF);
+                    if let Err(e) = self.redial(peer).await {
+                        log::debug!("Failed to dial {peer} again: {e}");
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn try_request(
+        &self,
+        peer: PeerId,
+        request: Request,
+        timeout: Duration,
+    ) -> Result<Response, NetworkError> {
         let (sender, receiver) = oneshot::channel();
         self.sender
             .send(Command::Request {
@@ -171,7 +461,15 @@ impl Client {
             })
             .await
             .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not be dropped.")
+        // dropping the receiver lets the event loop forget the request
+        let response = tokio::time::timeout(timeout, receiver)
+            .await
+            .map_err(|_| NetworkError::RequestTimeout(timeout))?
+            .expect("Sender not to be dropped.")?;
+        match response {
+            Response::Error(error) => Err(error.into()),
+            response => Ok(response),
+        }
     }
 
     /// Respond with the provided file content to the given request.
@@ -182,6 +480,61 @@ impl Client {
             .expect("Command receiver not to be dropped.");
     }
 
+    /// Request a single chunk of a file from the given peer.
+    pub async fn request_chunk(
+        &self,
+        peer: PeerId,
+        request: ChunkRequest,
+    ) -> Result<ChunkResponse, NetworkError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::RequestChunk {
+                peer,
+                request,
+                sender,
+            })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
+    /// Respond to a chunk request.
+    pub async fn response_chunk(
+        &self,
+        response: ChunkResponse,
+        channel: ResponseChannel<ChunkResponse>,
+    ) {
+        self.sender
+            .send(Command::ResponseChunk { response, channel })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Send a direct message to its target, resolving once the target acknowledged it.
+    pub async fn send_direct(
+        &self,
+        message: SealedDirectMessage,
+    ) -> Result<DirectMessageAck, NetworkError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::SendDirect { message, sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
+    /// Acknowledge a received direct message.
+    pub async fn ack_direct(
+        &self,
+        ack: DirectMessageAck,
+        channel: ResponseChannel<DirectMessageAck>,
+    ) {
+        self.sender
+            .send(Command::AckDirect { ack, channel })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
     pub async fn publish(
         &self,
         topic: Sha256Topic,
@@ -220,7 +573,33 @@ impl Client {
         receiver.await.expect("Sender not to be dropped.")
     }
     pub fn local_peer_id(&self) -> PeerId {
-        self.local_peer_id.clone()
+        *self
+            .local_peer_id
+            .read()
+            .expect("Peer id lock not to be poisoned.")
+    }
+    /// Replace the swarm with one built around `keypair` and `network_key`, keeping listeners and
+    /// subscriptions.
+    pub async fn rebuild(
+        &self,
+        keypair: identity::Keypair,
+        config: SwarmConfig,
+    ) -> Result<(), NetworkError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Rebuild {
+                keypair: keypair.clone(),
+                config,
+                sender,
+            })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")?;
+        *self
+            .local_peer_id
+            .write()
+            .expect("Peer id lock not to be poisoned.") = keypair.public().to_peer_id();
+        Ok(())
     }
     pub async fn connected_peers(&self) -> Vec<PeerId> {
         let (sender, receiver) = oneshot::channel();
@@ -245,8 +624,25 @@ pub struct EventLoop {
     swarm: Swarm<ComposedBehaviour>,
     command_receiver: mpsc::Receiver<Command>,
     event_sender: mpsc::Sender<InboundEvent>,
-    pending_dial: HashMap<PeerId, oneshot::Sender<Result<(), NetworkError>>>,
+    listeners: Arc<Mutex<HashMap<ListenerId, Vec<Multiaddr>>>>,
+    listen_requests: Arc<Mutex<HashMap<ListenerId, Multiaddr>>>,
+    group_keys: Arc<Mutex<GroupKeys>>,
+    subscribed_topics: HashMap<TopicHash, Sha256Topic>,
+    bootstrap_nodes: Vec<(PeerId, Multiaddr)>,
+    pending_dial: HashMap<PeerId, Vec<oneshot::Sender<Result<(), NetworkError>>>>,
+    /// DHT lookups of peers dialed without an address.
+    pending_lookup: HashMap<QueryId, PeerId>,
+    /// The open connections to each peer.
+    connections: HashMap<PeerId, Vec<ConnectedPoint>>,
+    /// Peers whose direct connections came out of hole punching.
+    hole_punched: HashSet<PeerId>,
+    /// The connection type of each connected peer, as last reported.
+    connection_types: HashMap<PeerId, ConnectionType>,
+    /// Requests waiting for their response. Each of them ends with its response or an outbound
+    /// failure, including the failure reported when its connection closes.
     pending_request_file: HashMap<RequestId, oneshot::Sender<Result<Response, NetworkError>>>,
+    pending_request_chunk: HashMap<RequestId, oneshot::Sender<Result<ChunkResponse, NetworkError>>>,
+    pending_direct: HashMap<RequestId, oneshot::Sender<Result<DirectMessageAck, NetworkError>>>,
 }
 
 impl EventLoop {
@@ -254,20 +650,242 @@ impl EventLoop {
         swarm: Swarm<ComposedBehaviour>,
         command_receiver: mpsc::Receiver<Command>,
         event_sender: mpsc::Sender<InboundEvent>,
+        listeners: Arc<Mutex<HashMap<ListenerId, Vec<Multiaddr>>>>,
+        listen_requests: Arc<Mutex<HashMap<ListenerId, Multiaddr>>>,
+        group_keys: Arc<Mutex<GroupKeys>>,
     ) -> Self {
         Self {
             swarm,
             command_receiver,
             event_sender,
+            listeners,
+            listen_requests,
+            group_keys,
+            subscribed_topics: Default::default(),
+            bootstrap_nodes: Default::default(),
             pending_dial: Default::default(),
+            pending_lookup: Default::default(),
+            connections: Default::default(),
+            hole_punched: Default::default(),
+            connection_types: Default::default(),
             pending_request_file: Default::default(),
+            pending_request_chunk: Default::default(),
+            pending_direct: Default::default(),
         }
     }
 
+    /// Swap in a new swarm, reopening the current listen ports and topic subscriptions on it.
+    async fn rebuild(
+        &mut self,
+        keypair: identity::Keypair,
+        config: SwarmConfig,
+    ) -> Result<(), NetworkError> {
+        let swarm = build_swarm(keypair, config)?;
+        let old_requests = std::mem::take(&mut *self.listen_requests.lock().await);
+        let old_listeners = self.listeners.lock().await.clone();
+        self.swarm = swarm;
+
+        for sender in self.pending_dial.drain().flat_map(|(_, senders)| senders) {
+            let _ = sender.send(Err(NetworkError::Other(anyhow::anyhow!(
+                "swarm was rebuilt"
+            ))));
+        }
+        self.pending_lookup.clear();
+        self.connections.clear();
+        self.hole_punched.clear();
+        for peer_id in self.connection_types.keys().copied().collect::<Vec<_>>() {
+            self.update_connection_type(peer_id).await;
+        }
+        for (_, sender) in self.pending_request_file.drain() {
+            let _ = sender.send(Err(NetworkError::Other(anyhow::anyhow!(
+                "swarm was rebuilt"
+            ))));
+        }
+        for (_, sender) in self.pending_request_chunk.drain() {
+            let _ = sender.send(Err(NetworkError::Other(anyhow::anyhow!(
+                "swarm was rebuilt"
+            ))));
+        }
+        for (_, sender) in self.pending_direct.drain() {
+            let _ = sender.send(Err(NetworkError::Other(anyhow::anyhow!(
+                "swarm was rebuilt"
+            ))));
+        }
+        for (listener_id, addresses) in old_listeners {
+            self.event_sender
+                .send(InboundEvent::ListenerClosed {
+                    listener_id,
+                    addresses,
+                })
+                .await
+                .expect("Event receiver not to be dropped.");
+        }
+        for (listener_id, addr) in old_requests {
+            // keep the ports the old listener was assigned
+            let reopen = match old_listeners
+                .get(&listener_id)
+                .and_then(|bound| bound.first())
+            {
+                Some(bound) => with_bound_ports(&addr, bound),
+                None => addr.clone(),
+            };
+            match self.swarm.listen_on(reopen) {
+                Ok(listener_id) => {
+                    self.listen_requests.lock().await.insert(listener_id, addr);
+                }
+                Err(e) => log::warn!("Failed to listen on {addr} after rebuild: {e}"),
+            }
+        }
+        self.bootstrap();
+        let local_peer_id = *self.swarm.local_peer_id();
+        for topic in self.subscribed_topics.values() {
+            self.swarm.behaviour_mut().gossipsub.subscribe(topic)?;
+            self.event_sender
+                .send(InboundEvent::Subscribed {
+                    peer_id: local_peer_id,
+                    topic: topic.hash(),
+                })
+                .await
+                .expect("Event receiver not to be dropped.");
+        }
+        Ok(())
+    }
+
+    /// Add the bootstrap nodes to the routing table and look up the local peer through them,
+    /// which fills the routing table with the nodes close to us.
+    fn bootstrap(&mut self) {
+        let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+        for (peer_id, addr) in &self.bootstrap_nodes {
+            kademlia.add_address(peer_id, addr.clone());
+        }
+        if let Err(e) = kademlia.bootstrap() {
+            log::debug!("Skipping DHT bootstrap: {e:?}");
+        }
+    }
+
+    /// Report the connection type of `peer_id` if it changed: direct connections win over
+    /// relayed ones.
+    async fn update_connection_type(&mut self, peer_id: PeerId) {
+        let connections = self
+            .connections
+            .get(&peer_id)
+            .map_or(&[][..], Vec::as_slice);
+        let connection = if connections.iter().any(|endpoint| !is_relayed(endpoint)) {
+            if self.hole_punched.contains(&peer_id) {
+                Some(ConnectionType::HolePunched)
+            } else {
+                Some(ConnectionType::Direct)
+            }
+        } else if connections.is_empty() {
+            None
+        } else {
+            Some(ConnectionType::Relayed)
+        };
+        if connection.is_none() {
+            self.connections.remove(&peer_id);
+        }
+        if connection != Some(ConnectionType::HolePunched) {
+            self.hole_punched.remove(&peer_id);
+        }
+        let previous = match connection {
+            Some(connection) => self.connection_types.insert(peer_id, connection),
+            None => self.connection_types.remove(&peer_id),
+        };
+        if previous != connection {
+            self.event_sender
+                .send(InboundEvent::ConnectionChanged {
+                    peer_id,
+                    connection,
+                })
+                .await
+                .expect("Event receiver not to be dropped.");
+        }
+    }
+
+    /// Settle every dial of `peer_id` waiting for a connection.
+    fn finish_dial(&mut self, peer_id: &PeerId, result: Result<(), NetworkError>) {
+        let Some(senders) = self.pending_dial.remove(peer_id) else {
+            return;
+        };
+        match result {
+            Ok(()) => senders.into_iter().for_each(|sender| {
+                let _ = sender.send(Ok(()));
+            }),
+            Err(e) => {
+                let message = e.to_string();
+                let mut senders = senders.into_iter();
+                if let Some(sender) = senders.next() {
+                    let _ = sender.send(Err(e));
+                }
+                for sender in senders {
+                    let _ = sender.send(Err(anyhow::anyhow!(message.clone()).into()));
+                }
+            }
+        }
+    }
+
+    /// Dial a peer looked up in the DHT, if the lookup found it.
+    fn finish_lookup(&mut self, query_id: QueryId, peers: Vec<PeerId>) {
+        let Some(peer_id) = self.pending_lookup.remove(&query_id) else {
+            return;
+        };
+        if !self.pending_dial.contains_key(&peer_id) {
+            // the lookup itself already connected to the peer
+            return;
+        }
+        if self.swarm.is_connected(&peer_id) {
+            self.finish_dial(&peer_id, Ok(()));
+            return;
+        }
+        if !peers.contains(&peer_id) {
+            self.finish_dial(&peer_id, Err(NetworkError::PeerNotFound(peer_id)));
+            return;
+        }
+        // the addresses found by the lookup are in the routing table now
+        if let Err(e) = self.dial_peer(peer_id, Vec::new()) {
+            self.finish_dial(&peer_id, Err(e.into()));
+        }
+    }
+
+    /// Dial `peer_id` at `addresses` and every other address known for it. The addresses are
+    /// tried one at a time, QUIC ones first, as QUIC connects in fewer round trips and copes
+    /// better with packet loss; TCP is only dialed once every QUIC address failed.
+    fn dial_peer(
+        &mut self,
+        peer_id: PeerId,
+        mut addresses: Vec<Multiaddr>,
+    ) -> Result<(), DialError> {
+        addresses.extend(self.swarm.behaviour_mut().addresses_of_peer(&peer_id));
+        addresses.sort_by_key(|addr| !is_quic(addr));
+        let mut seen = HashSet::new();
+        addresses.retain(|addr| seen.insert(addr.clone()));
+        self.swarm.dial(
+            DialOpts::peer_id(peer_id)
+                .addresses(addresses)
+                // dialing several addresses at once would race TCP against QUIC
+                .override_dial_concurrency_factor(NonZeroU8::new(1).expect("1 to be non-zero."))
+                .build(),
+        )
+    }
+
+    /// Forget the requests whose callers stopped waiting, which would otherwise be kept until
+    /// the peer answers or the request times out.
+    fn prune_pending_requests(&mut self) {
+        self.pending_request_file
+            .retain(|_, sender| !sender.is_closed());
+        self.pending_request_chunk
+            .retain(|_, sender| !sender.is_closed());
+        self.pending_direct.retain(|_, sender| !sender.is_closed());
+    }
+
     pub async fn run(mut self) {
+        let mut bootstrap_interval = tokio::time::interval(BOOTSTRAP_INTERVAL);
+        let mut prune_interval = tokio::time::interval(PRUNE_INTERVAL);
         loop {
             tokio::select! {
                 event = self.swarm.next() => self.handle_event(event.expect("Swarm stream to be infinite.")).await,
+                _ = bootstrap_interval.tick() => self.bootstrap(),
+                _ = prune_interval.tick() => self.prune_pending_requests(),
                 command = self.command_receiver.recv() => match command {
                     Some(c) => self.handle_command(c).await,
                     // Command channel closed, thus shutting down the network event loop.
@@ -284,12 +902,49 @@ impl EventLoop {
         match event {
             SwarmEvent::Behaviour(ComposedEvent::Gossipsub(event)) => match event {
                 GossipsubEvent::Message {
-                    propagation_source: _,
+                    propagation_source,
                     message_id,
                     message,
                 } => {
-                    let group_message =
-                        serde_json::from_slice::<GroupMessage>(&message.data).unwrap();
+                    let source = message.source.unwrap_or(propagation_source);
+                    let opened = self
+                        .group_keys
+                        .lock()
+                        .await
+                        .open(&message.topic, &message.data);
+                    let data = match opened {
+                        Ok(data) => data,
+                        Err(CryptoError::UnknownKey(_)) => {
+                            log::debug!("Message from {source} sealed with an unknown key");
+                            self.event_sender
+                                .send(InboundEvent::MissingGroupKey {
+                                    peer_id: source,
+                                    topic: message.topic,
+                                })
+                                .await
+                                .expect("Event receiver not to be dropped.");
+                            return;
+                        }
+                        Err(e) => {
+                            log::warn!("Dropping message from {source}: {e}");
+                            return;
+                        }
+                    };
+                    let mut group_message = match serde_json::from_slice::<GroupMessage>(&data) {
+                        Ok(group_message) if group_message.source == source => group_message,
+                        Ok(_) => {
+                            log::warn!("Dropping message from {source} claiming another sender");
+                            return;
+                        }
+                        Err(e) => {
+                            log::warn!("Dropping invalid message from {source}: {e}");
+                            return;
+                        }
+                    };
+                    // message ids are content addressed, so every member derives the same id
+                    if group_message.id.is_nil() {
+                        group_message.id = Uuid::new_v5(&Uuid::NAMESPACE_OID, &message_id.0);
+                    }
                     let _ = self
                         .event_sender
                         .send(InboundEvent::Message {
@@ -319,7 +974,7 @@ impl EventLoop {
                 GossipsubEvent::GossipsubNotSupported { .. } => {}
             },
             SwarmEvent::Behaviour(ComposedEvent::RequestResponse(
-                RequestResponseEvent::Message { message, .. },
+                RequestResponseEvent::Message { peer, message },
             )) => match message {
                 RequestResponseMessage::Request {
                     request, channel, ..
@@ -327,6 +982,7 @@ impl EventLoop {
                     let _ = self
                         .event_sender
                         .send(InboundEvent::InboundRequest {
+                            peer_id: peer,
                             request: request.0,
                             channel: Arc::new(Mutex::new(Some(channel))),
                         })
@@ -337,11 +993,10 @@ impl EventLoop {
                     request_id,
                     response,
                 } => {
-                    let _ = self
-                        .pending_request_file
-                        .remove(&request_id)
-                        .expect("Request to still be pending.")
-                        .send(Ok(response.0));
+                    // the request may have been cancelled
+                    if let Some(sender) = self.pending_request_file.remove(&request_id) {
+                        let _ = sender.send(Ok(response.0));
+                    }
                 }
             },
             SwarmEvent::Behaviour(ComposedEvent::RequestResponse(
@@ -349,15 +1004,96 @@ impl EventLoop {
                     request_id, error, ..
                 },
             )) => {
-                let _ = self
-                    .pending_request_file
-                    .remove(&request_id)
-                    .expect("Request to still be pending.")
-                    .send(Err(error.into()));
+                if let Some(sender) = self.pending_request_file.remove(&request_id) {
+                    let _ = sender.send(Err(error.into()));
+                }
             }
             SwarmEvent::Behaviour(ComposedEvent::RequestResponse(
                 RequestResponseEvent::ResponseSent { .. },
             )) => {}
+            SwarmEvent::Behaviour(ComposedEvent::FileTransfer(RequestResponseEvent::Message {
+                peer,
+                message,
+            })) => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    self.event_sender
+                        .send(InboundEvent::ChunkRequest {
+                            peer_id: peer,
+                            request,
+                            channel: Arc::new(Mutex::new(Some(channel))),
+                        })
+                        .await
+                        .expect("Event receiver not to be dropped.");
+                }
+                RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                } => {
+                    if let Some(sender) = self.pending_request_chunk.remove(&request_id) {
+                        let _ = sender.send(Ok(response));
+                    }
+                }
+            },
+            SwarmEvent::Behaviour(ComposedEvent::FileTransfer(
+                RequestResponseEvent::OutboundFailure {
+                    request_id, error, ..
+                },
+            )) => {
+                if let Some(sender) = self.pending_request_chunk.remove(&request_id) {
+                    let _ = sender.send(Err(error.into()));
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::FileTransfer(
+                RequestResponseEvent::InboundFailure { peer, error, .. },
+            )) => {
+                log::warn!("Failed to serve chunk to {peer}: {error}");
+            }
+            SwarmEvent::Behaviour(ComposedEvent::FileTransfer(
+                RequestResponseEvent::ResponseSent { .. },
+            )) => {}
+            SwarmEvent::Behaviour(ComposedEvent::DirectMessage(
+                RequestResponseEvent::Message { peer, message },
+            )) => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    self.event_sender
+                        .send(InboundEvent::DirectMessage {
+                            peer_id: peer,
+                            message: request,
+                            channel: Arc::new(Mutex::new(Some(channel))),
+                        })
+                        .await
+                        .expect("Event receiver not to be dropped.");
+                }
+                RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                } => {
+                    if let Some(sender) = self.pending_direct.remove(&request_id) {
+                        let _ = sender.send(Ok(response));
+                    }
+                }
+            },
+            SwarmEvent::Behaviour(ComposedEvent::DirectMessage(
+                RequestResponseEvent::OutboundFailure {
+                    request_id, error, ..
+                },
+            )) => {
+                if let Some(sender) = self.pending_direct.remove(&request_id) {
+                    let _ = sender.send(Err(error.into()));
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::DirectMessage(
+                RequestResponseEvent::InboundFailure { peer, error, .. },
+            )) => {
+                log::warn!("Failed to acknowledge direct message from {peer}: {error}");
+            }
+            SwarmEvent::Behaviour(ComposedEvent::DirectMessage(
+                RequestResponseEvent::ResponseSent { .. },
+            )) => {}
             SwarmEvent::Behaviour(ComposedEvent::Mdns(event)) => match event {
                 mdns::Event::Discovered(list) => {
                     for (peer_id, addr) in list {
@@ -366,6 +1102,11 @@ impl EventLoop {
                             .behaviour_mut()
                             .gossipsub
                             .add_explicit_peer(&peer_id);
+                        // peers on the local network can point others to them through the DHT
+                        self.swarm
+                            .behaviour_mut()
+                            .kademlia
+                            .add_address(&peer_id, addr);
                         self.event_sender
                             .send(InboundEvent::PeerDiscovered { peer_id })
                             .await
@@ -387,6 +1128,104 @@ impl EventLoop {
                     }
                 }
             },
+            SwarmEvent::Behaviour(ComposedEvent::Identify(event)) => match event {
+                identify::Event::Received { peer_id, info } => {
+                    // other nodes of the app serve the DHT, so their addresses are worth keeping
+                    let kademlia_protocol = String::from_utf8_lossy(KADEMLIA_PROTOCOL);
+                    if info.protocols.iter().any(|p| *p == kademlia_protocol) {
+                        for addr in &info.listen_addrs {
+                            self.swarm
+                                .behaviour_mut()
+                                .kademlia
+                                .add_address(&peer_id, addr.clone());
+                        }
+                    }
+                    // how the peer sees us is how nodes behind the same NAT are reachable, which
+                    // hole punching relies on
+                    self.swarm
+                        .add_external_address(info.observed_addr, AddressScore::Finite(1));
+                    self.event_sender
+                        .send(InboundEvent::PeerIdentified {
+                            peer_id,
+                            info: PeerInfo {
+                                listen_addrs: info.listen_addrs,
+                                protocols: info.protocols,
+                                protocol_version: info.protocol_version,
+                                agent_version: info.agent_version,
+                                rtt: None,
+                            },
+                        })
+                        .await
+                        .expect("Event receiver not to be dropped.");
+                }
+                identify::Event::Error { peer_id, error } => {
+                    log::debug!("Failed to identify {peer_id}: {error}")
+                }
+                _ => {}
+            },
+            SwarmEvent::Behaviour(ComposedEvent::Ping(ping::Event { peer, result })) => {
+                match result {
+                    Ok(ping::Success::Ping { rtt }) => self
+                        .event_sender
+                        .send(InboundEvent::PeerPing { peer_id: peer, rtt })
+                        .await
+                        .expect("Event receiver not to be dropped."),
+                    Ok(ping::Success::Pong) => {}
+                    Err(e) => log::debug!("Failed to ping {peer}: {e}"),
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(event)) => match event {
+                KademliaEvent::OutboundQueryProgressed {
+                    id,
+                    result: QueryResult::GetClosestPeers(result),
+                    step,
+                    ..
+                } => {
+                    if step.last {
+                        let peers = match result {
+                            Ok(GetClosestPeersOk { peers, .. }) => peers,
+                            Err(GetClosestPeersError::Timeout { peers, .. }) => peers,
+                        };
+                        self.finish_lookup(id, peers);
+                    }
+                }
+                KademliaEvent::OutboundQueryProgressed {
+                    result: QueryResult::Bootstrap(Err(e)),
+                    ..
+                } => log::debug!("DHT bootstrap failed: {e:?}"),
+                KademliaEvent::RoutingUpdated {
+                    peer, is_new_peer, ..
+                } if is_new_peer => log::debug!("Added {peer} to the DHT routing table"),
+                _ => {}
+            },
+            SwarmEvent::Behaviour(ComposedEvent::RelayClient(event)) => match event {
+                relay_client::Event::ReservationReqAccepted { relay_peer_id, .. } => {
+                    log::info!("Reserved a slot on relay {relay_peer_id}")
+                }
+                relay_client::Event::ReservationReqFailed {
+                    relay_peer_id,
+                    error,
+                    ..
+                } => log::warn!("Failed to reserve a slot on relay {relay_peer_id}: {error}"),
+                event => log::debug!("{event:?}"),
+            },
+            SwarmEvent::Behaviour(ComposedEvent::RelayServer(event)) => {
+                log::debug!("{event:?}")
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Dcutr(event)) => match event {
+                dcutr::behaviour::Event::DirectConnectionUpgradeSucceeded { remote_peer_id } => {
+                    log::info!("Punched a direct connection to {remote_peer_id}");
+                    self.hole_punched.insert(remote_peer_id);
+                    self.update_connection_type(remote_peer_id).await;
+                }
+                dcutr::behaviour::Event::DirectConnectionUpgradeFailed {
+                    remote_peer_id,
+                    error,
+                } => {
+                    log::debug!("Failed to punch a direct connection to {remote_peer_id}: {error}")
+                }
+                event => log::debug!("{event:?}"),
+            },
             SwarmEvent::NewListenAddr {
                 address,
                 listener_id,
@@ -404,6 +1243,7 @@ impl EventLoop {
                 addresses,
                 reason,
             } => {
+                self.listen_requests.lock().await.remove(&listener_id);
                 self.event_sender
                     .send(InboundEvent::ListenerClosed {
                         listener_id,
@@ -432,19 +1272,38 @@ impl EventLoop {
             }
             SwarmEvent::IncomingConnection { .. } => {}
             SwarmEvent::ConnectionEstablished {
-                peer_id, endpoint, ..
+                peer_id,
+                endpoint,
+                num_established,
+                ..
             } => {
                 if endpoint.is_dialer() {
-                    if let Some(sender) = self.pending_dial.remove(&peer_id) {
-                        let _ = sender.send(Ok(()));
+                    self.finish_dial(&peer_id, Ok(()));
+                }
+                self.connections.entry(peer_id).or_default().push(endpoint);
+                if num_established.get() == 1 {
+                    self.event_sender
+                        .send(InboundEvent::ConnectionEstablished { peer_id })
+                        .await
+                        .expect("Event receiver not to be dropped.");
+                }
+                self.update_connection_type(peer_id).await;
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id, endpoint, ..
+            } => {
+                if let Some(connections) = self.connections.get_mut(&peer_id) {
+                    if let Some(index) = connections.iter().position(|open| *open == endpoint) {
+                        connections.swap_remove(index);
                     }
                 }
+                self.update_connection_type(peer_id).await;
             }
-            SwarmEvent::ConnectionClosed { .. } => {}
             SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
                 if let Some(peer_id) = peer_id {
-                    if let Some(sender) = self.pending_dial.remove(&peer_id) {
-                        let _ = sender.send(Err(error.into()));
+                    // dials made by a lookup of the peer may still succeed through other nodes
+                    if !self.pending_lookup.values().any(|peer| *peer == peer_id) {
+                        self.finish_dial(&peer_id, Err(error.into()));
                     }
                 }
             }
@@ -457,13 +1316,17 @@ impl EventLoop {
     async fn handle_command(&mut self, command: Command) {
         match command {
             Command::StartListen { addr, sender } => {
-                let _ = match self.swarm.listen_on(addr) {
-                    Ok(listener_id) => sender.send(Ok(listener_id)),
+                let _ = match self.swarm.listen_on(addr.clone()) {
+                    Ok(listener_id) => {
+                        self.listen_requests.lock().await.insert(listener_id, addr);
+                        sender.send(Ok(listener_id))
+                    }
                     Err(e) => sender.send(Err(e.into())),
                 };
             }
             Command::StopListen { sender, listeners } => {
                 for listener_id in listeners {
+                    self.listen_requests.lock().await.remove(&listener_id);
                     if !self.swarm.remove_listener(listener_id) {
                         log::warn!("Listener {:?} not found.", listener_id);
                     };
@@ -472,20 +1335,45 @@ impl EventLoop {
             }
             Command::Dial {
                 peer_id,
-                addr,
+                addresses,
                 sender,
-            } => {
-                if let hash_map::Entry::Vacant(e) = self.pending_dial.entry(peer_id) {
-                    match self.swarm.dial(addr) {
-                        Ok(()) => {
-                            e.insert(sender);
-                        }
-                        Err(e) => {
-                            let _ = sender.send(Err(e.into()));
-                        }
+            } => match self.pending_dial.entry(peer_id) {
+                // wait for the dial in progress
+                hash_map::Entry::Occupied(mut e) => e.get_mut().push(sender),
+                hash_map::Entry::Vacant(_) if self.swarm.is_connected(&peer_id) => {
+                    let _ = sender.send(Ok(()));
+                }
+                hash_map::Entry::Vacant(e) if addresses.is_empty() => {
+                    e.insert(vec![sender]);
+                    let query_id = self
+                        .swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .get_closest_peers(peer_id);
+                    self.pending_lookup.insert(query_id, peer_id);
+                }
+                hash_map::Entry::Vacant(_) => match self.dial_peer(peer_id, addresses) {
+                    Ok(()) => {
+                        self.pending_dial.insert(peer_id, vec![sender]);
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(e.into()));
                     }
-                } else {
-                    log::warn!("Already dialing peer {peer_id}");
+                },
+            },
+            Command::Bootstrap { nodes } => {
+                self.bootstrap_nodes = nodes;
+                self.bootstrap();
+            }
+            Command::AddressesOfPeer { peer_id, sender } => {
+                let _ = sender.send(self.swarm.behaviour_mut().addresses_of_peer(&peer_id));
+            }
+            Command::AddAddresses { peer_id, addresses } => {
+                for addr in addresses {
+                    self.swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .add_address(&peer_id, addr);
                 }
             }
             Command::Request {
@@ -501,11 +1389,60 @@ impl EventLoop {
                 self.pending_request_file.insert(request_id, sender);
             }
             Command::Response { response, channel } => {
-                self.swarm
+                if self
+                    .swarm
                     .behaviour_mut()
                     .request_response
                     .send_response(channel, FileResponse(response))
-                    .expect("Connection to peer to be still open.");
+                    .is_err()
+                {
+                    log::warn!("Connection closed before the response could be sent.");
+                }
+            }
+            Command::RequestChunk {
+                peer,
+                request,
+                sender,
+            } => {
+                let request_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .file_transfer
+                    .send_request(&peer, request);
+                self.pending_request_chunk.insert(request_id, sender);
+            }
+            Command::ResponseChunk { response, channel } => {
+                if self
+                    .swarm
+                    .behaviour_mut()
+                    .file_transfer
+                    .send_response(channel, response)
+                    .is_err()
+                {
+                    log::warn!("Connection closed before the chunk could be sent.");
+                }
+            }
+            Command::SendDirect { message, sender } => {
+                let target = message.target;
+                let request_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .direct_message
+                    .send_request(&target, message);
+                self.pending_direct.insert(request_id, sender);
+            }
+            Command::AckDirect { ack, channel } => {
+                if self
+                    .swarm
+                    .behaviour_mut()
+                    .direct_message
+                    .send_response(channel, ack)
+                    .is_err()
+                {
+                    log::warn!(
+                        "Connection closed before the direct message could be acknowledged."
+                    );
+                }
             }
             Command::Publish {
                 topic,
@@ -514,11 +1451,23 @@ impl EventLoop {
             } => {
                 let group_message =
                     GroupMessage::new(message, self.swarm.local_peer_id().to_owned());
+                let sealed = self
+                    .group_keys
+                    .lock()
+                    .await
+                    .seal(&topic.hash(), &serde_json::to_vec(&group_message).unwrap());
+                let sealed = match sealed {
+                    Ok(sealed) => sealed,
+                    Err(e) => {
+                        let _ = sender.send(Err(e.into()));
+                        return;
+                    }
+                };
                 let res = self
                     .swarm
                     .behaviour_mut()
                     .gossipsub
-                    .publish(topic.clone(), serde_json::to_vec(&group_message).unwrap());
+                    .publish(topic.clone(), sealed);
 
                 let res = match res {
                     Ok(message_id) => {
@@ -543,6 +1492,7 @@ impl EventLoop {
                         if !res {
                             log::warn!("Already subscribed to topic {:?}", topic);
                         }
+                        self.subscribed_topics.insert(topic.hash(), topic.clone());
                         let local_peer_id = self.swarm.local_peer_id().clone();
                         self.event_sender
                             .send(InboundEvent::Subscribed {
@@ -564,6 +1514,7 @@ impl EventLoop {
                         if !res {
                             log::warn!("Already unsubscribed from topic {:?}", topic);
                         }
+                        self.subscribed_topics.remove(&topic.hash());
                         let local_peer_id = self.swarm.local_peer_id().clone();
                         self.event_sender
                             .send(InboundEvent::Unsubscribed {
@@ -583,6 +1534,13 @@ impl EventLoop {
                 let peers = self.swarm.connected_peers().cloned().collect();
                 let _ = sender.send(peers);
             }
+            Command::Rebuild {
+                keypair,
+                config,
+                sender,
+            } => {
+                let _ = sender.send(self.rebuild(keypair, config).await);
+            }
         }
     }
 }
@@ -599,9 +1557,17 @@ enum Command {
     },
     Dial {
         peer_id: PeerId,
-        addr: Multiaddr,
+        addresses: Vec<Multiaddr>,
         sender: oneshot::Sender<Result<(), NetworkError>>,
     },
+    AddressesOfPeer {
+        peer_id: PeerId,
+        sender: oneshot::Sender<Vec<Multiaddr>>,
+    },
+    AddAddresses {
+        peer_id: PeerId,
+        addresses: Vec<Multiaddr>,
+    },
     Request {
         peer: PeerId,
         request: Request,
@@ -611,6 +1577,23 @@ enum Command {
         response: Response,
         channel: ResponseChannel<FileResponse>,
     },
+    RequestChunk {
+        peer: PeerId,
+        request: ChunkRequest,
+        sender: oneshot::Sender<Result<ChunkResponse, NetworkError>>,
+    },
+    ResponseChunk {
+        response: ChunkResponse,
+        channel: ResponseChannel<ChunkResponse>,
+    },
+    SendDirect {
+        message: SealedDirectMessage,
+        sender: oneshot::Sender<Result<DirectMessageAck, NetworkError>>,
+    },
+    AckDirect {
+        ack: DirectMessageAck,
+        channel: ResponseChannel<DirectMessageAck>,
+    },
     Publish {
         topic: Sha256Topic,
         message: Message,
@@ -627,4 +1610,258 @@ enum Command {
     ConnectedPeers {
         sender: oneshot::Sender<Vec<PeerId>>,
     },
+    Bootstrap {
+        nodes: Vec<(PeerId, Multiaddr)>,
+    },
+    Rebuild {
+        keypair: identity::Keypair,
+        config: SwarmConfig,
+        sender: oneshot::Sender<Result<(), NetworkError>>,
+    },
+}
+
+/// Whether a connection goes through a relay circuit rather than straight to the peer.
+fn is_relayed(endpoint: &ConnectedPoint) -> bool {
+    let addr = match endpoint {
+        ConnectedPoint::Dialer { address, .. } => address,
+        ConnectedPoint::Listener { local_addr, .. } => local_addr,
+    };
+    addr.iter().any(|protocol| protocol == Protocol::P2pCircuit)
+}
+
+pub fn is_quic(addr: &Multiaddr) -> bool {
+    addr.iter().any(|protocol| protocol == Protocol::Quic)
+}
+
+/// The id of the peer a multiaddr ends with.
+pub fn peer_id_of(addr: &Multiaddr) -> Result<PeerId, NetworkError> {
+    match addr.iter().last() {
+        Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash)
+            .map_err(|_| NetworkError::InvalidAddress(format!("invalid peer ID in {addr}"))),
+        _ => Err(NetworkError::InvalidAddress(
+            "Expect peer multiaddr to contain peer ID.".to_string(),
+        )),
+    }
+}
+
+/// Fill the ports left for the OS to pick in the requested listen address `requested` with the
+/// ones it picked, as seen in `bound`, so that reopening it binds the same ports again.
+pub fn with_bound_ports(requested: &Multiaddr, bound: &Multiaddr) -> Multiaddr {
+    let mut bound = bound.iter();
+    requested
+        .iter()
+        .map(|protocol| match (protocol, bound.next()) {
+            (Protocol::Tcp(0), Some(Protocol::Tcp(port))) => Protocol::Tcp(port),
+            (Protocol::Udp(0), Some(Protocol::Udp(port))) => Protocol::Udp(port),
+            (protocol, _) => protocol,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EVENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Wait for the first event `select` picks a value out of.
+    async fn next_event<T>(
+        events: &mut mpsc::Receiver<InboundEvent>,
+        mut select: impl FnMut(InboundEvent) -> Option<T>,
+    ) -> T {
+        tokio::time::timeout(EVENT_TIMEOUT, async {
+            loop {
+                let event = events.recv().await.expect("Event loop to be running.");
+                if let Some(value) = select(event) {
+                    return value;
+                }
+            }
+        })
+        .await
+        .expect("Event to arrive in time.")
+    }
+
+    /// Keep the event loop of a node going without looking at its events.
+    fn drain(mut events: mpsc::Receiver<InboundEvent>) {
+        tokio::spawn(async move { while events.recv().await.is_some() {} });
+    }
+
+    fn start(config: SwarmConfig) -> (Client, PeerId, mpsc::Receiver<InboundEvent>) {
+        let network = new(identity::Keypair::generate_ed25519(), config).unwrap();
+        tokio::spawn(network.event_loop.run());
+        (network.client, network.peer_id, network.event_receiver)
+    }
+
+    /// A local TCP address nothing listens on yet.
+    fn unused_tcp_addr() -> Multiaddr {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap()
+    }
+
+    /// Start a relay node and return the address of the circuits it relays.
+    async fn start_relay() -> Multiaddr {
+        // the relay hands out reservations on its external addresses, so it has to know its
+        // address before the listener asks for one
+        let relay_addr = unused_tcp_addr();
+        let mut relay = new(
+            identity::Keypair::generate_ed25519(),
+            SwarmConfig {
+                relay_server: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        relay
+            .event_loop
+            .swarm
+            .add_external_address(relay_addr.clone(), AddressScore::Infinite);
+        tokio::spawn(relay.event_loop.run());
+        drain(relay.event_receiver);
+        relay
+            .client
+            .start_listening(relay_addr.clone())
+            .await
+            .unwrap();
+        relay_addr
+            .with(Protocol::P2p(relay.peer_id.into()))
+            .with(Protocol::P2pCircuit)
+    }
+
+    /// Start a node listening on a local address it announces as external, as DCUtR only hands
+    /// out external addresses.
+    async fn start_reachable() -> (Client, PeerId, mpsc::Receiver<InboundEvent>) {
+        let listen_addr = unused_tcp_addr();
+        let mut network = new(
+            identity::Keypair::generate_ed25519(),
+            SwarmConfig::default(),
+        )
+        .unwrap();
+        network
+            .event_loop
+            .swarm
+            .add_external_address(listen_addr.clone(), AddressScore::Infinite);
+        tokio::spawn(network.event_loop.run());
+        network.client.start_listening(listen_addr).await.unwrap();
+        (network.client, network.peer_id, network.event_receiver)
+    }
+
+    /// Make `client` reachable through a reservation on the relay of `circuit_addr`.
+    async fn listen_via_relay(
+        client: &Client,
+        events: &mut mpsc::Receiver<InboundEvent>,
+        circuit_addr: Multiaddr,
+    ) {
+        client.start_listening(circuit_addr).await.unwrap();
+        next_event(events, |event| match event {
+            InboundEvent::NewListenAddr { address, .. }
+                if address
+                    .iter()
+                    .any(|protocol| protocol == Protocol::P2pCircuit) =>
+            {
+                Some(())
+            }
+            _ => None,
+        })
+        .await;
+    }
+
+    /// The connection type reported for `target` once it is not `None`.
+    async fn next_connection(
+        events: &mut mpsc::Receiver<InboundEvent>,
+        target: PeerId,
+    ) -> ConnectionType {
+        next_event(events, |event| match event {
+            InboundEvent::ConnectionChanged {
+                peer_id,
+                connection: Some(connection),
+            } if peer_id == target => Some(connection),
+            _ => None,
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn direct_connection_is_reported_as_direct() {
+        let (listener, listener_peer_id, mut listener_events) = start(SwarmConfig::default());
+        listener
+            .start_listening("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .await
+            .unwrap();
+        let listen_addr = next_event(&mut listener_events, |event| match event {
+            InboundEvent::NewListenAddr { address, .. } => Some(address),
+            _ => None,
+        })
+        .await;
+        drain(listener_events);
+
+        let (dialer, _, mut dialer_events) = start(SwarmConfig::default());
+        dialer
+            .dial(listener_peer_id, Some(listen_addr))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_connection(&mut dialer_events, listener_peer_id).await,
+            ConnectionType::Direct
+        );
+    }
+
+    #[tokio::test]
+    async fn relayed_connection_is_reported_as_relayed() {
+        let circuit_addr = start_relay().await;
+
+        // the listener is only reachable through its reservation on the relay
+        let (listener, listener_peer_id, mut listener_events) = start(SwarmConfig::default());
+        listen_via_relay(&listener, &mut listener_events, circuit_addr.clone()).await;
+        drain(listener_events);
+
+        let (dialer, _, mut dialer_events) = start(SwarmConfig::default());
+        dialer
+            .dial(
+                listener_peer_id,
+                Some(circuit_addr.with(Protocol::P2p(listener_peer_id.into()))),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            next_connection(&mut dialer_events, listener_peer_id).await,
+            ConnectionType::Relayed
+        );
+    }
+
+    #[tokio::test]
+    async fn upgraded_relayed_connection_is_reported_as_hole_punched() {
+        let circuit_addr = start_relay().await;
+
+        // the listener is dialed through the relay, but both nodes listen on addresses that DCUtR
+        // exchanges to upgrade the connection
+        let (listener, listener_peer_id, mut listener_events) = start_reachable().await;
+        listen_via_relay(&listener, &mut listener_events, circuit_addr.clone()).await;
+        drain(listener_events);
+
+        let (dialer, _, mut dialer_events) = start_reachable().await;
+        dialer
+            .dial(
+                listener_peer_id,
+                Some(circuit_addr.with(Protocol::P2p(listener_peer_id.into()))),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            next_connection(&mut dialer_events, listener_peer_id).await,
+            ConnectionType::Relayed
+        );
+        // the direct connection may be reported before DCUtR tells it came from the upgrade
+        next_event(&mut dialer_events, |event| match event {
+            InboundEvent::ConnectionChanged {
+                peer_id,
+                connection: Some(ConnectionType::HolePunched),
+            } if peer_id == listener_peer_id => Some(()),
+            _ => None,
+        })
+        .await;
+    }
 }
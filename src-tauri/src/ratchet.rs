@@ -0,0 +1,747 @@
+use std::{collections::HashMap, fmt, path::PathBuf};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use curve25519_dalek::{
+    constants::X25519_BASEPOINT, edwards::CompressedEdwardsY, montgomery::MontgomeryPoint,
+    scalar::Scalar,
+};
+use hkdf::Hkdf;
+use libp2p::{
+    identity::{self, ed25519},
+    PeerId,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use tokio::{
+    fs,
+    io::{self, AsyncWriteExt},
+};
+
+use crate::{
+    crypto::{self, hex_bytes, hex_key},
+    error::{CryptoError, NetworkError},
+    identity::NodeIdentity,
+};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const HANDSHAKE_INFO: &[u8] = b"direct-message/handshake";
+const ROOT_INFO: &[u8] = b"direct-message/root";
+const CHAIN_INFO: &[u8] = b"direct-message/chain";
+const MESSAGE_INFO: &[u8] = b"direct-message/message";
+/// How many messages of a single chain may be skipped, e.g. because they were lost or arrive
+/// out of order. Bounds the work a forged header can cause.
+const MAX_SKIP: u64 = 1000;
+/// How many keys of skipped messages are kept per peer, the oldest are dropped first.
+const MAX_SKIPPED_KEYS: usize = 2000;
+/// How many superseded sessions are remembered per peer to refuse replays of their handshake.
+const MAX_RETIRED: usize = 64;
+
+/// An X25519 keypair.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeyPair {
+    #[serde(with = "hex_key")]
+    secret: [u8; KEY_LEN],
+    #[serde(with = "hex_key")]
+    public: [u8; KEY_LEN],
+}
+
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("public", &hex::encode(self.public))
+            .finish()
+    }
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let mut secret = [0; KEY_LEN];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Self::from_secret(secret)
+    }
+
+    fn from_secret(secret: [u8; KEY_LEN]) -> Self {
+        let public = (X25519_BASEPOINT * clamp(secret)).to_bytes();
+        Self { secret, public }
+    }
+
+    /// The X25519 counterpart of an ed25519 keypair: ed25519 signs with the same clamped scalar,
+    /// so the public key matches [`public_key`] of the peer id.
+    pub fn from_ed25519(keypair: &ed25519::Keypair) -> Self {
+        let hash = Sha512::digest(keypair.secret().as_ref());
+        let mut secret = [0; KEY_LEN];
+        secret.copy_from_slice(&hash[..KEY_LEN]);
+        Self::from_secret(secret)
+    }
+
+    fn diffie_hellman(&self, public: &[u8; KEY_LEN]) -> Result<[u8; KEY_LEN], CryptoError> {
+        let shared = (MontgomeryPoint(*public) * clamp(self.secret)).to_bytes();
+        // low order points yield an all zero secret whatever our key is
+        if shared == [0; KEY_LEN] {
+            return Err(CryptoError::InvalidKey);
+        }
+        Ok(shared)
+    }
+}
+
+fn clamp(mut secret: [u8; KEY_LEN]) -> Scalar {
+    secret[0] &= 248;
+    secret[31] &= 127;
+    secret[31] |= 64;
+    Scalar::from_bits(secret)
+}
+
+/// The X25519 public key of `peer_id`, converted from the ed25519 key the id is made of.
+pub fn public_key(peer_id: &PeerId) -> Result<[u8; KEY_LEN], CryptoError> {
+    let public = match crypto::identity_key(peer_id)? {
+        identity::PublicKey::Ed25519(public) => public.encode(),
+        _ => return Err(CryptoError::UnsupportedPeer(peer_id.to_string())),
+    };
+    CompressedEdwardsY(public)
+        .decompress()
+        .map(|point| point.to_montgomery().to_bytes())
+        .ok_or(CryptoError::InvalidKey)
+}
+
+/// Sent in the clear along with every direct message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Header {
+    /// The ephemeral key the session was started with, identifying the session.
+    #[serde(with = "hex_key")]
+    session: [u8; KEY_LEN],
+    /// The current ratchet key of the sender.
+    #[serde(with = "hex_key")]
+    ratchet: [u8; KEY_LEN],
+    /// The number of messages in the previous sending chain of the sender.
+    previous: u64,
+    /// The number of the message in the current sending chain of the sender.
+    number: u64,
+}
+
+impl Header {
+    /// The id of the session the message was sealed in.
+    pub fn session(&self) -> [u8; KEY_LEN] {
+        self.session
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        [
+            self.session.as_slice(),
+            self.ratchet.as_slice(),
+            &self.previous.to_be_bytes(),
+            &self.number.to_be_bytes(),
+        ]
+        .concat()
+    }
+}
+
+/// A message sealed by [`Sessions::seal`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Envelope {
+    pub header: Header,
+    #[serde(with = "hex_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Chain {
+    #[serde(with = "hex_key")]
+    key: [u8; KEY_LEN],
+    number: u64,
+}
+
+impl Chain {
+    fn new(key: [u8; KEY_LEN]) -> Self {
+        Self { key, number: 0 }
+    }
+
+    /// Advance the chain, returning the key of the current message. The chain key is replaced so
+    /// that the message key cannot be derived again.
+    fn advance(&mut self) -> [u8; KEY_LEN] {
+        let mut okm = [0; 2 * KEY_LEN];
+        Hkdf::<Sha256>::new(None, &self.key)
+            .expand(CHAIN_INFO, &mut okm)
+            .expect("Chain keys to fit HKDF-SHA256 output.");
+        let (key, message_key) = split(okm);
+        self.key = key;
+        self.number += 1;
+        message_key
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SkippedKey {
+    #[serde(with = "hex_key")]
+    ratchet: [u8; KEY_LEN],
+    number: u64,
+    #[serde(with = "hex_key")]
+    key: [u8; KEY_LEN],
+}
+
+/// A double ratchet session with a single peer. Every message is sealed with its own key and the
+/// keys are forgotten once used, while the Diffie-Hellman ratchet mixes fresh key material into
+/// the chains whenever the direction of the conversation changes. A leaked session state thus
+/// neither decrypts earlier messages nor, once both peers have sent again, later ones.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Session {
+    #[serde(with = "hex_key")]
+    id: [u8; KEY_LEN],
+    /// Whether a message of the peer has been opened in this session yet.
+    confirmed: bool,
+    #[serde(with = "hex_key")]
+    root: [u8; KEY_LEN],
+    ratchet: KeyPair,
+    #[serde(default, with = "hex_key::option")]
+    remote_ratchet: Option<[u8; KEY_LEN]>,
+    sending: Option<Chain>,
+    receiving: Option<Chain>,
+    /// The length of the previous sending chain, sent along so that the peer can keep the keys of
+    /// messages of that chain it has not received yet.
+    previous: u64,
+    skipped: Vec<SkippedKey>,
+}
+
+impl Session {
+    /// Start a session with a peer, agreeing on a secret from both identity keys and an
+    /// ephemeral key. Only the owner of `remote` can derive it, and only we could have sent it.
+    ///
+    /// The peer contributes no ephemeral key of its own until it replies, so the messages sent
+    /// before that are not forward secret: they can be opened by whoever later gets hold of the
+    /// identity key of the peer. Closing that gap needs a signed prekey published by every node
+    /// ahead of time, as in X3DH, which there is no place for on the network yet.
+    fn initiate(local: &KeyPair, remote: &[u8; KEY_LEN]) -> Result<Self, CryptoError> {
+        let ephemeral = KeyPair::generate();
+        let secret = handshake_secret(
+            &local.diffie_hellman(remote)?,
+            &ephemeral.diffie_hellman(remote)?,
+            &local.public,
+            remote,
+        );
+        let ratchet = KeyPair::generate();
+        let (root, sending) = kdf_root(&secret, &ratchet.diffie_hellman(remote)?);
+        Ok(Self {
+            id: ephemeral.public,
+            confirmed: false,
+            root,
+            ratchet,
+            remote_ratchet: Some(*remote),
+            sending: Some(Chain::new(sending)),
+            receiving: None,
+            previous: 0,
+            skipped: Vec::new(),
+        })
+    }
+
+    /// The counterpart of [`Session::initiate`], for a session started by `remote` with the
+    /// ephemeral key `id`. The local identity key serves as the first ratchet key, it is
+    /// replaced as soon as the first message is opened. Until then the session is only as safe
+    /// as the local identity key, see [`Session::initiate`].
+    fn respond(
+        local: &KeyPair,
+        remote: &[u8; KEY_LEN],
+        id: [u8; KEY_LEN],
+    ) -> Result<Self, CryptoError> {
+        let root = handshake_secret(
+            &local.diffie_hellman(remote)?,
+            &local.diffie_hellman(&id)?,
+            remote,
+            &local.public,
+        );
+        Ok(Self {
+            id,
+            confirmed: false,
+            root,
+            ratchet: local.clone(),
+            remote_ratchet: None,
+            sending: None,
+            receiving: None,
+            previous: 0,
+            skipped: Vec::new(),
+        })
+    }
+
+    fn seal(&mut self, plaintext: &[u8], aad: &[u8]) -> Result<Envelope, CryptoError> {
+        // responders send once they have opened the first message, which sets up the chain
+        let chain = self.sending.as_mut().ok_or(CryptoError::Encryption)?;
+        let header = Header {
+            session: self.id,
+            ratchet: self.ratchet.public,
+            previous: self.previous,
+            number: chain.number,
+        };
+        let (cipher, nonce) = message_cipher(&chain.advance());
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: &[aad, &header.to_bytes()].concat(),
+                },
+            )
+            .map_err(|_| CryptoError::Encryption)?;
+        Ok(Envelope { header, ciphertext })
+    }
+
+    fn open(&mut self, envelope: &Envelope, aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let header = &envelope.header;
+        let key = match self
+            .skipped
+            .iter()
+            .position(|key| key.ratchet == header.ratchet && key.number == header.number)
+        {
+            Some(position) => self.skipped.remove(position).key,
+            None => {
+                if self.remote_ratchet != Some(header.ratchet) {
+                    self.skip(header.previous)?;
+                    self.step(header.ratchet)?;
+                }
+                self.skip(header.number)?;
+                self.receiving
+                    .as_mut()
+                    .expect("Receiving chain to be set up by the ratchet step.")
+                    .advance()
+            }
+        };
+        let (cipher, nonce) = message_cipher(&key);
+        let plaintext = cipher
+            .decrypt(
+                &nonce,
+                Payload {
+                    msg: &envelope.ciphertext,
+                    aad: &[aad, &header.to_bytes()].concat(),
+                },
+            )
+            .map_err(|_| CryptoError::Decryption)?;
+        self.confirmed = true;
+        Ok(plaintext)
+    }
+
+    /// Keep the keys of the messages of the receiving chain up to `until`, so that they can still
+    /// be opened when they arrive late.
+    fn skip(&mut self, until: u64) -> Result<(), CryptoError> {
+        let (Some(chain), Some(ratchet)) = (self.receiving.as_mut(), self.remote_ratchet) else {
+            return Ok(());
+        };
+        if until > chain.number.saturating_add(MAX_SKIP) {
+            return Err(CryptoError::TooManySkipped);
+        }
+        while chain.number < until {
+            let number = chain.number;
+            let key = chain.advance();
+            self.skipped.push(SkippedKey {
+                ratchet,
+                number,
+                key,
+            });
+        }
+        let excess = self.skipped.len().saturating_sub(MAX_SKIPPED_KEYS);
+        self.skipped.drain(..excess);
+        Ok(())
+    }
+
+    /// Move to the new ratchet key of the peer and answer it with a fresh one of our own.
+    fn step(&mut self, remote: [u8; KEY_LEN]) -> Result<(), CryptoError> {
+        let (root, receiving) = kdf_root(&self.root, &self.ratchet.diffie_hellman(&remote)?);
+        let ratchet = KeyPair::generate();
+        let (root, sending) = kdf_root(&root, &ratchet.diffie_hellman(&remote)?);
+        self.previous = self.sending.as_ref().map_or(0, |chain| chain.number);
+        self.remote_ratchet = Some(remote);
+        self.ratchet = ratchet;
+        self.root = root;
+        self.receiving = Some(Chain::new(receiving));
+        self.sending = Some(Chain::new(sending));
+        Ok(())
+    }
+}
+
+fn handshake_secret(
+    identities: &[u8; KEY_LEN],
+    ephemeral: &[u8; KEY_LEN],
+    initiator: &[u8; KEY_LEN],
+    responder: &[u8; KEY_LEN],
+) -> [u8; KEY_LEN] {
+    let mut secret = [0; KEY_LEN];
+    Hkdf::<Sha256>::new(
+        Some(&[initiator.as_slice(), responder.as_slice()].concat()),
+        &[identities.as_slice(), ephemeral.as_slice()].concat(),
+    )
+    .expand(HANDSHAKE_INFO, &mut secret)
+    .expect("Key to fit HKDF-SHA256 output.");
+    secret
+}
+
+fn kdf_root(root: &[u8; KEY_LEN], shared: &[u8; KEY_LEN]) -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+    let mut okm = [0; 2 * KEY_LEN];
+    Hkdf::<Sha256>::new(Some(root), shared)
+        .expand(ROOT_INFO, &mut okm)
+        .expect("Root and chain keys to fit HKDF-SHA256 output.");
+    split(okm)
+}
+
+/// Message keys are used once, so the nonce is derived along with the cipher key.
+fn message_cipher(key: &[u8; KEY_LEN]) -> (ChaCha20Poly1305, Nonce) {
+    let mut okm = [0; KEY_LEN + NONCE_LEN];
+    Hkdf::<Sha256>::new(None, key)
+        .expand(MESSAGE_INFO, &mut okm)
+        .expect("Cipher key and nonce to fit HKDF-SHA256 output.");
+    let (key, nonce) = okm.split_at(KEY_LEN);
+    (
+        ChaCha20Poly1305::new(Key::from_slice(key)),
+        Nonce::clone_from_slice(nonce),
+    )
+}
+
+fn split(okm: [u8; 2 * KEY_LEN]) -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+    let (first, second) = okm.split_at(KEY_LEN);
+    (
+        first.try_into().expect("First half to be a key."),
+        second.try_into().expect("Second half to be a key."),
+    )
+}
+
+/// The session with a single peer, as stored on disk.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PeerSessions {
+    current: Option<Session>,
+    /// Ids of sessions replaced by a newer one, so that their handshake cannot be replayed to
+    /// reset the session.
+    #[serde(default)]
+    retired: Vec<[u8; KEY_LEN]>,
+}
+
+impl PeerSessions {
+    fn retire(&mut self, id: [u8; KEY_LEN]) {
+        self.retired.push(id);
+        let excess = self.retired.len().saturating_sub(MAX_RETIRED);
+        self.retired.drain(..excess);
+    }
+}
+
+/// The double ratchet sessions of the local node with every peer it exchanged direct messages
+/// with. The state of each peer is saved after every message to a file next to the node
+/// identity, so that sessions survive restarts but old message keys are gone for good.
+#[derive(Default)]
+pub struct Sessions {
+    /// The identity the loaded sessions belong to.
+    local: Option<PeerId>,
+    peers: HashMap<PeerId, PeerSessions>,
+}
+
+impl fmt::Debug for Sessions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sessions")
+            .field("local", &self.local)
+            .field("peers", &self.peers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Sessions {
+    /// Encrypt `plaintext` for `peer_id`, starting a session with it if there is none yet.
+    /// `aad` is authenticated along with the message.
+    pub async fn seal(
+        &mut self,
+        identity: &NodeIdentity,
+        peer_id: &PeerId,
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<Envelope, NetworkError> {
+        let local = identity.agreement_key();
+        let peer = self.load(identity, peer_id).await?;
+        if peer.current.is_none() {
+            peer.current = Some(Session::initiate(&local, &public_key(peer_id)?)?);
+        }
+        let envelope = peer
+            .current
+            .as_mut()
+            .expect("Session to be started.")
+            .seal(plaintext, aad)?;
+        self.save(identity, peer_id).await?;
+        Ok(envelope)
+    }
+
+    /// Drop the session `session` with `peer_id` after the peer could not open a message sealed
+    /// in it, e.g. because it lost its state or kept a session it started at the same time, so
+    /// that the next message starts a new one. Nothing happens if the current session is another
+    /// one by now, e.g. the one the peer started at the same time and we switched to since.
+    pub async fn reset(
+        &mut self,
+        identity: &NodeIdentity,
+        peer_id: &PeerId,
+        session: &[u8; KEY_LEN],
+    ) -> io::Result<()> {
+        let peer = self.load(identity, peer_id).await?;
+        if peer
+            .current
+            .as_ref()
+            .map_or(true, |current| &current.id != session)
+        {
+            return Ok(());
+        }
+        if let Some(current) = peer.current.take() {
+            peer.retire(current.id);
+        }
+        self.save(identity, peer_id).await
+    }
+
+    /// Decrypt a message sealed by `peer_id`. The session state is only updated when the message
+    /// is authentic.
+    pub async fn open(
+        &mut self,
+        identity: &NodeIdentity,
+        peer_id: &PeerId,
+        envelope: &Envelope,
+        aad: &[u8],
+    ) -> Result<Vec<u8>, NetworkError> {
+        let local = identity.agreement_key();
+        let local_peer_id = identity.peer_id();
+        let peer = self.load(identity, peer_id).await?;
+        let id = envelope.header.session;
+        let mut session = match &peer.current {
+            Some(current) if current.id == id => current.clone(),
+            _ if peer.retired.contains(&id) => return Err(CryptoError::StaleSession.into()),
+            _ => Session::respond(&local, &public_key(peer_id)?, id)?,
+        };
+        let plaintext = session.open(envelope, aad)?;
+        match peer.current.replace(session) {
+            Some(current) if current.id != id => {
+                // both peers started a session at once, the one of the lower peer id is kept
+                // and the other one refused from now on, also once ours is confirmed
+                if !current.confirmed && local_peer_id < *peer_id {
+                    peer.current = Some(current);
+                    peer.retire(id);
+                    self.save(identity, peer_id).await?;
+                    return Err(CryptoError::StaleSession.into());
+                }
+                // otherwise the peer started over, e.g. after losing its state
+                peer.retire(current.id);
+            }
+            _ => {}
+        }
+        self.save(identity, peer_id).await?;
+        Ok(plaintext)
+    }
+
+    async fn load(
+        &mut self,
+        identity: &NodeIdentity,
+        peer_id: &PeerId,
+    ) -> io::Result<&mut PeerSessions> {
+        if self.local != Some(identity.peer_id()) {
+            self.local = Some(identity.peer_id());
+            self.peers.clear();
+        }
+        if !self.peers.contains_key(peer_id) {
+            let peer = match fs::read(session_path(identity, peer_id)).await {
+                Ok(data) => serde_json::from_slice(&data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => PeerSessions::default(),
+                Err(e) => return Err(e),
+            };
+            self.peers.insert(*peer_id, peer);
+        }
+        Ok(self
+            .peers
+            .get_mut(peer_id)
+            .expect("Sessions of the peer to be loaded."))
+    }
+
+    /// Write the sessions of `peer_id` to a temporary file first, so that a crash never leaves
+    /// a torn state behind.
+    async fn save(&self, identity: &NodeIdentity, peer_id: &PeerId) -> io::Result<()> {
+        let Some(peer) = self.peers.get(peer_id) else {
+            return Ok(());
+        };
+        let path = session_path(identity, peer_id);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let temp_path = path.with_extension("json.tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&temp_path).await?;
+        file.write_all(&serde_json::to_vec(peer)?).await?;
+        file.sync_all().await?;
+        fs::rename(temp_path, path).await
+    }
+}
+
+fn session_path(identity: &NodeIdentity, peer_id: &PeerId) -> PathBuf {
+    identity.sessions_dir().join(format!("{peer_id}.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AAD: &[u8] = b"aad";
+
+    /// A session started by a fresh initiator and the responder's half of it, which is only set
+    /// up once the responder opens the first message.
+    fn start() -> (Session, KeyPair, KeyPair) {
+        let initiator = KeyPair::generate();
+        let responder = KeyPair::generate();
+        let session = Session::initiate(&initiator, &responder.public).unwrap();
+        (session, initiator, responder)
+    }
+
+    #[test]
+    fn messages_open_in_any_order_but_only_once() {
+        let (mut alice, alice_key, bob_key) = start();
+        let sealed = (0..3)
+            .map(|i| alice.seal(format!("message {i}").as_bytes(), AAD).unwrap())
+            .collect::<Vec<_>>();
+        let mut bob = Session::respond(&bob_key, &alice_key.public, alice.id).unwrap();
+
+        assert_eq!(bob.open(&sealed[2], AAD).unwrap(), b"message 2");
+        assert_eq!(bob.open(&sealed[0], AAD).unwrap(), b"message 0");
+        assert_eq!(bob.open(&sealed[1], AAD).unwrap(), b"message 1");
+        // message keys are forgotten once used
+        assert!(bob.open(&sealed[1], AAD).is_err());
+        assert!(bob.skipped.is_empty());
+
+        // the reply moves both sides to fresh ratchet keys
+        let reply = bob.seal(b"reply", AAD).unwrap();
+        assert_ne!(reply.header.ratchet, bob_key.public);
+        assert_eq!(alice.open(&reply, AAD).unwrap(), b"reply");
+        let next = alice.seal(b"next", AAD).unwrap();
+        assert_eq!(bob.open(&next, AAD).unwrap(), b"next");
+    }
+
+    #[test]
+    fn tampered_messages_are_refused() {
+        let (mut alice, alice_key, bob_key) = start();
+        let mut bob = Session::respond(&bob_key, &alice_key.public, alice.id).unwrap();
+        let mut sealed = alice.seal(b"message", AAD).unwrap();
+        assert!(bob.clone().open(&sealed, b"other aad").is_err());
+        sealed.ciphertext[0] ^= 1;
+        assert!(bob.open(&sealed, AAD).is_err());
+    }
+
+    #[test]
+    fn skipping_is_bounded() {
+        let (mut alice, alice_key, bob_key) = start();
+        let sealed = (0..=MAX_SKIP + 1)
+            .map(|_| alice.seal(b"message", AAD).unwrap())
+            .collect::<Vec<_>>();
+        let bob = Session::respond(&bob_key, &alice_key.public, alice.id).unwrap();
+
+        let mut within = bob.clone();
+        assert!(within.open(&sealed[MAX_SKIP as usize], AAD).is_ok());
+        assert_eq!(within.skipped.len() as u64, MAX_SKIP);
+
+        let mut beyond = bob;
+        assert!(matches!(
+            beyond.open(&sealed[MAX_SKIP as usize + 1], AAD),
+            Err(CryptoError::TooManySkipped)
+        ));
+    }
+
+    async fn identity() -> NodeIdentity {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        NodeIdentity::load_or_generate(dir).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn simultaneous_initiation_settles_on_the_lower_peer_id() {
+        let (mut first, mut second) = (identity().await, identity().await);
+        if first.peer_id() > second.peer_id() {
+            std::mem::swap(&mut first, &mut second);
+        }
+        let (low, high) = (first.peer_id(), second.peer_id());
+        let (mut low_sessions, mut high_sessions) = (Sessions::default(), Sessions::default());
+
+        let from_low = low_sessions.seal(&first, &high, b"low", AAD).await.unwrap();
+        let from_high = high_sessions
+            .seal(&second, &low, b"high", AAD)
+            .await
+            .unwrap();
+        // the lower peer keeps its own session and refuses the other one
+        assert!(matches!(
+            low_sessions.open(&first, &high, &from_high, AAD).await,
+            Err(NetworkError::CryptoError(CryptoError::StaleSession))
+        ));
+        assert_eq!(
+            high_sessions
+                .open(&second, &low, &from_low, AAD)
+                .await
+                .unwrap(),
+            b"low"
+        );
+        // the rejection of the message sealed in the dropped session leaves the kept one alone
+        high_sessions
+            .reset(&second, &low, &from_high.header.session())
+            .await
+            .unwrap();
+
+        let again = high_sessions
+            .seal(&second, &low, b"high again", AAD)
+            .await
+            .unwrap();
+        assert_eq!(again.header.session(), from_low.header.session());
+        assert_eq!(
+            low_sessions.open(&first, &high, &again, AAD).await.unwrap(),
+            b"high again"
+        );
+        let reply = low_sessions
+            .seal(&first, &high, b"low again", AAD)
+            .await
+            .unwrap();
+        assert_eq!(
+            high_sessions
+                .open(&second, &low, &reply, AAD)
+                .await
+                .unwrap(),
+            b"low again"
+        );
+    }
+
+    #[tokio::test]
+    async fn reset_starts_a_new_session() {
+        let (alice, bob) = (identity().await, identity().await);
+        let (mut alice_sessions, mut bob_sessions) = (Sessions::default(), Sessions::default());
+        let first = alice_sessions
+            .seal(&alice, &bob.peer_id(), b"first", AAD)
+            .await
+            .unwrap();
+        bob_sessions
+            .open(&bob, &alice.peer_id(), &first, AAD)
+            .await
+            .unwrap();
+
+        alice_sessions
+            .reset(&alice, &bob.peer_id(), &first.header.session())
+            .await
+            .unwrap();
+        let second = alice_sessions
+            .seal(&alice, &bob.peer_id(), b"second", AAD)
+            .await
+            .unwrap();
+        assert_ne!(second.header.session(), first.header.session());
+        assert_eq!(
+            bob_sessions
+                .open(&bob, &alice.peer_id(), &second, AAD)
+                .await
+                .unwrap(),
+            b"second"
+        );
+        // the retired session cannot be brought back
+        assert!(bob_sessions
+            .open(&bob, &alice.peer_id(), &first, AAD)
+            .await
+            .is_err());
+    }
+}
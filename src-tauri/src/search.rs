@@ -0,0 +1,264 @@
+use std::collections::{BTreeMap, HashMap};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    models::{GroupId, GroupMessage},
+    network::message::Message,
+};
+
+// BM25 parameters
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+/// Terms that only share a prefix with a query term count for this fraction of a full match.
+const PREFIX_WEIGHT: f32 = 0.5;
+const SNIPPET_RADIUS: usize = 40;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchQuery {
+    pub query: String,
+    #[serde(default)]
+    pub group_id: Option<GroupId>,
+    #[serde(default)]
+    pub sender: Option<PeerId>,
+    /// Only match messages sent at or after this timestamp.
+    #[serde(default)]
+    pub since: Option<i64>,
+    /// Only match messages sent at or before this timestamp.
+    #[serde(default)]
+    pub until: Option<i64>,
+    #[serde(default = "SearchQuery::default_limit")]
+    pub limit: usize,
+}
+
+impl SearchQuery {
+    fn default_limit() -> usize {
+        20
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchHit {
+    pub group_id: GroupId,
+    pub message: GroupMessage,
+    pub score: f32,
+    /// The matched text with some characters of surrounding context.
+    pub snippet: String,
+    /// The messages right before and after the hit in the same group.
+    pub context_before: Vec<GroupMessage>,
+    pub context_after: Vec<GroupMessage>,
+}
+
+type DocKey = (GroupId, Uuid);
+
+#[derive(Debug, Clone)]
+struct IndexedDoc {
+    source: PeerId,
+    timestamp: i64,
+    length: usize,
+}
+
+/// An in-memory inverted index over text bodies, file names and senders of group messages.
+#[derive(Debug, Clone, Default)]
+pub struct SearchIndex {
+    postings: BTreeMap<String, HashMap<DocKey, u32>>,
+    docs: HashMap<DocKey, IndexedDoc>,
+    total_length: usize,
+}
+
+impl SearchIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, group_id: &GroupId, message: &GroupMessage) {
+        let key = (group_id.clone(), message.id);
+        if self.docs.contains_key(&key) {
+            return;
+        }
+        let mut terms = tokenize(&indexed_text(&message.message));
+        terms.push(message.source.to_string().to_lowercase());
+
+        for term in &terms {
+            *self
+                .postings
+                .entry(term.clone())
+                .or_default()
+                .entry(key.clone())
+                .or_default() += 1;
+        }
+        self.total_length += terms.len();
+        self.docs.insert(
+            key,
+            IndexedDoc {
+                source: message.source,
+                timestamp: message.timestamp,
+                length: terms.len(),
+            },
+        );
+    }
+
+    pub fn remove_group(&mut self, group_id: &GroupId) {
+        self.docs.retain(|(doc_group, _), doc| {
+            let keep = doc_group != group_id;
+            if !keep {
+                self.total_length -= doc.length;
+            }
+            keep
+        });
+        self.postings.retain(|_, docs| {
+            docs.retain(|(doc_group, _), _| doc_group != group_id);
+            !docs.is_empty()
+        });
+    }
+
+    /// Rank the messages matching any term of `query`, best first.
+    pub fn search(&self, query: &SearchQuery) -> Vec<(DocKey, f32)> {
+        let terms = tokenize(&query.query);
+        if terms.is_empty() || self.docs.is_empty() {
+            return Vec::new();
+        }
+        let average_length = self.total_length as f32 / self.docs.len() as f32;
+        let mut scores = HashMap::<&DocKey, f32>::new();
+
+        for term in &terms {
+            for (indexed_term, docs) in self
+                .postings
+                .range(term.clone()..)
+                .take_while(|(indexed_term, _)| indexed_term.starts_with(term.as_str()))
+            {
+                let weight = if indexed_term == term {
+                    1.0
+                } else {
+                    PREFIX_WEIGHT
+                };
+                let idf = (1.0
+                    + (self.docs.len() as f32 - docs.len() as f32 + 0.5)
+                        / (docs.len() as f32 + 0.5))
+                    .ln();
+                for (key, frequency) in docs {
+                    let doc = &self.docs[key];
+                    if !Self::matches_filters(key, doc, query) {
+                        continue;
+                    }
+                    let frequency = *frequency as f32;
+                    let norm = K1 * (1.0 - B + B * doc.length as f32 / average_length);
+                    *scores.entry(key).or_default() +=
+                        weight * idf * frequency * (K1 + 1.0) / (frequency + norm);
+                }
+            }
+        }
+
+        let mut hits = scores
+            .into_iter()
+            .map(|(key, score)| (key.clone(), score))
+            .collect::<Vec<_>>();
+        // ties go to the more recent message
+        hits.sort_by(|(a_key, a_score), (b_key, b_score)| {
+            b_score
+                .total_cmp(a_score)
+                .then_with(|| self.docs[b_key].timestamp.cmp(&self.docs[a_key].timestamp))
+        });
+        hits.truncate(query.limit);
+        hits
+    }
+
+    fn matches_filters(key: &DocKey, doc: &IndexedDoc, query: &SearchQuery) -> bool {
+        query
+            .group_id
+            .as_ref()
+            .map_or(true, |group_id| &key.0 == group_id)
+            && query.sender.map_or(true, |sender| doc.source == sender)
+            && query.since.map_or(true, |since| doc.timestamp >= since)
+            && query.until.map_or(true, |until| doc.timestamp <= until)
+    }
+}
+
+fn indexed_text(message: &Message) -> String {
+    match message {
+        Message::Text(text) => text.clone(),
+        Message::File(file) => file.name.clone(),
+    }
+}
+
+/// CJK text has no spaces between words, so every ideograph is indexed as its own term.
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}'
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{ac00}'..='\u{d7af}'
+        | '\u{f900}'..='\u{faff}')
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    let mut current = String::new();
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if is_cjk(c) {
+            if !current.is_empty() {
+                terms.push(std::mem::take(&mut current));
+            }
+            terms.push(c.to_string());
+        } else if c.is_alphanumeric() {
+            current.push(c);
+        } else if !current.is_empty() {
+            terms.push(std::mem::take(&mut current));
+        }
+    }
+    if !current.is_empty() {
+        terms.push(current);
+    }
+    terms
+}
+
+/// Cut the part of the message around the first occurrence of a query term.
+pub fn snippet(message: &Message, query: &str) -> String {
+    let text = indexed_text(message);
+    // lowercase char by char, remembering where in `text` each lowercased char came from, as
+    // lowercasing may change the length of a char
+    let mut haystack = String::new();
+    let mut origins = Vec::new();
+    for (index, c) in text.char_indices() {
+        for lower in c.to_lowercase() {
+            origins.push((haystack.len(), index));
+            haystack.push(lower);
+        }
+    }
+    let Some(position) = tokenize(query)
+        .iter()
+        .filter_map(|term| haystack.find(term.as_str()))
+        .min()
+    else {
+        return text.chars().take(SNIPPET_RADIUS * 2).collect();
+    };
+    let position = origins
+        .iter()
+        .rev()
+        .find(|(offset, _)| *offset <= position)
+        .map_or(0, |(_, index)| *index);
+
+    let start = text[..position]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_RADIUS - 1)
+        .map_or(0, |(index, _)| index);
+    let end = text[position..]
+        .char_indices()
+        .nth(SNIPPET_RADIUS * 2)
+        .map_or(text.len(), |(index, _)| position + index);
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    snippet.push_str(&text[start..end]);
+    if end < text.len() {
+        snippet.push('…');
+    }
+    snippet
+}
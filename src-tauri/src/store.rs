@@ -1,8 +1,555 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Bound,
+    path::Path,
+};
+
+use libp2p::PeerId;
+use uuid::Uuid;
+
+use crate::{
+    crypto::{GroupKey, GroupMembers},
+    error::StoreError,
+    models::{
+        Conversation, DirectMessage, GroupId, GroupInfo, GroupMessage, HistoryCursor, HistoryPage,
+        KnownPeer,
+    },
+};
+
 pub trait MessageStore {
     type Message;
     type Error;
 
-    fn get(&self, id: &str) -> Result<Option<Self::Message>, Self::Error>;
-    fn put(&mut self, id: &str, message: Self::Message) -> Result<(), Self::Error>;
-    fn remove(&mut self, id: &str) -> Result<Option<Self::Message>, Self::Error>;
+    fn get(&self, group_id: &GroupId, id: &Uuid) -> Result<Option<Self::Message>, Self::Error>;
+    fn put(&mut self, group_id: &GroupId, message: Self::Message) -> Result<bool, Self::Error>;
+    fn remove(
+        &mut self,
+        group_id: &GroupId,
+        id: &Uuid,
+    ) -> Result<Option<Self::Message>, Self::Error>;
+    fn history(&self, group_id: &GroupId) -> Result<Vec<Self::Message>, Self::Error>;
+    fn page(
+        &self,
+        group_id: &GroupId,
+        cursor: Option<&HistoryCursor>,
+        limit: usize,
+    ) -> Result<HistoryPage, Self::Error>;
+    fn count(&self, group_id: &GroupId) -> Result<usize, Self::Error>;
+}
+
+/// An embedded on-disk store backed by sled.
+///
+/// Messages live in the `messages` tree under `group id | timestamp | sequence`, so a prefix
+/// scan over a group yields its history in chronological order. The `message_index` tree maps
+/// `group id | message id` to that key for lookups and de-duplication by message id, and
+/// `message_count` keeps the number of messages per group.
+///
+/// Direct messages are laid out the same way in `direct_messages` and `direct_index`, with the
+/// remote peer id in place of the group id. `conversations` keeps the latest message of every
+/// conversation and `direct_outbox` the sent messages that were not acknowledged yet.
+/// `group_keys` holds the encryption keys of every group, old ones included, and `known_peers`
+/// the peers the node has been connected to with their last known addresses. `sync_positions`
+/// keeps, under `group id | peer id`, the timestamp of the last message synced from that peer.
+/// `group_members` holds the invites the members of every group were admitted with and the
+/// revocations of the peers that left.
+#[derive(Debug, Clone)]
+pub struct SledStore {
+    db: sled::Db,
+    groups: sled::Tree,
+    subscribers: sled::Tree,
+    messages: sled::Tree,
+    message_index: sled::Tree,
+    message_count: sled::Tree,
+    direct_messages: sled::Tree,
+    direct_index: sled::Tree,
+    conversations: sled::Tree,
+    direct_outbox: sled::Tree,
+    group_keys: sled::Tree,
+    known_peers: sled::Tree,
+    sync_positions: sled::Tree,
+    group_members: sled::Tree,
+}
+
+impl SledStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        let db = sled::open(path)?;
+        Ok(Self {
+            groups: db.open_tree("groups")?,
+            subscribers: db.open_tree("subscribers")?,
+            messages: db.open_tree("messages")?,
+            message_index: db.open_tree("message_index")?,
+            message_count: db.open_tree("message_count")?,
+            direct_messages: db.open_tree("direct_messages")?,
+            direct_index: db.open_tree("direct_index")?,
+            conversations: db.open_tree("conversations")?,
+            direct_outbox: db.open_tree("direct_outbox")?,
+            group_keys: db.open_tree("group_keys")?,
+            known_peers: db.open_tree("known_peers")?,
+            sync_positions: db.open_tree("sync_positions")?,
+            group_members: db.open_tree("group_members")?,
+            db,
+        })
+    }
+
+    pub fn put_group(&self, group_id: &GroupId, group_info: &GroupInfo) -> Result<(), StoreError> {
+        self.groups
+            .insert(group_id.as_bytes(), serde_json::to_vec(group_info)?)?;
+        Ok(())
+    }
+
+    /// Remove a group together with its subscribers and history.
+    pub fn remove_group(&self, group_id: &GroupId) -> Result<(), StoreError> {
+        self.groups.remove(group_id.as_bytes())?;
+        self.subscribers.remove(group_id.as_bytes())?;
+        for key in self.messages.scan_prefix(group_id.as_bytes()).keys() {
+            self.messages.remove(key?)?;
+        }
+        for key in self.message_index.scan_prefix(group_id.as_bytes()).keys() {
+            self.message_index.remove(key?)?;
+        }
+        self.message_count.remove(group_id.as_bytes())?;
+        self.group_keys.remove(group_id.as_bytes())?;
+        for key in self.sync_positions.scan_prefix(group_id.as_bytes()).keys() {
+            self.sync_positions.remove(key?)?;
+        }
+        self.group_members.remove(group_id.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn groups(&self) -> Result<HashMap<GroupId, GroupInfo>, StoreError> {
+        self.groups
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((GroupId::from_slice(&key)?, serde_json::from_slice(&value)?))
+            })
+            .collect()
+    }
+
+    pub fn put_subscribers(
+        &self,
+        group_id: &GroupId,
+        subscribers: &HashSet<PeerId>,
+    ) -> Result<(), StoreError> {
+        self.subscribers
+            .insert(group_id.as_bytes(), serde_json::to_vec(subscribers)?)?;
+        Ok(())
+    }
+
+    pub fn subscribers(&self, group_id: &GroupId) -> Result<HashSet<PeerId>, StoreError> {
+        match self.subscribers.get(group_id.as_bytes())? {
+            Some(value) => Ok(serde_json::from_slice(&value)?),
+            None => Ok(HashSet::new()),
+        }
+    }
+
+    pub fn put_group_keys(&self, group_id: &GroupId, keys: &[GroupKey]) -> Result<(), StoreError> {
+        self.group_keys
+            .insert(group_id.as_bytes(), serde_json::to_vec(keys)?)?;
+        Ok(())
+    }
+
+    pub fn group_keys(&self, group_id: &GroupId) -> Result<Vec<GroupKey>, StoreError> {
+        match self.group_keys.get(group_id.as_bytes())? {
+            Some(value) => Ok(serde_json::from_slice(&value)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn put_group_members(
+        &self,
+        group_id: &GroupId,
+        members: &GroupMembers,
+    ) -> Result<(), StoreError> {
+        self.group_members
+            .insert(group_id.as_bytes(), serde_json::to_vec(members)?)?;
+        Ok(())
+    }
+
+    pub fn group_members(&self, group_id: &GroupId) -> Result<GroupMembers, StoreError> {
+        match self.group_members.get(group_id.as_bytes())? {
+            Some(value) => Ok(serde_json::from_slice(&value)?),
+            None => Ok(GroupMembers::default()),
+        }
+    }
+
+    fn sync_key(group_id: &GroupId, peer_id: &PeerId) -> Vec<u8> {
+        [
+            group_id.as_bytes().as_slice(),
+            peer_id.to_bytes().as_slice(),
+        ]
+        .concat()
+    }
+
+    /// The timestamp of the last message of `group_id` synced from `peer_id`.
+    pub fn sync_position(
+        &self,
+        group_id: &GroupId,
+        peer_id: &PeerId,
+    ) -> Result<Option<i64>, StoreError> {
+        match self.sync_positions.get(Self::sync_key(group_id, peer_id))? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put_sync_position(
+        &self,
+        group_id: &GroupId,
+        peer_id: &PeerId,
+        timestamp: i64,
+    ) -> Result<(), StoreError> {
+        self.sync_positions.insert(
+            Self::sync_key(group_id, peer_id),
+            serde_json::to_vec(&timestamp)?,
+        )?;
+        Ok(())
+    }
+
+    fn index_key(group_id: &GroupId, id: &Uuid) -> Vec<u8> {
+        [group_id.as_bytes().as_slice(), id.as_bytes().as_slice()].concat()
+    }
+
+    fn message_key(&self, group_id: &GroupId, timestamp: i64) -> Result<Vec<u8>, StoreError> {
+        Ok(Self::key_at(group_id, timestamp, self.db.generate_id()?))
+    }
+
+    fn key_at(group_id: &GroupId, timestamp: i64, sequence: u64) -> Vec<u8> {
+        Self::prefixed_key_at(group_id.as_bytes(), timestamp, sequence)
+    }
+
+    fn prefixed_key_at(prefix: &[u8], timestamp: i64, sequence: u64) -> Vec<u8> {
+        // flip the sign bit so that negative timestamps still sort before positive ones
+        let timestamp = (timestamp as u64) ^ (1 << 63);
+        [prefix, &timestamp.to_be_bytes(), &sequence.to_be_bytes()].concat()
+    }
+
+    fn add_to_count(&self, group_id: &GroupId, delta: i64) -> Result<(), StoreError> {
+        self.message_count
+            .update_and_fetch(group_id.as_bytes(), |count| {
+                let count = count
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .map(u64::from_be_bytes)
+                    .unwrap_or_default();
+                Some(count.saturating_add_signed(delta).to_be_bytes().to_vec())
+            })?;
+        Ok(())
+    }
+
+    /// The key range of the messages in `group_id` selected by `cursor`, and whether the page
+    /// should be read backwards from the end of that range.
+    fn cursor_range(
+        &self,
+        group_id: &GroupId,
+        cursor: Option<&HistoryCursor>,
+    ) -> Result<Option<((Bound<Vec<u8>>, Bound<Vec<u8>>), bool)>, StoreError> {
+        let first = Bound::Included(Self::key_at(group_id, i64::MIN, 0));
+        let last = Bound::Included(Self::key_at(group_id, i64::MAX, u64::MAX));
+        let range = match cursor {
+            None => ((first, last), true),
+            Some(HistoryCursor::BeforeId(id)) | Some(HistoryCursor::AfterId(id)) => {
+                let Some(key) = self.message_index.get(Self::index_key(group_id, id))? else {
+                    return Ok(None);
+                };
+                let key = key.to_vec();
+                if matches!(cursor, Some(HistoryCursor::BeforeId(_))) {
+                    ((first, Bound::Excluded(key)), true)
+                } else {
+                    ((Bound::Excluded(key), last), false)
+                }
+            }
+            Some(HistoryCursor::BeforeTimestamp(timestamp)) => (
+                (
+                    first,
+                    Bound::Excluded(Self::key_at(group_id, *timestamp, 0)),
+                ),
+                true,
+            ),
+            Some(HistoryCursor::AfterTimestamp(timestamp)) => (
+                (
+                    Bound::Excluded(Self::key_at(group_id, *timestamp, u64::MAX)),
+                    last,
+                ),
+                false,
+            ),
+        };
+        Ok(Some(range))
+    }
+}
+
+/// Direct messages, stored per conversation under the remote peer id.
+impl SledStore {
+    fn direct_index_key(peer_id: &PeerId, id: &Uuid) -> Vec<u8> {
+        [peer_id.to_bytes().as_slice(), id.as_bytes().as_slice()].concat()
+    }
+
+    /// Store a direct message exchanged with `peer_id`, returning `false` if a message with the
+    /// same id is already stored. Undelivered messages are kept in the outbox until
+    /// [`Self::mark_delivered`] is called.
+    pub fn put_direct(
+        &self,
+        peer_id: &PeerId,
+        message: &DirectMessage,
+    ) -> Result<bool, StoreError> {
+        let index_key = Self::direct_index_key(peer_id, &message.id);
+        if self.direct_index.contains_key(&index_key)? {
+            return Ok(false);
+        }
+        let key = Self::prefixed_key_at(
+            &peer_id.to_bytes(),
+            message.timestamp,
+            self.db.generate_id()?,
+        );
+        self.direct_messages
+            .insert(&key, serde_json::to_vec(message)?)?;
+        self.direct_index.insert(&index_key, key)?;
+        if !message.delivered {
+            self.direct_outbox.insert(index_key, &[])?;
+        }
+        let is_latest = match self.conversations.get(peer_id.to_bytes())? {
+            Some(value) => {
+                serde_json::from_slice::<DirectMessage>(&value)?.timestamp <= message.timestamp
+            }
+            None => true,
+        };
+        if is_latest {
+            self.conversations
+                .insert(peer_id.to_bytes(), serde_json::to_vec(message)?)?;
+        }
+        Ok(true)
+    }
+
+    /// Record that `peer_id` acknowledged the message `id`, returning the updated message.
+    pub fn mark_delivered(
+        &self,
+        peer_id: &PeerId,
+        id: &Uuid,
+    ) -> Result<Option<DirectMessage>, StoreError> {
+        let index_key = Self::direct_index_key(peer_id, id);
+        self.direct_outbox.remove(&index_key)?;
+        let Some(key) = self.direct_index.get(&index_key)? else {
+            return Ok(None);
+        };
+        let Some(value) = self.direct_messages.get(&key)? else {
+            return Ok(None);
+        };
+        let mut message = serde_json::from_slice::<DirectMessage>(&value)?;
+        message.delivered = true;
+        let value = serde_json::to_vec(&message)?;
+        self.direct_messages.insert(key, value.as_slice())?;
+        if let Some(latest) = self.conversations.get(peer_id.to_bytes())? {
+            if serde_json::from_slice::<DirectMessage>(&latest)?.id == *id {
+                self.conversations.insert(peer_id.to_bytes(), value)?;
+            }
+        }
+        Ok(Some(message))
+    }
+
+    /// The messages sent to `peer_id` that it has not acknowledged, oldest first.
+    pub fn undelivered(&self, peer_id: &PeerId) -> Result<Vec<DirectMessage>, StoreError> {
+        let mut messages = Vec::new();
+        for index_key in self.direct_outbox.scan_prefix(peer_id.to_bytes()).keys() {
+            let Some(key) = self.direct_index.get(index_key?)? else {
+                continue;
+            };
+            if let Some(value) = self.direct_messages.get(key)? {
+                messages.push(serde_json::from_slice::<DirectMessage>(&value)?);
+            }
+        }
+        messages.sort_by_key(|message| message.timestamp);
+        Ok(messages)
+    }
+
+    /// Up to `limit` messages of the conversation with `peer_id` before the message `before`,
+    /// or the latest ones if no message is given.
+    pub fn direct_page(
+        &self,
+        peer_id: &PeerId,
+        before: Option<&Uuid>,
+        limit: usize,
+    ) -> Result<HistoryPage<DirectMessage>, StoreError> {
+        let prefix = peer_id.to_bytes();
+        let first = Bound::Included(Self::prefixed_key_at(&prefix, i64::MIN, 0));
+        let end = match before {
+            Some(id) => match self.direct_index.get(Self::direct_index_key(peer_id, id))? {
+                Some(key) => Bound::Excluded(key.to_vec()),
+                None => {
+                    return Ok(HistoryPage {
+                        messages: Vec::new(),
+                        has_more: false,
+                    })
+                }
+            },
+            None => Bound::Included(Self::prefixed_key_at(&prefix, i64::MAX, u64::MAX)),
+        };
+        // read one extra message to find out whether there is more history before this page
+        let values = self
+            .direct_messages
+            .range((first, end))
+            .values()
+            .rev()
+            .take(limit.saturating_add(1))
+            .collect::<Result<Vec<_>, _>>()?;
+        let has_more = values.len() > limit;
+        let mut messages = values
+            .iter()
+            .take(limit)
+            .map(|value| serde_json::from_slice(value))
+            .collect::<Result<Vec<DirectMessage>, _>>()?;
+        messages.reverse();
+        Ok(HistoryPage { messages, has_more })
+    }
+
+    pub fn has_conversation(&self, peer_id: &PeerId) -> Result<bool, StoreError> {
+        Ok(self.conversations.contains_key(peer_id.to_bytes())?)
+    }
+
+    /// Every conversation, the most recently active first.
+    pub fn conversations(&self) -> Result<Vec<Conversation>, StoreError> {
+        let mut conversations = self
+            .conversations
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok(Conversation {
+                    peer_id: PeerId::from_bytes(&key)
+                        .map_err(|e| StoreError::InvalidPeerId(e.to_string()))?,
+                    last_message: serde_json::from_slice(&value)?,
+                })
+            })
+            .collect::<Result<Vec<_>, StoreError>>()?;
+        conversations.sort_by(|a, b| b.last_message.timestamp.cmp(&a.last_message.timestamp));
+        Ok(conversations)
+    }
+}
+
+/// Peers to reconnect to, stored under their peer id.
+impl SledStore {
+    pub fn put_known_peer(&self, peer_id: &PeerId, peer: &KnownPeer) -> Result<(), StoreError> {
+        self.known_peers
+            .insert(peer_id.to_bytes(), serde_json::to_vec(peer)?)?;
+        Ok(())
+    }
+
+    pub fn known_peer(&self, peer_id: &PeerId) -> Result<Option<KnownPeer>, StoreError> {
+        match self.known_peers.get(peer_id.to_bytes())? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn known_peers(&self) -> Result<HashMap<PeerId, KnownPeer>, StoreError> {
+        self.known_peers
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((
+                    PeerId::from_bytes(&key)
+                        .map_err(|e| StoreError::InvalidPeerId(e.to_string()))?,
+                    serde_json::from_slice(&value)?,
+                ))
+            })
+            .collect()
+    }
+
+    pub fn remove_known_peer(&self, peer_id: &PeerId) -> Result<(), StoreError> {
+        self.known_peers.remove(peer_id.to_bytes())?;
+        Ok(())
+    }
+}
+
+impl MessageStore for SledStore {
+    type Message = GroupMessage;
+    type Error = StoreError;
+
+    fn get(&self, group_id: &GroupId, id: &Uuid) -> Result<Option<GroupMessage>, StoreError> {
+        let Some(key) = self.message_index.get(Self::index_key(group_id, id))? else {
+            return Ok(None);
+        };
+        match self.messages.get(key)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Store a message, returning `false` if a message with the same id is already stored.
+    fn put(&mut self, group_id: &GroupId, message: GroupMessage) -> Result<bool, StoreError> {
+        let index_key = Self::index_key(group_id, &message.id);
+        if self.message_index.contains_key(&index_key)? {
+            return Ok(false);
+        }
+        let key = self.message_key(group_id, message.timestamp)?;
+        self.messages.insert(&key, serde_json::to_vec(&message)?)?;
+        self.message_index.insert(index_key, key)?;
+        self.add_to_count(group_id, 1)?;
+        Ok(true)
+    }
+
+    fn remove(
+        &mut self,
+        group_id: &GroupId,
+        id: &Uuid,
+    ) -> Result<Option<GroupMessage>, StoreError> {
+        let Some(key) = self.message_index.remove(Self::index_key(group_id, id))? else {
+            return Ok(None);
+        };
+        match self.messages.remove(key)? {
+            Some(value) => {
+                self.add_to_count(group_id, -1)?;
+                Ok(Some(serde_json::from_slice(&value)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn history(&self, group_id: &GroupId) -> Result<Vec<GroupMessage>, StoreError> {
+        self.messages
+            .scan_prefix(group_id.as_bytes())
+            .values()
+            .map(|value| Ok(serde_json::from_slice(&value?)?))
+            .collect()
+    }
+
+    fn page(
+        &self,
+        group_id: &GroupId,
+        cursor: Option<&HistoryCursor>,
+        limit: usize,
+    ) -> Result<HistoryPage, StoreError> {
+        let Some((range, backwards)) = self.cursor_range(group_id, cursor)? else {
+            return Ok(HistoryPage {
+                messages: Vec::new(),
+                has_more: false,
+            });
+        };
+        let iter = self.messages.range(range).values();
+        // read one extra message to find out whether there is more history after this page
+        let values = if backwards {
+            iter.rev()
+                .take(limit.saturating_add(1))
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            iter.take(limit.saturating_add(1))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        let has_more = values.len() > limit;
+        let mut messages = values
+            .iter()
+            .take(limit)
+            .map(|value| serde_json::from_slice(value))
+            .collect::<Result<Vec<GroupMessage>, _>>()?;
+        if backwards {
+            messages.reverse();
+        }
+        Ok(HistoryPage { messages, has_more })
+    }
+
+    fn count(&self, group_id: &GroupId) -> Result<usize, StoreError> {
+        Ok(self
+            .message_count
+            .get(group_id.as_bytes())?
+            .and_then(|bytes| bytes.as_ref().try_into().ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or_default() as usize)
+    }
 }
@@ -0,0 +1,607 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use uuid::Uuid;
+
+use crate::{
+    error::{NetworkError, TransferError},
+    models::{FileInfo, FileManifest},
+    network::{
+        message::{ChunkRequest, ChunkResponse},
+        Client,
+    },
+};
+
+pub const MIN_CHUNK_SIZE: u64 = 256 * 1024;
+pub const MAX_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+/// Larger files are refused, whatever size a peer claims they have.
+pub const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+/// Chunks grow with the file so that manifests of large files stay small.
+const TARGET_CHUNK_COUNT: u64 = 4096;
+/// How many chunk requests are kept in flight to every source of a download.
+const PARALLEL_CHUNKS: usize = 4;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+pub fn chunk_size_for(size: u64) -> u64 {
+    ((size + TARGET_CHUNK_COUNT - 1) / TARGET_CHUNK_COUNT)
+        .next_power_of_two()
+        .clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE)
+}
+
+pub fn hash_chunk(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// The sha256 of the whole file at `path`, hex encoded.
+pub async fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; MIN_CHUNK_SIZE as usize];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Check that a manifest received from a peer can be laid out on disk: the file is not too
+/// large, the chunk size is one we could have picked and there is one hash for every chunk.
+pub fn validate_manifest(manifest: &FileManifest) -> Result<(), TransferError> {
+    let invalid = || TransferError::InvalidManifest(manifest.file.name.clone());
+    let size = manifest.file.size;
+    if size > MAX_FILE_SIZE || !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&manifest.chunk_size) {
+        return Err(invalid());
+    }
+    let chunk_count = (size + manifest.chunk_size - 1) / manifest.chunk_size;
+    if manifest.chunk_count() != chunk_count {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// Read the file at `path` chunk by chunk and hash every chunk.
+pub async fn build_manifest(path: &Path, file: FileInfo) -> io::Result<FileManifest> {
+    let chunk_size = chunk_size_for(file.size);
+    let mut reader = fs::File::open(path).await?;
+    let mut chunk_hashes = Vec::new();
+    let mut buf = Vec::with_capacity(chunk_size as usize);
+    loop {
+        buf.clear();
+        let read = (&mut reader).take(chunk_size).read_to_end(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        chunk_hashes.push(hash_chunk(&buf));
+    }
+    Ok(FileManifest {
+        file,
+        chunk_size,
+        chunk_hashes,
+    })
+}
+
+pub async fn read_chunk(path: &Path, manifest: &FileManifest, index: u64) -> io::Result<Vec<u8>> {
+    let offset = manifest
+        .chunk_offset(index)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "chunk index out of range"))?;
+    let mut file = fs::File::open(path).await?;
+    file.seek(io::SeekFrom::Start(offset)).await?;
+    let mut data = Vec::with_capacity(manifest.chunk_len(index) as usize);
+    file.take(manifest.chunk_len(index))
+        .read_to_end(&mut data)
+        .await?;
+    Ok(data)
+}
+
+/// A download in progress, persisted under the downloads directory so that it can be resumed
+/// after a disconnect or a restart. The record itself is written once; the indices of verified
+/// chunks are appended to a log next to it as they arrive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PartialDownload {
+    pub id: Uuid,
+    /// The peer the download was started from.
+    pub peer_id: PeerId,
+    pub manifest: FileManifest,
+    /// The directory the file is moved into once complete.
+    pub dir: PathBuf,
+    /// Named after the download and kept next to its record until the file is complete, so
+    /// downloads of files with the same name stay apart and unverified data stays out of `dir`.
+    pub part_path: PathBuf,
+    /// Paused downloads are not resumed when their provider reconnects.
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(skip)]
+    pub verified_chunks: BTreeSet<u64>,
+}
+
+/// A progress report of a running transfer.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferProgress {
+    pub id: Uuid,
+    pub bytes: u64,
+    pub total: u64,
+    /// Bytes per second since the transfer was (re)started.
+    pub rate: f64,
+    /// Estimated seconds until completion, if anything was transferred yet.
+    pub eta: Option<u64>,
+}
+
+/// Measures the rate of a transfer and rate-limits its progress reports.
+#[derive(Debug)]
+struct ProgressTracker {
+    id: Uuid,
+    started: Instant,
+    initial_bytes: u64,
+    bytes: u64,
+    total: u64,
+    last_report: Option<Instant>,
+}
+
+impl ProgressTracker {
+    fn new(id: Uuid, bytes: u64, total: u64) -> Self {
+        Self {
+            id,
+            started: Instant::now(),
+            initial_bytes: bytes,
+            bytes,
+            total,
+            last_report: None,
+        }
+    }
+
+    /// Add `bytes` to the transfer, returning a report if one is due.
+    fn advance(&mut self, bytes: u64) -> Option<TransferProgress> {
+        self.bytes += bytes;
+        let now = Instant::now();
+        let due = self
+            .last_report
+            .map_or(true, |last| now - last >= PROGRESS_INTERVAL);
+        if !due && self.bytes < self.total {
+            return None;
+        }
+        self.last_report = Some(now);
+        Some(self.progress())
+    }
+
+    fn progress(&self) -> TransferProgress {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            (self.bytes - self.initial_bytes) as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta = (rate > 0.0).then(|| ((self.total - self.bytes) as f64 / rate).ceil() as u64);
+        TransferProgress {
+            id: self.id,
+            bytes: self.bytes,
+            total: self.total,
+            rate,
+            eta,
+        }
+    }
+}
+
+impl PartialDownload {
+    pub fn new(
+        peer_id: PeerId,
+        manifest: FileManifest,
+        dir: PathBuf,
+        records_dir: &Path,
+    ) -> Result<Self, NetworkError> {
+        // refuse files that could not be saved before fetching anything
+        validate_manifest(&manifest)?;
+        file_name(&manifest.file)?;
+        let id = Uuid::new_v4();
+        Ok(Self {
+            id,
+            peer_id,
+            part_path: records_dir.join(format!("{id}.part")),
+            manifest,
+            dir,
+            paused: false,
+            verified_chunks: BTreeSet::new(),
+        })
+    }
+
+    /// Load every download recorded in `records_dir`.
+    pub async fn load_all(records_dir: &Path) -> io::Result<Vec<Self>> {
+        let mut downloads = Vec::new();
+        let mut entries = match fs::read_dir(records_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(downloads),
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path
+                .extension()
+                .map_or(true, |extension| extension != "json")
+            {
+                continue;
+            }
+            let mut download = match serde_json::from_slice::<Self>(&fs::read(&path).await?) {
+                Ok(download) => download,
+                Err(e) => {
+                    log::warn!("skipping invalid download record {path:?}: {e}");
+                    continue;
+                }
+            };
+            download.load_log(records_dir).await?;
+            downloads.push(download);
+        }
+        Ok(downloads)
+    }
+
+    fn record_path(&self, records_dir: &Path) -> PathBuf {
+        records_dir.join(format!("{}.json", self.id))
+    }
+
+    fn log_path(&self, records_dir: &Path) -> PathBuf {
+        records_dir.join(format!("{}.chunks", self.id))
+    }
+
+    /// Read the verified chunks from the log, e.g. after a download task was aborted before it
+    /// could hand its progress back.
+    pub async fn load_log(&mut self, records_dir: &Path) -> io::Result<()> {
+        let log = match fs::read(self.log_path(records_dir)).await {
+            Ok(log) => log,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let chunk_count = self.manifest.chunk_count();
+        self.verified_chunks = log
+            .chunks_exact(8)
+            .map(|index| u64::from_be_bytes(index.try_into().unwrap()))
+            .filter(|&index| index < chunk_count)
+            .collect();
+        Ok(())
+    }
+
+    pub async fn save(&self, records_dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(records_dir).await?;
+        fs::write(self.record_path(records_dir), serde_json::to_vec(self)?).await
+    }
+
+    async fn record_chunk(&mut self, records_dir: &Path, index: u64) -> io::Result<()> {
+        let mut log = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.log_path(records_dir))
+            .await?;
+        log.write_all(&index.to_be_bytes()).await?;
+        log.flush().await?;
+        self.verified_chunks.insert(index);
+        Ok(())
+    }
+
+    /// Delete the record and the partial file.
+    pub async fn remove(&self, records_dir: &Path) -> io::Result<()> {
+        let _ = fs::remove_file(&self.part_path).await;
+        let _ = fs::remove_file(self.log_path(records_dir)).await;
+        match fs::remove_file(self.record_path(records_dir)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drop recorded chunks that no longer match the manifest, e.g. because the partial file
+    /// was not fully flushed before the app went down.
+    async fn verify_part(&mut self, records_dir: &Path) -> io::Result<()> {
+        if fs::metadata(&self.part_path).await.is_err() {
+            self.verified_chunks.clear();
+            let _ = fs::remove_file(self.log_path(records_dir)).await;
+            return Ok(());
+        }
+        let mut valid = BTreeSet::new();
+        for &index in &self.verified_chunks {
+            // a corrupt log may hold indices beyond the end of the file
+            let Some(expected) = self.manifest.chunk_hashes.get(index as usize) else {
+                continue;
+            };
+            let data = read_chunk(&self.part_path, &self.manifest, index).await?;
+            if &hash_chunk(&data) == expected {
+                valid.insert(index);
+            }
+        }
+        if valid.len() != self.verified_chunks.len() {
+            let log = valid
+                .iter()
+                .flat_map(|index| index.to_be_bytes())
+                .collect::<Vec<_>>();
+            fs::write(self.log_path(records_dir), log).await?;
+            self.verified_chunks = valid;
+        }
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.verified_chunks.len() as u64 == self.manifest.chunk_count()
+    }
+
+    /// The number of bytes received and verified so far.
+    pub fn received_bytes(&self) -> u64 {
+        self.verified_chunks
+            .iter()
+            .map(|&index| self.manifest.chunk_len(index))
+            .sum()
+    }
+}
+
+/// Fetch the chunks of `download` that are still missing from `sources`, verifying every chunk
+/// against the manifest, and move the file into its directory once complete. Chunks are spread
+/// over all sources at once; a source that fails a request is dropped and its chunks go to the
+/// others. On failure the partial file and its record are kept so that the download can be
+/// resumed later. `on_progress` is called with progress reports as chunks arrive.
+pub async fn download<F>(
+    client: &Client,
+    download: &mut PartialDownload,
+    sources: &[PeerId],
+    records_dir: &Path,
+    mut on_progress: F,
+) -> Result<PathBuf, NetworkError>
+where
+    F: FnMut(TransferProgress) + Send,
+{
+    // the partial file is allocated at the size the manifest gives
+    validate_manifest(&download.manifest)?;
+    let mut part = if fs::metadata(&download.part_path).await.is_ok() {
+        download.verify_part(records_dir).await?;
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&download.part_path)
+            .await?
+    } else {
+        download.save(records_dir).await?;
+        download.verified_chunks.clear();
+        let part = fs::File::create(&download.part_path).await?;
+        part.set_len(download.manifest.file.size).await?;
+        part
+    };
+    if !download.verified_chunks.is_empty() {
+        log::info!(
+            "Resuming {} at {}/{} chunks",
+            download.manifest.file.name,
+            download.verified_chunks.len(),
+            download.manifest.chunk_count()
+        );
+    }
+
+    let mut tracker = ProgressTracker::new(
+        download.id,
+        download.received_bytes(),
+        download.manifest.file.size,
+    );
+    on_progress(tracker.progress());
+
+    let manifest = download.manifest.clone();
+    let mut missing = (0..manifest.chunk_count())
+        .filter(|index| !download.verified_chunks.contains(index))
+        .collect::<VecDeque<_>>();
+    // the number of requests in flight to every source that has not failed yet
+    let mut loads = sources
+        .iter()
+        .map(|&peer_id| (peer_id, 0))
+        .collect::<HashMap<_, usize>>();
+    let mut requests = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        // a source is handed the next chunk as soon as it delivers one, so faster sources end
+        // up serving more of the file
+        for (&peer_id, load) in loads.iter_mut() {
+            while *load < PARALLEL_CHUNKS {
+                let Some(index) = missing.pop_front() else {
+                    break;
+                };
+                requests.push(fetch_chunk(client, peer_id, &manifest, index));
+                *load += 1;
+            }
+        }
+        let Some((peer_id, index, result)) = requests.next().await else {
+            break;
+        };
+        if let Some(load) = loads.get_mut(&peer_id) {
+            *load -= 1;
+        }
+        let data = match result {
+            Ok(data) => data,
+            Err(e) => {
+                if loads.remove(&peer_id).is_some() {
+                    log::warn!(
+                        "Dropping {peer_id} as a source of {}: {e}",
+                        manifest.file.name
+                    );
+                }
+                missing.push_front(index);
+                last_error = Some(e);
+                continue;
+            }
+        };
+        let offset = manifest
+            .chunk_offset(index)
+            .ok_or(TransferError::CorruptChunk(index))?;
+        part.seek(io::SeekFrom::Start(offset)).await?;
+        part.write_all(&data).await?;
+        part.flush().await?;
+        download.record_chunk(records_dir, index).await?;
+        if let Some(progress) = tracker.advance(data.len() as u64) {
+            on_progress(progress);
+        }
+    }
+    drop(part);
+    if !missing.is_empty() {
+        // every source failed
+        return Err(last_error
+            .unwrap_or_else(|| TransferError::NoSource(manifest.file.name.clone()).into()));
+    }
+
+    // the chunks match the manifest, but the manifest itself may not match the requested file
+    if let Some(expected) = &manifest.file.hash {
+        if &hash_file(&download.part_path).await? != expected {
+            download.remove(records_dir).await?;
+            return Err(TransferError::HashMismatch(manifest.file.name.clone()).into());
+        }
+    }
+    let path = available_path(&download.dir, &file_name(&manifest.file)?).await;
+    // the partial file lives next to the record, which may be on another file system
+    if fs::rename(&download.part_path, &path).await.is_err() {
+        fs::copy(&download.part_path, &path).await?;
+    }
+    download.remove(records_dir).await?;
+    Ok(path)
+}
+
+/// Request the chunk at `index` from `peer_id`, returning the chunk data once it is verified.
+async fn fetch_chunk(
+    client: &Client,
+    peer_id: PeerId,
+    manifest: &FileManifest,
+    index: u64,
+) -> (PeerId, u64, Result<Vec<u8>, NetworkError>) {
+    let request = ChunkRequest {
+        file: manifest.file.clone(),
+        index,
+    };
+    let result = match client.request_chunk(peer_id, request).await {
+        Ok(ChunkResponse::Chunk(chunk)) => {
+            let expected = &manifest.chunk_hashes[index as usize];
+            if chunk.index != index
+                || chunk.data.len() as u64 != manifest.chunk_len(index)
+                || &chunk.hash != expected
+                || &hash_chunk(&chunk.data) != expected
+            {
+                Err(TransferError::CorruptChunk(index).into())
+            } else {
+                Ok(chunk.data)
+            }
+        }
+        Ok(ChunkResponse::Unavailable) => {
+            Err(TransferError::Unavailable(manifest.file.name.clone()).into())
+        }
+        Err(e) => Err(e),
+    };
+    (peer_id, index, result)
+}
+
+/// The file name of `file` without any directory components a peer might have sent.
+fn file_name(file: &FileInfo) -> Result<String, TransferError> {
+    Path::new(&file.name)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or_else(|| TransferError::InvalidFileName(file.name.clone()))
+}
+
+/// `dir/name`, or `dir/name (n).ext` with the first free `n` if that file already exists.
+async fn available_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if fs::metadata(&path).await.is_err() {
+        return path;
+    }
+    let stem = path
+        .file_stem()
+        .map_or_else(String::new, |stem| stem.to_string_lossy().to_string());
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()));
+    let mut n = 1;
+    loop {
+        let path = dir.join(format!(
+            "{stem} ({n}){}",
+            extension.as_deref().unwrap_or("")
+        ));
+        if fs::metadata(&path).await.is_err() {
+            return path;
+        }
+        n += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_info(size: u64) -> FileInfo {
+        FileInfo {
+            name: "file.bin".to_string(),
+            size,
+            file_type: None,
+            hash: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn manifest_covers_the_file_with_a_short_last_chunk() {
+        let size = MIN_CHUNK_SIZE * 2 + 10;
+        let data = (0..size).map(|i| i as u8).collect::<Vec<_>>();
+        let path = std::env::temp_dir().join(format!("{}.bin", Uuid::new_v4()));
+        fs::write(&path, &data).await.unwrap();
+        let manifest = build_manifest(&path, file_info(size)).await.unwrap();
+
+        assert_eq!(manifest.chunk_size, MIN_CHUNK_SIZE);
+        assert_eq!(manifest.chunk_count(), 3);
+        assert_eq!(manifest.chunk_len(0), MIN_CHUNK_SIZE);
+        assert_eq!(manifest.chunk_len(2), 10);
+        assert_eq!(manifest.chunk_len(3), 0);
+        assert!(validate_manifest(&manifest).is_ok());
+        let last = read_chunk(&path, &manifest, 2).await.unwrap();
+        assert_eq!(last, data[(MIN_CHUNK_SIZE * 2) as usize..]);
+        assert_eq!(hash_chunk(&last), manifest.chunk_hashes[2]);
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn empty_file_has_no_chunks() {
+        let path = std::env::temp_dir().join(format!("{}.bin", Uuid::new_v4()));
+        fs::write(&path, b"").await.unwrap();
+        let manifest = build_manifest(&path, file_info(0)).await.unwrap();
+
+        assert_eq!(manifest.chunk_count(), 0);
+        assert!(validate_manifest(&manifest).is_ok());
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[test]
+    fn chunk_len_does_not_overflow() {
+        let manifest = FileManifest {
+            file: file_info(u64::MAX),
+            chunk_size: MAX_CHUNK_SIZE,
+            chunk_hashes: Vec::new(),
+        };
+        assert_eq!(manifest.chunk_offset(u64::MAX), None);
+        assert_eq!(manifest.chunk_len(u64::MAX), 0);
+    }
+
+    #[test]
+    fn hostile_manifests_are_rejected() {
+        let manifest = |size, chunk_size, chunk_count| FileManifest {
+            file: file_info(size),
+            chunk_size,
+            chunk_hashes: vec![String::new(); chunk_count],
+        };
+        // a file beyond the size cap
+        assert!(validate_manifest(&manifest(u64::MAX, MAX_CHUNK_SIZE, 0)).is_err());
+        // chunk sizes we would never pick
+        assert!(validate_manifest(&manifest(10, 0, 1)).is_err());
+        assert!(validate_manifest(&manifest(10, MIN_CHUNK_SIZE - 1, 1)).is_err());
+        assert!(validate_manifest(&manifest(10, MAX_CHUNK_SIZE + 1, 1)).is_err());
+        // too few and too many hashes for the size
+        assert!(validate_manifest(&manifest(MIN_CHUNK_SIZE + 1, MIN_CHUNK_SIZE, 1)).is_err());
+        assert!(validate_manifest(&manifest(MIN_CHUNK_SIZE, MIN_CHUNK_SIZE, 2)).is_err());
+        assert!(validate_manifest(&manifest(MIN_CHUNK_SIZE + 1, MIN_CHUNK_SIZE, 2)).is_ok());
+    }
+}
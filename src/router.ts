@@ -15,6 +15,24 @@ const routes: RouteRecordRaw[] = [
       title: "Home",
     },
   },
+  {
+    path: "/peers",
+    name: "peers",
+    component: () => import("./pages/Peers.vue"),
+    meta: {
+      icon: "mdi-account-network",
+      title: "Peers",
+    },
+  },
+  {
+    path: "/transfers",
+    name: "transfers",
+    component: () => import("./pages/Transfers.vue"),
+    meta: {
+      icon: "mdi-swap-vertical",
+      title: "Transfers",
+    },
+  },
   {
     path: "/settings",
     name: "settings",
@@ -37,6 +37,11 @@ export const useGroupState = defineStore("group", () => {
   AppEvent.onMessage((event) => {
     groupStates.value[event.payload[0]].history.push(event.payload[1]);
   });
+  AppEvent.onHistorySync((event) => {
+    const history = groupStates.value[event.payload[0]].history;
+    history.push(...event.payload[1]);
+    history.sort((a, b) => a.timestamp - b.timestamp);
+  });
 
   return {
     groups: groups.state,
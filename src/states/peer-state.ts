@@ -0,0 +1,22 @@
+import { getKnownPeers, getReconnectStates } from "@/utils/backend";
+import { defineStore } from "pinia";
+import { AppEvent } from "@/utils/app-event";
+export const usePeerState = defineStore("peer", () => {
+  const knownPeers = useAsyncState(getKnownPeers, {}, { shallow: false });
+  const reconnectStates = useAsyncState(
+    getReconnectStates(),
+    {},
+    { shallow: false }
+  );
+  AppEvent.onReconnectState((event) => {
+    reconnectStates.state.value[event.payload[0]] = event.payload[1];
+    // only remembered peers are reconnected to, a new one was just stored
+    if (!knownPeers.state.value[event.payload[0]]) {
+      knownPeers.execute();
+    }
+  });
+  return {
+    knownPeers: knownPeers.state,
+    reconnectStates: reconnectStates.state,
+  };
+});
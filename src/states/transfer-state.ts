@@ -0,0 +1,85 @@
+import { getTransfers } from "@/utils/backend";
+import { defineStore } from "pinia";
+import { AppEvent } from "@/utils/app-event";
+import { TransferInfo } from "@/utils/types";
+
+export type Transfer = TransferInfo & {
+  /** Bytes per second, while chunks are being transferred. */
+  rate?: number;
+  eta?: number | null;
+  path?: string;
+  error?: string;
+};
+
+export const useTransferState = defineStore("transfer", () => {
+  const transfers = useAsyncState(
+    async () =>
+      Object.fromEntries(
+        (await getTransfers()).map((transfer) => [transfer.id, transfer])
+      ) as { [index: string]: Transfer },
+    {},
+    { shallow: false }
+  );
+
+  AppEvent.onTransferStarted((event) => {
+    const [id, file, peerId] = event.payload;
+    const transfer = transfers.state.value[id];
+    if (transfer) {
+      transfer.paused = false;
+      transfer.active = true;
+      transfer.error = undefined;
+    } else {
+      transfers.state.value[id] = {
+        id,
+        file,
+        peerId,
+        bytes: 0,
+        total: file.size,
+        paused: false,
+        active: true,
+      };
+    }
+  });
+  AppEvent.onTransferProgress((event) => {
+    const transfer = transfers.state.value[event.payload.id];
+    if (transfer) {
+      transfer.bytes = event.payload.bytes;
+      transfer.total = event.payload.total;
+      transfer.rate = event.payload.rate;
+      transfer.eta = event.payload.eta;
+    }
+  });
+  AppEvent.onTransferPaused((event) => {
+    const transfer = transfers.state.value[event.payload];
+    if (transfer) {
+      transfer.paused = true;
+      transfer.active = false;
+      transfer.rate = undefined;
+      transfer.eta = undefined;
+    }
+  });
+  AppEvent.onTransferCompleted((event) => {
+    const transfer = transfers.state.value[event.payload[0]];
+    if (transfer) {
+      transfer.bytes = transfer.total;
+      transfer.active = false;
+      transfer.path = event.payload[1];
+    }
+  });
+  AppEvent.onTransferFailed((event) => {
+    const transfer = transfers.state.value[event.payload[0]];
+    if (transfer) {
+      transfer.active = false;
+      transfer.rate = undefined;
+      transfer.eta = undefined;
+      transfer.error = event.payload[1];
+    }
+  });
+  AppEvent.onTransferCancelled((event) => {
+    delete transfers.state.value[event.payload];
+  });
+
+  return {
+    transfers: transfers.state,
+  };
+});
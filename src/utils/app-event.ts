@@ -1,11 +1,15 @@
 import { Event, listen } from "@tauri-apps/api/event";
 import {
+  DirectMessage,
+  FileInfo,
   GroupId,
   GroupInfo,
   GroupMessage,
   GroupState,
   Multiaddr,
   PeerId,
+  ReconnectState,
+  TransferProgress,
   UserInfo,
 } from "./types";
 
@@ -28,6 +32,42 @@ export class AppEvent {
       console.error(err);
     }
   }
+  static async onHistorySync(
+    callBackFn: (args: Event<[GroupId, GroupMessage[]]>) => void
+  ) {
+    try {
+      return await listen<[GroupId, GroupMessage[]]>(
+        "history-sync",
+        callBackFn
+      );
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onDirectMessage(
+    callBackFn: (args: Event<[PeerId, DirectMessage]>) => void
+  ) {
+    try {
+      return await listen<[PeerId, DirectMessage]>(
+        "direct-message",
+        callBackFn
+      );
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onDirectMessageDelivered(
+    callBackFn: (args: Event<[PeerId, string]>) => void
+  ) {
+    try {
+      return await listen<[PeerId, string]>(
+        "direct-message-delivered",
+        callBackFn
+      );
+    } catch (err) {
+      console.error(err);
+    }
+  }
   static async onUserUpdate(
     callBackFn: (args: Event<[PeerId, UserInfo]>) => void
   ) {
@@ -37,6 +77,18 @@ export class AppEvent {
       console.error(err);
     }
   }
+  static async onReconnectState(
+    callBackFn: (args: Event<[PeerId, ReconnectState]>) => void
+  ) {
+    try {
+      return await listen<[PeerId, ReconnectState]>(
+        "reconnect-state",
+        callBackFn
+      );
+    } catch (err) {
+      console.error(err);
+    }
+  }
   static async onSubscribed(
     callBackFn: (args: Event<[GroupId, PeerId]>) => void
   ) {
@@ -64,4 +116,57 @@ export class AppEvent {
       console.error(err);
     }
   }
+  static async onTransferStarted(
+    callBackFn: (args: Event<[string, FileInfo, PeerId]>) => void
+  ) {
+    try {
+      return await listen<[string, FileInfo, PeerId]>(
+        "transfer-started",
+        callBackFn
+      );
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onTransferProgress(
+    callBackFn: (args: Event<TransferProgress>) => void
+  ) {
+    try {
+      return await listen<TransferProgress>("transfer-progress", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onTransferPaused(callBackFn: (args: Event<string>) => void) {
+    try {
+      return await listen<string>("transfer-paused", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onTransferCompleted(
+    callBackFn: (args: Event<[string, string]>) => void
+  ) {
+    try {
+      return await listen<[string, string]>("transfer-completed", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onTransferFailed(
+    callBackFn: (args: Event<[string, string]>) => void
+  ) {
+    try {
+      return await listen<[string, string]>("transfer-failed", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
+  static async onTransferCancelled(callBackFn: (args: Event<string>) => void) {
+    try {
+      return await listen<string>("transfer-cancelled", callBackFn);
+    } catch (err) {
+      console.error(err);
+    }
+  }
 }
@@ -5,9 +5,12 @@ import {
   GroupInfo,
   GroupMessage,
   GroupState,
+  KnownPeer,
   Message,
   PeerId,
+  ReconnectState,
   Setting,
+  TransferInfo,
   UserInfo,
 } from "./types";
 
@@ -150,6 +153,25 @@ export async function newGroup(groupInfo: GroupInfo): Promise<GroupId> {
   return await invoke<GroupId>("new_group", { groupInfo });
 }
 
+export async function inviteToGroup(
+  groupId: GroupId,
+  peerId: PeerId
+): Promise<string> {
+  return await invoke<string>("invoke_manager", {
+    name: "group",
+    action: "invite",
+    params: { groupId, peerId },
+  });
+}
+
+export async function acceptGroupInvite(code: string): Promise<GroupId> {
+  return await invoke<GroupId>("invoke_manager", {
+    name: "group",
+    action: "accept_invite",
+    params: code,
+  });
+}
+
 export async function getGroupState(groupId: GroupId): Promise<GroupState> {
   return await invoke<GroupState>("invoke_manager", {
     name: "group",
@@ -164,3 +186,45 @@ export async function getUsers(): Promise<{ [index: PeerId]: UserInfo }> {
     action: "get_users",
   });
 }
+
+export async function getKnownPeers(): Promise<{
+  [index: PeerId]: KnownPeer;
+}> {
+  return await invoke<{ [index: PeerId]: KnownPeer }>("invoke_manager", {
+    name: "peer",
+    action: "known_peers",
+  });
+}
+
+export async function getReconnectStates(): Promise<{
+  [index: PeerId]: ReconnectState;
+}> {
+  return await invoke<{ [index: PeerId]: ReconnectState }>("invoke_manager", {
+    name: "peer",
+    action: "reconnect_states",
+  });
+}
+
+export async function forgetPeer(peerId: PeerId) {
+  await invoke("invoke_manager", {
+    name: "peer",
+    action: "forget",
+    params: peerId,
+  });
+}
+
+export async function getTransfers(): Promise<TransferInfo[]> {
+  return await invoke<TransferInfo[]>("get_transfers");
+}
+
+export async function pauseTransfer(id: string) {
+  await invoke("pause_transfer", { id });
+}
+
+export async function resumeTransfer(id: string) {
+  await invoke("resume_transfer", { id });
+}
+
+export async function cancelTransfer(id: string) {
+  await invoke("cancel_transfer", { id });
+}
@@ -7,6 +7,8 @@ export type Action = {
 export type FileInfo = {
   name: string;
   size: number;
+  fileType?: string;
+  hash?: string;
   createTime?: Date;
   modifyTime?: Date;
 };
@@ -16,22 +18,71 @@ export type FileSource = {
 };
 export type Setting = {
   recvPath: string;
+  networkKey?: string;
+  bootstrap?: string[];
+  relayServer?: boolean;
+  listen?: ListenConfig;
+  listenAddrs?: Multiaddr[];
+};
+
+export type ListenConfig = {
+  tcpPort: number;
+  quicPort: number;
+  ipv6: boolean;
+  interfaces: string[];
 };
 
 export type GroupId = string;
 export type PeerId = string;
 export type GroupMessage = {
+  id: string;
   message: Message;
   timestamp: number;
   source?: string;
 };
 
+export type DirectMessage = {
+  id: string;
+  source: PeerId;
+  target: PeerId;
+  timestamp: number;
+  message: Message;
+  delivered: boolean;
+};
+
+export type Conversation = {
+  peerId: PeerId;
+  lastMessage: DirectMessage;
+};
+
 export type UserInfo = {
   name: string;
   avatar: string;
   status: "online" | "offline";
+  connection: ConnectionType | null;
+  peer: PeerInfo | null;
+};
+
+export type PeerInfo = {
+  listenAddrs: Multiaddr[];
+  protocols: string[];
+  protocolVersion: string;
+  agentVersion: string;
+  rtt: number | null;
+};
+
+export type KnownPeer = {
+  addresses: Multiaddr[];
+  lastSeen: number;
 };
 
+export type ReconnectState =
+  | { state: "connected" }
+  | { state: "dialing"; attempt: number }
+  | { state: "waiting"; attempt: number; retryAt: number };
+
+export type ConnectionType = "direct" | "relayed" | "holePunched";
+
 export type GroupState = {
   subscribers: PeerId[];
   history: GroupMessage[];
@@ -47,3 +98,21 @@ export type GroupInfo = {
   description: string | null;
 };
 export type Multiaddr = string;
+
+export type TransferInfo = {
+  id: string;
+  file: FileInfo;
+  peerId: PeerId;
+  bytes: number;
+  total: number;
+  paused: boolean;
+  active: boolean;
+};
+
+export type TransferProgress = {
+  id: string;
+  bytes: number;
+  total: number;
+  rate: number;
+  eta: number | null;
+};